@@ -1,8 +1,8 @@
-use std::fmt::{self, Display, Formatter};
+use std::collections::BTreeMap;
 
 use rust_decimal::Decimal;
 
-use crate::errors::AccountOperationError;
+use crate::{currency::Currency, errors::AccountOperationError, fx::FxRates, tiers::AccountLimits};
 
 pub type AccountId = u16;
 
@@ -21,34 +21,81 @@ impl Wallet {
     }
 }
 
-/// A customer account with its wallet.
+/// Balance of a wallet, as written in the accounts report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletBalance {
+    pub client: AccountId,
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    /// `available + held`, kept apart as it may not have the same scale.
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// A customer account with one wallet per currency.
 /// It's just a business encapsulation.
 #[derive(Debug)]
 pub struct Account {
     id: AccountId,
-    wallet: Wallet,
+    /// Currency reported when a single row per client is expected.
+    default_currency: Currency,
+    wallets: BTreeMap<Currency, Wallet>,
+    /// Limits of the client tier.
+    limits: AccountLimits,
     pub locked: bool,
 }
 
 impl Account {
     pub fn new(id: AccountId) -> Self {
+        Self::new_with_currency(id, Currency::default())
+    }
+
+    pub fn new_with_currency(id: AccountId, default_currency: Currency) -> Self {
         Self {
             id,
-            wallet: Wallet::default(),
+            default_currency,
+            wallets: BTreeMap::new(),
+            limits: AccountLimits::default(),
             locked: false,
         }
     }
 
+    pub fn with_limits(self, limits: AccountLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub fn limits(&self) -> &AccountLimits {
+        &self.limits
+    }
+
+    /// Set a wallet from an opening balance, regardless of the lock and the limits.
+    pub fn open_balance(&mut self, balance: &WalletBalance) {
+        let wallet = self.wallet_mut(balance.currency);
+        wallet.amount = balance.total;
+        wallet.held = balance.held;
+        self.locked |= balance.locked;
+    }
+
+    /// Check a withdrawal against the tier, only for withdrawals the client asked for. The
+    /// amount is compared as is, in the currency of the wallet debited.
+    pub fn check_withdrawal_limit(&self, amount: Decimal) -> Result<(), AccountOperationError> {
+        match self.limits.max_withdrawal {
+            Some(max) if amount > max => {
+                Err(AccountOperationError::WithdrawalLimitExceeded(self.id, max))
+            }
+            _ => Ok(()),
+        }
+    }
+
     #[cfg(test)]
     pub fn new_with_wallet(id: AccountId, wallet: Wallet) -> Self {
-        Self {
-            id,
-            wallet,
-            locked: false,
-        }
+        let mut acc = Self::new(id);
+        acc.wallets.insert(Currency::default(), wallet);
+        acc
     }
 
-    fn is_locked(&self) -> Result<(), AccountOperationError> {
+    pub fn is_locked(&self) -> Result<(), AccountOperationError> {
         match self.locked {
             false => Ok(()),
             true => Err(AccountOperationError::AccountLocked(self.id)),
@@ -59,84 +106,258 @@ impl Account {
         self.id
     }
 
-    pub fn deposit(&mut self, amount: Decimal) -> Result<(), AccountOperationError> {
+    pub fn default_currency(&self) -> Currency {
+        self.default_currency
+    }
+
+    /// Wallet of a currency, an empty one if the account never used it.
+    pub fn wallet(&self, currency: Currency) -> &Wallet {
+        const EMPTY: &Wallet = &Wallet {
+            amount: Decimal::ZERO,
+            held: Decimal::ZERO,
+        };
+        self.wallets.get(&currency).unwrap_or(EMPTY)
+    }
+
+    fn wallet_mut(&mut self, currency: Currency) -> &mut Wallet {
+        self.wallets.entry(currency).or_default()
+    }
+
+    pub fn wallets(&self) -> impl Iterator<Item = (&Currency, &Wallet)> {
+        self.wallets.iter()
+    }
+
+    /// All wallets converted and summed up in a single currency, with the latest rates.
+    pub fn wallet_in(
+        &self,
+        to: Currency,
+        rates: &FxRates,
+    ) -> Result<Wallet, AccountOperationError> {
+        let mut total = Wallet::default();
+        for (currency, wallet) in self.wallets() {
+            let convert = |amount| {
+                rates
+                    .convert(amount, *currency, to, None)
+                    .map(|c| c.amount)
+                    .ok_or(AccountOperationError::MissingFxRate(*currency, to))
+            };
+            let held = convert(wallet.held)?;
+            let available = convert(wallet.available_funds())?;
+            // Convert the parts, not the total, so that available + held == total still holds.
+            total.held += held;
+            total.amount += available + held;
+        }
+        total.held = total.held.normalize();
+        total.amount = total.amount.normalize();
+        Ok(total)
+    }
+
+    fn wallet_balance(&self, currency: Currency, wallet: &Wallet) -> WalletBalance {
+        WalletBalance {
+            client: self.id,
+            currency,
+            available: wallet.available_funds(),
+            held: wallet.held,
+            total: wallet.amount,
+            locked: self.locked,
+        }
+    }
+
+    /// Balance of a wallet, empty if the account never used this currency.
+    pub fn balance(&self, currency: Currency) -> WalletBalance {
+        self.wallet_balance(currency, self.wallet(currency))
+    }
+
+    /// Balance of each wallet.
+    pub fn balances(&self) -> impl Iterator<Item = WalletBalance> + '_ {
+        self.wallets()
+            .map(|(currency, wallet)| self.wallet_balance(*currency, wallet))
+    }
+
+    /// Balance of all wallets converted in a single currency, see `wallet_in`.
+    pub fn balance_in(
+        &self,
+        currency: Currency,
+        rates: &FxRates,
+    ) -> Result<WalletBalance, AccountOperationError> {
+        Ok(self.wallet_balance(currency, &self.wallet_in(currency, rates)?))
+    }
+
+    pub fn deposit(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
+        self.deposit_with_fee(currency, amount, Decimal::ZERO)
+    }
+
+    /// Deposit an amount and take a fee from the account, both or none.
+    pub fn deposit_with_fee(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+        fee: Decimal,
+    ) -> Result<(), AccountOperationError> {
         self.is_locked()?;
 
-        if Decimal::ZERO >= amount {
+        if Decimal::ZERO >= amount || Decimal::ZERO > fee {
             return Err(AccountOperationError::NonPositiveAmount);
         }
 
-        self.wallet.amount = self
-            .wallet
+        let (id, max_balance) = (self.id, self.limits.max_balance);
+        let wallet = self.wallet_mut(currency);
+        let new_amount = wallet
             .amount
             .checked_add(amount)
             .ok_or(AccountOperationError::OverflowInWallet)?;
 
+        if fee > new_amount - wallet.held {
+            return Err(AccountOperationError::InsufficientFunds);
+        }
+        if let Some(max) = max_balance {
+            if new_amount - fee > max {
+                return Err(AccountOperationError::BalanceLimitExceeded(id, max));
+            }
+        }
+
+        wallet.amount = new_amount - fee;
+
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), AccountOperationError> {
+    pub fn withdraw(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
+        self.withdraw_with_fee(currency, amount, Decimal::ZERO)
+    }
+
+    /// Withdraw an amount and its fee, the account must afford both.
+    pub fn withdraw_with_fee(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+        fee: Decimal,
+    ) -> Result<(), AccountOperationError> {
         self.is_locked()?;
 
-        if Decimal::ZERO >= amount {
+        if Decimal::ZERO >= amount || Decimal::ZERO > fee {
             return Err(AccountOperationError::NonPositiveAmount);
         }
 
-        if amount > self.wallet.available_funds() {
+        let debit = amount
+            .checked_add(fee)
+            .ok_or(AccountOperationError::InsufficientFunds)?;
+        if debit > self.wallet(currency).available_funds() {
             return Err(AccountOperationError::InsufficientFunds);
         }
 
-        self.wallet.amount -= amount;
+        self.wallet_mut(currency).amount -= debit;
         Ok(())
     }
 
-    pub fn hold(&mut self, amount: Decimal) -> Result<(), AccountOperationError> {
-        self.is_locked()?;
+    /// Give back funds taken by a reversed operation, even to a locked account.
+    pub fn refund(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
+        if Decimal::ZERO >= amount {
+            return Err(AccountOperationError::NonPositiveAmount);
+        }
+
+        let wallet = self.wallet_mut(currency);
+        wallet.amount = wallet
+            .amount
+            .checked_add(amount)
+            .ok_or(AccountOperationError::OverflowInWallet)?;
+        Ok(())
+    }
 
+    /// Credit funds straight into the held funds, like a contested chargeback waiting for a
+    /// ruling. Like `refund`, the lock isn't checked as the scheme moves these funds.
+    pub fn credit_held(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
+        self.refund(currency, amount)?;
+        self.wallet_mut(currency).held += amount;
+        Ok(())
+    }
+
+    /// Release held funds without checking the lock, see `credit_held`.
+    pub fn release_held(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
         if Decimal::ZERO >= amount {
             return Err(AccountOperationError::NonPositiveAmount);
         }
 
-        // assuming that you can't hold what you don't have
-        if amount > self.wallet.available_funds() {
+        let wallet = self.wallet_mut(currency);
+        if amount > wallet.held {
             return Err(AccountOperationError::InsufficientFunds);
         }
-        self.wallet.held += amount;
+        wallet.held -= amount;
+        Ok(())
+    }
+
+    /// Remove held funds without checking the lock, see `credit_held`.
+    pub fn debit_held(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
+        self.release_held(currency, amount)?;
+        self.wallet_mut(currency).amount -= amount;
         Ok(())
     }
 
-    pub fn unhold(&mut self, amount: Decimal) -> Result<(), AccountOperationError> {
+    /// Take a fee coming with an operation that can't fail for lack of funds, like a chargeback
+    /// fee. The fee is limited to the available funds so the wallet never goes negative, the
+    /// amount actually taken is returned. Like `credit_held`, the lock isn't checked as the fee
+    /// comes with a chargeback.
+    pub fn charge_fee(
+        &mut self,
+        currency: Currency,
+        fee: Decimal,
+    ) -> Result<Decimal, AccountOperationError> {
+        if Decimal::ZERO > fee {
+            return Err(AccountOperationError::NonPositiveAmount);
+        }
+
+        let wallet = self.wallet_mut(currency);
+        let fee = fee.min(wallet.available_funds().max(Decimal::ZERO));
+        wallet.amount = wallet
+            .amount
+            .checked_sub(fee)
+            .ok_or(AccountOperationError::OverflowInWallet)?;
+        Ok(fee)
+    }
+
+    pub fn hold(
+        &mut self,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), AccountOperationError> {
         self.is_locked()?;
 
         if Decimal::ZERO >= amount {
             return Err(AccountOperationError::NonPositiveAmount);
         }
 
-        if amount > self.wallet.held {
+        // assuming that you can't hold what you don't have
+        if amount > self.wallet(currency).available_funds() {
             return Err(AccountOperationError::InsufficientFunds);
         }
-
-        self.wallet.held -= amount;
-
+        self.wallet_mut(currency).held += amount;
         Ok(())
     }
 }
 
-/// We use this Display impl to output an Account to a csv record.
-impl Display for Account {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        writeln!(
-            f,
-            "{},{},{},{},{}",
-            self.get_id(),
-            self.wallet.available_funds(),
-            self.wallet.held,
-            self.wallet.amount,
-            self.locked
-        )
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use rust_decimal_macros::dec;
@@ -144,13 +365,15 @@ mod tests {
     use super::*;
     use crate::errors::AccountOperationError::*;
 
+    const XXX: Currency = Currency::NONE;
+
     #[test]
     fn account_can_deposit_funds() {
         let mut acc = Account::new(0);
-        let _ = acc.deposit(dec!(1.773));
-        assert_eq!(acc.wallet.amount, dec!(1.773));
-        let _ = acc.deposit(dec!(1.664));
-        assert_eq!(acc.wallet.amount, dec!(3.437));
+        let _ = acc.deposit(XXX, dec!(1.773));
+        assert_eq!(acc.wallet(XXX).amount, dec!(1.773));
+        let _ = acc.deposit(XXX, dec!(1.664));
+        assert_eq!(acc.wallet(XXX).amount, dec!(3.437));
     }
 
     #[test]
@@ -158,7 +381,7 @@ mod tests {
         let mut acc = Account::new(0);
 
         let expected_error = Err(NonPositiveAmount);
-        let result = acc.deposit(dec!(-1));
+        let result = acc.deposit(XXX, dec!(-1));
         assert_eq!(result, expected_error);
     }
 
@@ -171,17 +394,17 @@ mod tests {
                 held: dec!(0),
             },
         );
-        let _ = acc.withdraw(dec!(1.773));
-        assert_eq!(acc.wallet.amount, dec!(1662.227));
-        let _ = acc.withdraw(dec!(1.664));
-        assert_eq!(acc.wallet.amount, dec!(1660.563));
+        let _ = acc.withdraw(XXX, dec!(1.773));
+        assert_eq!(acc.wallet(XXX).amount, dec!(1662.227));
+        let _ = acc.withdraw(XXX, dec!(1.664));
+        assert_eq!(acc.wallet(XXX).amount, dec!(1660.563));
     }
 
     #[test]
     fn account_cannot_withdraw_funds_with_empty_wallet() {
         let mut acc = Account::new(0);
         let expected_error = Err(InsufficientFunds);
-        let result = acc.withdraw(dec!(1.773));
+        let result = acc.withdraw(XXX, dec!(1.773));
         assert_eq!(result, expected_error);
     }
 
@@ -194,20 +417,20 @@ mod tests {
                 held: dec!(0),
             },
         );
-        let _ = acc.hold(dec!(10));
-        assert_eq!(acc.wallet.held, dec!(10));
+        let _ = acc.hold(XXX, dec!(10));
+        assert_eq!(acc.wallet(XXX).held, dec!(10));
     }
 
     #[test]
     fn account_cannot_hold_funds_with_an_empty_wallet() {
         let mut acc = Account::new(0);
         let expected_error = Err(InsufficientFunds);
-        let result = acc.withdraw(dec!(1.773));
+        let result = acc.withdraw(XXX, dec!(1.773));
         assert_eq!(result, expected_error);
     }
 
     #[test]
-    fn account_can_unhold_funds() {
+    fn account_can_release_held_funds() {
         let mut acc = Account::new_with_wallet(
             0,
             Wallet {
@@ -215,7 +438,112 @@ mod tests {
                 held: dec!(10),
             },
         );
-        let _ = acc.unhold(dec!(10));
-        assert_eq!(acc.wallet.held, dec!(0));
+        // Released even on a locked account, the other disputes of a charged back
+        // transaction still have to be closed
+        acc.locked = true;
+        assert_eq!(acc.release_held(XXX, dec!(11)), Err(InsufficientFunds));
+        let _ = acc.release_held(XXX, dec!(10));
+        assert_eq!(acc.wallet(XXX).held, dec!(0));
+        assert_eq!(acc.wallet(XXX).amount, dec!(1664));
+    }
+
+    #[test]
+    fn account_keeps_one_wallet_per_currency() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut acc = Account::new_with_currency(0, eur);
+        let _ = acc.deposit(eur, dec!(10));
+        let _ = acc.deposit(XXX, dec!(5));
+
+        assert_eq!(acc.withdraw(XXX, dec!(6)), Err(InsufficientFunds));
+        let _ = acc.hold(eur, dec!(8));
+        assert_eq!(acc.wallet(eur).available_funds(), dec!(2));
+        assert_eq!(acc.wallet(XXX).available_funds(), dec!(5));
+
+        assert_eq!(
+            acc.balance(eur),
+            WalletBalance {
+                client: 0,
+                currency: eur,
+                available: dec!(2),
+                held: dec!(8),
+                total: dec!(10),
+                locked: false,
+            }
+        );
+        assert_eq!(
+            acc.balances().map(|b| b.currency).collect::<Vec<_>>(),
+            vec![eur, XXX]
+        );
+    }
+
+    #[test]
+    fn account_pays_fees() {
+        let mut acc = Account::new(0);
+        assert_eq!(
+            acc.deposit_with_fee(XXX, dec!(1), dec!(2)),
+            Err(InsufficientFunds)
+        );
+        assert_eq!(acc.wallet(XXX).amount, dec!(0));
+
+        let _ = acc.deposit_with_fee(XXX, dec!(10), dec!(1));
+        assert_eq!(acc.wallet(XXX).amount, dec!(9));
+
+        // 9 is available but not 9 plus the fee
+        assert_eq!(
+            acc.withdraw_with_fee(XXX, dec!(9), dec!(0.5)),
+            Err(InsufficientFunds)
+        );
+        let _ = acc.withdraw_with_fee(XXX, dec!(8), dec!(0.5));
+        assert_eq!(acc.wallet(XXX).amount, dec!(0.5));
+
+        // A fee that can't be refused takes what is available, no more
+        assert_eq!(acc.charge_fee(XXX, dec!(0.2)), Ok(dec!(0.2)));
+        assert_eq!(acc.charge_fee(XXX, dec!(15)), Ok(dec!(0.3)));
+        assert_eq!(acc.wallet(XXX).available_funds(), dec!(0));
+    }
+
+    #[test]
+    fn account_limits() {
+        let mut acc = Account::new(0).with_limits(AccountLimits {
+            max_balance: Some(dec!(100)),
+            max_withdrawal: Some(dec!(10)),
+            disputes_allowed: false,
+        });
+        assert_eq!(acc.deposit(XXX, dec!(100)), Ok(()));
+        assert_eq!(
+            acc.deposit(XXX, dec!(1)),
+            Err(BalanceLimitExceeded(0, dec!(100)))
+        );
+        assert_eq!(acc.wallet(XXX).amount, dec!(100));
+        // Each wallet is capped on its own, in its currency
+        let eur: Currency = "EUR".parse().unwrap();
+        assert_eq!(acc.deposit(eur, dec!(100)), Ok(()));
+        assert_eq!(
+            acc.deposit(eur, dec!(1)),
+            Err(BalanceLimitExceeded(0, dec!(100)))
+        );
+        assert_eq!(acc.check_withdrawal_limit(dec!(10)), Ok(()));
+        assert_eq!(
+            acc.check_withdrawal_limit(dec!(10.01)),
+            Err(WithdrawalLimitExceeded(0, dec!(10)))
+        );
+        // Funds given back by the scheme are not capped
+        assert_eq!(acc.refund(XXX, dec!(1)), Ok(()));
+    }
+
+    #[test]
+    fn account_open_balance() {
+        let mut acc = Account::new(0);
+        acc.open_balance(&WalletBalance {
+            client: 0,
+            currency: XXX,
+            available: dec!(1.5),
+            held: dec!(3),
+            total: dec!(4.5),
+            locked: true,
+        });
+        assert_eq!(acc.wallet(XXX).available_funds(), dec!(1.5));
+        assert_eq!(acc.wallet(XXX).amount, dec!(4.5));
+        assert!(acc.locked);
     }
 }
@@ -0,0 +1,180 @@
+/// Dispute and chargeback analytics.
+/// Each account worker counts its dispute events and sums what its disputes hold and lost, the
+/// report adds a global row so risk can follow chargeback ratios.
+use std::fmt::{self, Display, Formatter};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    account::AccountId, errors::Result, fx::DEFAULT_SCALE, tasks::command::PaymentEngineCommand,
+};
+
+pub const DISPUTE_REPORT_HEADER: &str = "client,deposits,opened,resolved,charged_back,expired,\
+open,held,lost,dispute_ratio,chargeback_ratio,avg_resolution_secs\n";
+
+/// Dispute figures of one client, or of all of them once merged.
+/// Disputes are counted per transaction: the partial disputes open at the same time on a
+/// transaction are opened, and later closed, as one. Amounts are in the engine default currency.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisputeStats {
+    /// `None` for the global row.
+    pub client: Option<AccountId>,
+    /// Deposits and incoming transfers, the transactions a client can dispute.
+    pub deposits: u64,
+    pub opened: u64,
+    /// Disputes closed in favour of the client, from a resolve or a ruling.
+    pub resolved: u64,
+    pub charged_back: u64,
+    /// Disputes left open past their deadline, also counted as resolved or charged back.
+    pub expired: u64,
+    /// Disputes in progress or waiting for a ruling.
+    pub open: u64,
+    pub held: Decimal,
+    pub lost: Decimal,
+    /// Total time between opening and closing disputes, for the ones with timestamps.
+    pub resolution_secs: i64,
+    pub timed_resolutions: u64,
+}
+
+impl DisputeStats {
+    pub fn new(client: AccountId) -> Self {
+        Self {
+            client: Some(client),
+            ..Self::default()
+        }
+    }
+
+    /// Add the figures of another client.
+    pub fn merge(&mut self, other: &DisputeStats) {
+        self.deposits += other.deposits;
+        self.opened += other.opened;
+        self.resolved += other.resolved;
+        self.charged_back += other.charged_back;
+        self.expired += other.expired;
+        self.open += other.open;
+        self.held += other.held;
+        self.lost += other.lost;
+        self.resolution_secs += other.resolution_secs;
+        self.timed_resolutions += other.timed_resolutions;
+    }
+
+    pub fn record_resolution_time(&mut self, secs: i64) {
+        self.resolution_secs += secs;
+        self.timed_resolutions += 1;
+    }
+
+    /// Disputes opened per deposit.
+    pub fn dispute_ratio(&self) -> Decimal {
+        Self::ratio(self.opened, self.deposits)
+    }
+
+    /// Chargebacks per deposit.
+    pub fn chargeback_ratio(&self) -> Decimal {
+        Self::ratio(self.charged_back, self.deposits)
+    }
+
+    pub fn avg_resolution_secs(&self) -> Option<i64> {
+        (self.timed_resolutions > 0).then(|| self.resolution_secs / self.timed_resolutions as i64)
+    }
+
+    fn ratio(count: u64, deposits: u64) -> Decimal {
+        if deposits == 0 {
+            return Decimal::ZERO;
+        }
+        (Decimal::from(count) / Decimal::from(deposits))
+            .round_dp_with_strategy(DEFAULT_SCALE, RoundingStrategy::MidpointNearestEven)
+            .normalize()
+    }
+}
+
+impl Display for DisputeStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "{},", client)?,
+            None => write!(f, "all,")?,
+        }
+        writeln!(
+            f,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            self.deposits,
+            self.opened,
+            self.resolved,
+            self.charged_back,
+            self.expired,
+            self.open,
+            self.held,
+            self.lost,
+            self.dispute_ratio(),
+            self.chargeback_ratio(),
+            self.avg_resolution_secs()
+                .map(|secs| secs.to_string())
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Write one row per client, ordered by client, then the global row.
+pub async fn send_dispute_report<T: AsyncWrite + Unpin>(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    mut output: T,
+) -> Result<()> {
+    let (stats_sender, mut stats_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendDisputeStats(stats_sender))
+        .await?;
+
+    let mut clients = Vec::new();
+    while let Some(stats) = stats_receiver.recv().await {
+        clients.push(stats);
+    }
+    clients.sort_by_key(|stats: &DisputeStats| stats.client);
+
+    let mut global = DisputeStats::default();
+    output.write_all(DISPUTE_REPORT_HEADER.as_bytes()).await?;
+    for stats in clients.iter() {
+        global.merge(stats);
+        output.write_all(stats.to_string().as_bytes()).await?;
+    }
+    output.write_all(global.to_string().as_bytes()).await?;
+    output.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn dispute_stats_merge() {
+        let mut global = DisputeStats::default();
+        global.merge(&DisputeStats {
+            deposits: 2,
+            opened: 1,
+            charged_back: 1,
+            lost: dec!(1.5),
+            ..DisputeStats::new(1)
+        });
+        let mut stats = DisputeStats {
+            deposits: 1,
+            opened: 1,
+            open: 1,
+            held: dec!(2),
+            ..DisputeStats::new(2)
+        };
+        stats.record_resolution_time(100);
+        stats.record_resolution_time(51);
+        global.merge(&stats);
+
+        assert_eq!(stats.to_string(), "2,1,1,0,0,0,1,2,0,1,0,75\n");
+        assert_eq!(
+            global.to_string(),
+            "all,3,2,0,1,0,1,2,1.5,0.6667,0.3333,75\n"
+        );
+    }
+}
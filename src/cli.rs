@@ -0,0 +1,651 @@
+/// Command line parsing.
+/// We only have a handful of flags so we parse them by hand instead of pulling a dependency.
+use std::ops::RangeInclusive;
+
+use crate::{
+    account::AccountId,
+    currency::Currency,
+    engine::{DisputeLimits, EngineConfig},
+    errors::{PaymentEngineError, Result},
+    id_set::RejectedIdPolicy,
+    report::{AccountsReportMode, OutputFormat},
+    tasks::source::InputFormat,
+    transaction::DisputeResolution,
+    validation::RecordValidator,
+};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CliOptions {
+    pub input_path: String,
+    /// Guessed from the input file extension when not set.
+    pub input_format: Option<InputFormat>,
+    pub reuse_rejected_ids: bool,
+    pub default_currency: Currency,
+    pub per_currency: bool,
+    pub base_currency: Option<Currency>,
+    pub fx_rates_path: Option<String>,
+    pub fees_path: Option<String>,
+    pub risk_rules_path: Option<String>,
+    pub tiers_path: Option<String>,
+    pub known_clients_path: Option<String>,
+    pub strict_clients: bool,
+    /// Accounts report of a previous run to start from.
+    pub opening_balances_path: Option<String>,
+    /// File receiving the alerts raised by the risk rules.
+    pub alerts_path: Option<String>,
+    pub dispute_limits: DisputeLimits,
+    /// Output the dispute analytics instead of the accounts.
+    pub dispute_report: bool,
+    /// File receiving the report instead of the standard output.
+    pub output_path: Option<String>,
+    /// Format of the accounts report, guessed from the output file extension when not set.
+    pub output_format: Option<OutputFormat>,
+    /// Parquet file receiving the transactions of every account.
+    pub ledger_path: Option<String>,
+    /// CSV file receiving the input records rejected before reaching the engine.
+    pub rejections_path: Option<String>,
+    /// `reconcile` subcommand: compare the accounts to the balances of this file.
+    pub expected_balances_path: Option<String>,
+    /// Checks applied to every input record before it reaches the engine.
+    pub validator: RecordValidator,
+    /// Only read and check the input, outputting a summary instead of the accounts.
+    pub dry_run: bool,
+    /// Print the run summary on the standard error.
+    pub stats: bool,
+    /// JSON file receiving the run summary.
+    pub stats_json_path: Option<String>,
+    /// Address of the Prometheus metrics endpoint, e.g. `127.0.0.1:9100`.
+    pub metrics_addr: Option<String>,
+}
+
+impl CliOptions {
+    /// Parse the arguments, the first one being the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut args = args.into_iter().peekable();
+        let program = args.next().unwrap_or_default();
+        let mut options = CliOptions::default();
+        let mut input_path = None;
+
+        let reconcile = args.next_if(|arg| arg == "reconcile").is_some();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--reuse-rejected-ids" => options.reuse_rejected_ids = true,
+                "--per-currency" => options.per_currency = true,
+                "--dry-run" => options.dry_run = true,
+                "--stats" => options.stats = true,
+                "--metrics-addr" => {
+                    options.metrics_addr = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--stats-json" => {
+                    options.stats_json_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--dispute-report" => options.dispute_report = true,
+                "--base-currency" => {
+                    options.base_currency = Some(Self::value(&program, &arg, args.next())?.parse()?)
+                }
+                "--fx-rates" => {
+                    options.fx_rates_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--fees" => options.fees_path = Some(Self::value(&program, &arg, args.next())?),
+                "--risk-rules" => {
+                    options.risk_rules_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--strict-clients" => options.strict_clients = true,
+                "--known-clients" => {
+                    options.known_clients_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--opening-balances" => {
+                    options.opening_balances_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--expected" if reconcile => {
+                    options.expected_balances_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--input-format" => {
+                    options.input_format =
+                        Some(match Self::value(&program, &arg, args.next())?.as_str() {
+                            "csv" => InputFormat::Csv,
+                            "jsonl" => InputFormat::JsonLines,
+                            value => {
+                                return Err(PaymentEngineError::CommandLineError(format!(
+                                    "Invalid value {} for {}, expected csv or jsonl. {}",
+                                    value,
+                                    arg,
+                                    Self::usage(&program)
+                                )))
+                            }
+                        })
+                }
+                "--output" => options.output_path = Some(Self::value(&program, &arg, args.next())?),
+                "--output-format" => {
+                    options.output_format =
+                        Some(match Self::value(&program, &arg, args.next())?.as_str() {
+                            "csv" => OutputFormat::Csv,
+                            "tsv" => OutputFormat::Tsv,
+                            "json" => OutputFormat::Json,
+                            "jsonl" => OutputFormat::JsonLines,
+                            "parquet" => OutputFormat::Parquet,
+                            value => {
+                                return Err(PaymentEngineError::CommandLineError(format!(
+                                    "Invalid value {} for {}, expected csv, tsv, json, jsonl or \
+                                    parquet. {}",
+                                    value,
+                                    arg,
+                                    Self::usage(&program)
+                                )))
+                            }
+                        })
+                }
+                "--ledger" => options.ledger_path = Some(Self::value(&program, &arg, args.next())?),
+                "--rejections" => {
+                    options.rejections_path = Some(Self::value(&program, &arg, args.next())?)
+                }
+                "--max-scale" => {
+                    let value = Self::value(&program, &arg, args.next())?;
+                    options.validator.max_scale = Some(value.parse().map_err(|_| {
+                        PaymentEngineError::CommandLineError(format!(
+                            "Invalid number of decimal places {} for {}. {}",
+                            value,
+                            arg,
+                            Self::usage(&program)
+                        ))
+                    })?)
+                }
+                "--client-range" => {
+                    options.validator.clients = Self::client_range(&program, &arg, args.next())?
+                }
+                "--tiers" => options.tiers_path = Some(Self::value(&program, &arg, args.next())?),
+                "--alerts" => options.alerts_path = Some(Self::value(&program, &arg, args.next())?),
+                "--dispute-window" => {
+                    options.dispute_limits.filing_days =
+                        Some(Self::days(&program, &arg, args.next())?)
+                }
+                "--dispute-deadline" => {
+                    options.dispute_limits.resolution_days =
+                        Some(Self::days(&program, &arg, args.next())?)
+                }
+                "--dispute-default" => {
+                    options.dispute_limits.default_resolution =
+                        match Self::value(&program, &arg, args.next())?.as_str() {
+                            "cancel" => DisputeResolution::Cancelled,
+                            "chargeback" => DisputeResolution::ChargedBack,
+                            value => {
+                                return Err(PaymentEngineError::CommandLineError(format!(
+                                    "Invalid value {} for {}, expected cancel or chargeback. {}",
+                                    value,
+                                    arg,
+                                    Self::usage(&program)
+                                )))
+                            }
+                        }
+                }
+                "--default-currency" => {
+                    options.default_currency = Self::value(&program, &arg, args.next())?.parse()?
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentEngineError::CommandLineError(format!(
+                        "Unknown option {}. {}",
+                        flag,
+                        Self::usage(&program)
+                    )))
+                }
+                _ if input_path.is_none() => input_path = Some(arg),
+                _ => {
+                    return Err(PaymentEngineError::CommandLineError(format!(
+                        "Unexpected argument {}. {}",
+                        arg,
+                        Self::usage(&program)
+                    )))
+                }
+            }
+        }
+
+        options.input_path = input_path.ok_or_else(|| {
+            PaymentEngineError::CommandLineError(format!(
+                "Missing input file name. {}",
+                Self::usage(&program)
+            ))
+        })?;
+
+        if options.strict_clients && options.known_clients_path.is_none() {
+            return Err(PaymentEngineError::CommandLineError(format!(
+                "--strict-clients needs --known-clients. {}",
+                Self::usage(&program)
+            )));
+        }
+
+        if reconcile && options.expected_balances_path.is_none() {
+            return Err(PaymentEngineError::CommandLineError(format!(
+                "reconcile needs --expected. {}",
+                Self::usage(&program)
+            )));
+        }
+
+        if options.output_format.is_some()
+            && (reconcile || options.dispute_report || options.dry_run)
+        {
+            return Err(PaymentEngineError::CommandLineError(format!(
+                "--output-format only applies to the accounts report. {}",
+                Self::usage(&program)
+            )));
+        }
+
+        let report_modes = [
+            options.per_currency,
+            options.base_currency.is_some(),
+            options.dispute_report,
+            reconcile,
+            options.dry_run,
+        ];
+        if report_modes.iter().filter(|set| **set).count() > 1 {
+            return Err(PaymentEngineError::CommandLineError(format!(
+                "--per-currency, --base-currency, --dispute-report, --dry-run and reconcile are \
+                exclusive. {}",
+                Self::usage(&program)
+            )));
+        }
+
+        if options.dry_run
+            && (options.ledger_path.is_some()
+                || options.alerts_path.is_some()
+                || options.opening_balances_path.is_some()
+                || options.metrics_addr.is_some())
+        {
+            return Err(PaymentEngineError::CommandLineError(format!(
+                "--dry-run doesn't process the transactions, --ledger, --alerts, \
+                --opening-balances and --metrics-addr don't apply. {}",
+                Self::usage(&program)
+            )));
+        }
+
+        Ok(options)
+    }
+
+    fn value(program: &str, flag: &str, value: Option<String>) -> Result<String> {
+        value.ok_or_else(|| {
+            PaymentEngineError::CommandLineError(format!(
+                "Missing value for {}. {}",
+                flag,
+                Self::usage(program)
+            ))
+        })
+    }
+
+    fn days(program: &str, flag: &str, value: Option<String>) -> Result<u32> {
+        let value = Self::value(program, flag, value)?;
+        value.parse().map_err(|_| {
+            PaymentEngineError::CommandLineError(format!(
+                "Invalid number of days {} for {}. {}",
+                value,
+                flag,
+                Self::usage(program)
+            ))
+        })
+    }
+
+    /// `<min>-<max>`, both ends included.
+    fn client_range(
+        program: &str,
+        flag: &str,
+        value: Option<String>,
+    ) -> Result<RangeInclusive<AccountId>> {
+        let value = Self::value(program, flag, value)?;
+        let range = value
+            .split_once('-')
+            .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)));
+        match range {
+            Some((min, max)) if min <= max => Ok(min..=max),
+            _ => Err(PaymentEngineError::CommandLineError(format!(
+                "Invalid client range {} for {}, expected <min>-<max>. {}",
+                value,
+                flag,
+                Self::usage(program)
+            ))),
+        }
+    }
+
+    fn usage(program: &str) -> String {
+        format!(
+            "Usage: {} [reconcile --expected <balances>.csv] [--reuse-rejected-ids] [--default-currency <ISO 4217>] \
+            [--input-format csv|jsonl] [--max-scale <decimals>] [--client-range <min>-<max>] \
+            [--opening-balances <accounts>.csv] [--fx-rates <rates>.csv] \
+            [--fees <fees>.csv] [--known-clients <clients>.csv [--strict-clients]] [--tiers <tiers>.csv] [--risk-rules <rules>.csv] [--alerts <alerts>.csv] \
+            [--dispute-window <days>] [--dispute-deadline <days>] \
+            [--dispute-default cancel|chargeback] \
+            [--per-currency | --base-currency <ISO 4217> | --dispute-report | --dry-run] \
+            [--output <file>] [--output-format csv|tsv|json|jsonl|parquet] \
+            [--ledger <ledger>.parquet] [--rejections <rejections>.csv] \
+            [--stats] [--stats-json <stats>.json] [--metrics-addr <host:port>] <transactions>.csv|.jsonl",
+            program
+        )
+    }
+
+    pub fn engine_config(&self) -> EngineConfig {
+        EngineConfig {
+            rejected_id_policy: match self.reuse_rejected_ids {
+                true => RejectedIdPolicy::Reusable,
+                false => RejectedIdPolicy::Reserved,
+            },
+            default_currency: self.default_currency,
+            dispute_limits: self.dispute_limits.clone(),
+            strict_clients: self.strict_clients,
+            ..EngineConfig::default()
+        }
+    }
+
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
+            .unwrap_or_else(|| InputFormat::from_path(&self.input_path))
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        match (self.output_format, &self.output_path) {
+            (Some(format), _) => format,
+            (None, Some(path)) => OutputFormat::from_path(path),
+            (None, None) => OutputFormat::Csv,
+        }
+    }
+
+    pub fn report_mode(&self) -> AccountsReportMode {
+        match (self.per_currency, self.base_currency) {
+            (true, _) => AccountsReportMode::PerCurrency,
+            (false, Some(currency)) => AccountsReportMode::BaseCurrency(currency),
+            (false, None) => AccountsReportMode::DefaultCurrency,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn cli_parse_input() -> Result<()> {
+        let options = CliOptions::parse(args(&["engine", "tx.csv"]))?;
+        assert_eq!(options.input_path, "tx.csv");
+        assert_eq!(options.input_format(), InputFormat::Csv);
+        assert!(!options.reuse_rejected_ids);
+
+        let options = CliOptions::parse(args(&["engine", "tx.jsonl"]))?;
+        assert_eq!(options.input_format(), InputFormat::JsonLines);
+        let options = CliOptions::parse(args(&["engine", "--input-format", "jsonl", "tx.txt"]))?;
+        assert_eq!(options.input_format(), InputFormat::JsonLines);
+        assert!(CliOptions::parse(args(&["engine", "--input-format", "xml", "tx"])).is_err());
+
+        let options = CliOptions::parse(args(&["engine", "--reuse-rejected-ids", "tx.csv"]))?;
+        assert!(options.reuse_rejected_ids);
+        assert_eq!(
+            options.engine_config().rejected_id_policy,
+            RejectedIdPolicy::Reusable
+        );
+
+        assert!(CliOptions::parse(args(&["engine"])).is_err());
+        assert!(CliOptions::parse(args(&["engine", "--nope", "tx.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["engine", "a.csv", "b.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["engine", "tx.csv", "--default-currency"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_validation() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--max-scale",
+            "4",
+            "--client-range",
+            "1-100",
+            "tx.csv",
+        ]))?;
+        assert_eq!(
+            options.validator,
+            RecordValidator {
+                max_scale: Some(4),
+                clients: 1..=100,
+            }
+        );
+        assert!(CliOptions::parse(args(&["engine", "--client-range", "100-1", "tx.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["engine", "--max-scale", "-1", "tx.csv"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_fx() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--default-currency",
+            "eur",
+            "--per-currency",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.default_currency, "EUR".parse()?);
+        assert_eq!(options.report_mode(), AccountsReportMode::PerCurrency);
+
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--fx-rates",
+            "rates.csv",
+            "--base-currency",
+            "USD",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.fx_rates_path.as_deref(), Some("rates.csv"));
+        assert_eq!(
+            options.report_mode(),
+            AccountsReportMode::BaseCurrency("USD".parse()?)
+        );
+        assert!(CliOptions::parse(args(&[
+            "engine",
+            "--per-currency",
+            "--base-currency",
+            "USD",
+            "tx.csv"
+        ]))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_fees() -> Result<()> {
+        let options = CliOptions::parse(args(&["engine", "--fees", "fees.csv", "tx.csv"]))?;
+        assert_eq!(options.fees_path.as_deref(), Some("fees.csv"));
+        assert_eq!(options.risk_rules_path, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_accounts() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--risk-rules",
+            "rules.csv",
+            "--alerts",
+            "alerts.csv",
+            "--tiers",
+            "tiers.csv",
+            "--opening-balances",
+            "yesterday.csv",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.tiers_path.as_deref(), Some("tiers.csv"));
+        assert_eq!(
+            options.opening_balances_path.as_deref(),
+            Some("yesterday.csv")
+        );
+        assert_eq!(options.risk_rules_path.as_deref(), Some("rules.csv"));
+        assert_eq!(options.alerts_path.as_deref(), Some("alerts.csv"));
+
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--known-clients",
+            "clients.csv",
+            "--strict-clients",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.known_clients_path.as_deref(), Some("clients.csv"));
+        assert!(options.engine_config().strict_clients);
+        assert!(CliOptions::parse(args(&["engine", "--strict-clients", "tx.csv"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_dispute_windows() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--dispute-window",
+            "120",
+            "--dispute-deadline",
+            "45",
+            "--dispute-default",
+            "chargeback",
+            "tx.csv",
+        ]))?;
+        assert_eq!(
+            options.engine_config().dispute_limits,
+            DisputeLimits {
+                filing_days: Some(120),
+                resolution_days: Some(45),
+                default_resolution: DisputeResolution::ChargedBack,
+            }
+        );
+
+        assert!(CliOptions::parse(args(&["engine", "--dispute-window", "-1", "tx.csv"])).is_err());
+        assert!(
+            CliOptions::parse(args(&["engine", "--dispute-default", "maybe", "tx.csv"])).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_reports() -> Result<()> {
+        let options = CliOptions::parse(args(&["engine", "tx.csv"]))?;
+        assert_eq!(options.report_mode(), AccountsReportMode::DefaultCurrency);
+        assert_eq!(options.output_format(), OutputFormat::Csv);
+
+        let options = CliOptions::parse(args(&["engine", "--output", "out.json", "tx.csv"]))?;
+        assert_eq!(options.output_path.as_deref(), Some("out.json"));
+        assert_eq!(options.output_format(), OutputFormat::Json);
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--output",
+            "out.json",
+            "--output-format",
+            "tsv",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.output_format(), OutputFormat::Tsv);
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--output",
+            "accounts.parquet",
+            "--ledger",
+            "ledger.parquet",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.output_format(), OutputFormat::Parquet);
+        assert_eq!(options.ledger_path.as_deref(), Some("ledger.parquet"));
+        let options = CliOptions::parse(args(&["engine", "--rejections", "rej.csv", "tx.csv"]))?;
+        assert_eq!(options.rejections_path.as_deref(), Some("rej.csv"));
+
+        assert!(CliOptions::parse(args(&["engine", "--dispute-report", "tx.csv"]))?.dispute_report);
+        assert!(CliOptions::parse(args(&[
+            "engine",
+            "--per-currency",
+            "--dispute-report",
+            "tx.csv"
+        ]))
+        .is_err());
+        assert!(CliOptions::parse(args(&[
+            "engine",
+            "--dispute-report",
+            "--output-format",
+            "json",
+            "tx.csv"
+        ]))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_reconcile() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "reconcile",
+            "--expected",
+            "bank.csv",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.expected_balances_path.as_deref(), Some("bank.csv"));
+        assert_eq!(options.input_path, "tx.csv");
+        assert!(CliOptions::parse(args(&["engine", "reconcile", "tx.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["engine", "--expected", "bank.csv", "tx.csv"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_dry_run() -> Result<()> {
+        let options = CliOptions::parse(args(&["engine", "--dry-run", "tx.csv"]))?;
+        assert!(options.dry_run);
+        assert!(CliOptions::parse(args(&["engine", "--dry-run", "--stats", "tx.csv"]))?.stats);
+        assert!(
+            CliOptions::parse(args(&["engine", "--dry-run", "--per-currency", "tx.csv"])).is_err()
+        );
+        assert!(CliOptions::parse(args(&[
+            "engine",
+            "--dry-run",
+            "--metrics-addr",
+            "127.0.0.1:9100",
+            "tx.csv"
+        ]))
+        .is_err());
+        assert!(CliOptions::parse(args(&[
+            "engine",
+            "--dry-run",
+            "--ledger",
+            "ledger.parquet",
+            "tx.csv"
+        ]))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_stats() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--stats",
+            "--stats-json",
+            "stats.json",
+            "tx.csv",
+        ]))?;
+        assert!(options.stats);
+        assert_eq!(options.stats_json_path.as_deref(), Some("stats.json"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cli_parse_metrics() -> Result<()> {
+        let options = CliOptions::parse(args(&[
+            "engine",
+            "--metrics-addr",
+            "127.0.0.1:9100",
+            "tx.csv",
+        ]))?;
+        assert_eq!(options.metrics_addr.as_deref(), Some("127.0.0.1:9100"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+/// Registry of the known clients, loaded from a local CSV file with a `client` column.
+/// In strict mode the engine rejects the rows of any other client instead of opening an account.
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::{account::AccountId, csv::read_records, errors::Result};
+
+#[derive(Debug, Clone, Deserialize)]
+struct ClientRecord {
+    client: AccountId,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KnownClients {
+    clients: HashSet<AccountId>,
+}
+
+impl KnownClients {
+    pub fn new<I: IntoIterator<Item = AccountId>>(clients: I) -> Self {
+        Self {
+            clients: clients.into_iter().collect(),
+        }
+    }
+
+    pub async fn from_csv<R: AsyncRead + Unpin + Send>(reader: R) -> Result<Self> {
+        let rows: Vec<ClientRecord> = read_records(reader).await?;
+        Ok(Self::new(rows.into_iter().map(|row| row.client)))
+    }
+
+    pub fn contains(&self, client: AccountId) -> bool {
+        self.clients.contains(&client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn known_clients_from_csv() -> Result<()> {
+        let data = b"\
+client
+1
+ 2
+"
+        .as_slice();
+        let clients = KnownClients::from_csv(data).await?;
+
+        assert_eq!(clients, KnownClients::new([1, 2]));
+        assert!(clients.contains(2));
+        assert!(!clients.contains(3));
+
+        Ok(())
+    }
+}
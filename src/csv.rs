@@ -1,12 +1,12 @@
+use std::collections::HashSet;
+
 use rust_decimal::Decimal;
-use serde::Deserialize;
-use tokio::{
-    io::{AsyncWrite, AsyncWriteExt},
-    sync::mpsc,
-};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{io::AsyncRead, sync::mpsc};
 
 use crate::{
-    account::AccountId,
+    account::{AccountId, WalletBalance},
+    currency::Currency,
     errors::{PaymentEngineError, Result},
     tasks::command::{DisputeCommandAction, DisputeCommandData, PaymentEngineCommand},
     transaction::{Dispute, Transaction, TransactionId, TransactionKind},
@@ -16,73 +16,296 @@ use crate::{
 pub struct TransactionRecord {
     /// `type` is a foreign keyword.
     #[serde(rename = "type")]
-    type_: TransactionRecordType,
-    client: AccountId,
-    tx: TransactionId,
-    amount: Option<Decimal>,
+    pub type_: TransactionRecordType,
+    pub client: AccountId,
+    pub tx: TransactionId,
+    pub amount: Option<Decimal>,
+    /// Optional column, rows without it are booked in the default currency.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Target currency, only for conversions.
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    /// Destination client, only for transfers.
+    #[serde(default)]
+    pub to_client: Option<AccountId>,
+    /// Optional unix timestamp, driving the dispute time limits.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum TransactionRecordType {
+pub enum TransactionRecordType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Representment,
+    Prearbitration,
+    Arbitration,
+    Conversion,
+    Transfer,
 }
 
-impl TryInto<PaymentEngineCommand> for TransactionRecord {
-    type Error = PaymentEngineError;
+impl TransactionRecordType {
+    /// Name of the type in the `type` column.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Representment => "representment",
+            Self::Prearbitration => "prearbitration",
+            Self::Arbitration => "arbitration",
+            Self::Conversion => "conversion",
+            Self::Transfer => "transfer",
+        }
+    }
+}
 
-    fn try_into(self) -> std::result::Result<PaymentEngineCommand, Self::Error> {
-        match self.type_ {
+impl TransactionRecord {
+    /// A record without the optional columns.
+    pub fn new(
+        type_: TransactionRecordType,
+        client: AccountId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            type_,
+            client,
+            tx,
+            amount,
+            currency: None,
+            to_currency: None,
+            to_client: None,
+            timestamp: None,
+        }
+    }
+
+    /// Build the engine command of this record, `default_currency` being used when the record
+    /// has no currency.
+    pub fn into_command(self, default_currency: Currency) -> Result<PaymentEngineCommand> {
+        let currency = self.currency.unwrap_or(default_currency);
+        let command: Result<PaymentEngineCommand> = match self.type_ {
             TransactionRecordType::Deposit => {
-                let amount = self.amount.ok_or_else(Self::Error::InvalidAmountFormat)?;
+                let amount = self
+                    .amount
+                    .ok_or_else(PaymentEngineError::InvalidAmountFormat)?;
 
-                let tx = Transaction::new(TransactionKind::Deposit, self.tx, self.client, amount);
+                let tx = Transaction::new_with_currency(
+                    TransactionKind::Deposit,
+                    self.tx,
+                    self.client,
+                    amount,
+                    currency,
+                );
                 Ok(PaymentEngineCommand::TransactionCommand(tx.into()))
             }
             TransactionRecordType::Withdrawal => {
-                let amount = self.amount.ok_or_else(Self::Error::InvalidAmountFormat)?;
+                let amount = self
+                    .amount
+                    .ok_or_else(PaymentEngineError::InvalidAmountFormat)?;
+
+                let tx = Transaction::new_with_currency(
+                    TransactionKind::Withdrawal,
+                    self.tx,
+                    self.client,
+                    amount,
+                    currency,
+                );
+                Ok(PaymentEngineCommand::TransactionCommand(tx.into()))
+            }
+            TransactionRecordType::Conversion => {
+                let amount = self
+                    .amount
+                    .ok_or_else(PaymentEngineError::InvalidAmountFormat)?;
+                let to_currency = self
+                    .to_currency
+                    .ok_or_else(PaymentEngineError::MissingConversionCurrency)?;
+
+                let tx = Transaction::new_conversion(
+                    self.tx,
+                    self.client,
+                    amount,
+                    currency,
+                    to_currency,
+                );
+                Ok(PaymentEngineCommand::TransactionCommand(tx.into()))
+            }
+            TransactionRecordType::Transfer => {
+                let amount = self
+                    .amount
+                    .ok_or_else(PaymentEngineError::InvalidAmountFormat)?;
+                let to_client = self
+                    .to_client
+                    .ok_or_else(PaymentEngineError::MissingTransferDestination)?;
 
                 let tx =
-                    Transaction::new(TransactionKind::Withdrawal, self.tx, self.client, amount);
+                    Transaction::new_transfer(self.tx, self.client, to_client, amount, currency);
                 Ok(PaymentEngineCommand::TransactionCommand(tx.into()))
             }
             TransactionRecordType::Dispute => {
-                let d = Dispute::new(self.client, self.tx);
+                let d = Dispute::new_partial(self.client, self.tx, self.amount);
                 let cmd = DisputeCommandData::new(DisputeCommandAction::OpenDispute, d);
                 Ok(PaymentEngineCommand::DisputeCommand(cmd))
             }
             TransactionRecordType::Resolve => {
-                let d = Dispute::new(self.client, self.tx);
+                let d = Dispute::new_partial(self.client, self.tx, self.amount);
                 let cmd = DisputeCommandData::new(DisputeCommandAction::CancelDispute, d);
                 Ok(PaymentEngineCommand::DisputeCommand(cmd))
             }
             TransactionRecordType::Chargeback => {
-                let d = Dispute::new(self.client, self.tx);
+                let d = Dispute::new_partial(self.client, self.tx, self.amount);
                 let cmd = DisputeCommandData::new(DisputeCommandAction::ChargebackDispute, d);
                 Ok(PaymentEngineCommand::DisputeCommand(cmd))
             }
+            TransactionRecordType::Representment => {
+                let d = Dispute::new_partial(self.client, self.tx, self.amount);
+                let cmd = DisputeCommandData::new(DisputeCommandAction::RepresentDispute, d);
+                Ok(PaymentEngineCommand::DisputeCommand(cmd))
+            }
+            TransactionRecordType::Prearbitration => {
+                let d = Dispute::new(self.client, self.tx);
+                let cmd = DisputeCommandData::new(DisputeCommandAction::PreArbitrateDispute, d);
+                Ok(PaymentEngineCommand::DisputeCommand(cmd))
+            }
+            TransactionRecordType::Arbitration => {
+                let d = Dispute::new(self.client, self.tx);
+                let cmd = DisputeCommandData::new(DisputeCommandAction::ArbitrateDispute, d);
+                Ok(PaymentEngineCommand::DisputeCommand(cmd))
+            }
+        };
+        Ok(command?.with_timestamp(self.timestamp))
+    }
+}
+
+impl TryInto<PaymentEngineCommand> for TransactionRecord {
+    type Error = PaymentEngineError;
+
+    fn try_into(self) -> std::result::Result<PaymentEngineCommand, Self::Error> {
+        self.into_command(Currency::default())
+    }
+}
+
+/// A row of the accounts report, read back as a balance.
+#[derive(Debug, Clone, Deserialize)]
+struct AccountRecord {
+    client: AccountId,
+    /// Only in the per currency layout, rows without it are in the default currency.
+    #[serde(default)]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl AccountRecord {
+    fn into_balance(self, default_currency: Currency) -> Result<WalletBalance> {
+        if self.held < Decimal::ZERO {
+            return Err(PaymentEngineError::InvalidBalance(
+                self.client,
+                "negative held funds",
+            ));
         }
+        if self.available + self.held != self.total {
+            return Err(PaymentEngineError::InvalidBalance(
+                self.client,
+                "total is not available + held",
+            ));
+        }
+        Ok(WalletBalance {
+            client: self.client,
+            currency: self.currency.unwrap_or(default_currency),
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        })
     }
 }
 
-pub async fn send_accounts_csv_to_stdout<T: AsyncWrite + Unpin>(
-    engine_sender: mpsc::Sender<PaymentEngineCommand>,
-    mut output: T,
-) -> Result<()> {
-    let (csv_sender, mut csv_receiver) = mpsc::channel(12);
-    engine_sender
-        .send(PaymentEngineCommand::SendAccountsToCSV(csv_sender))
-        .await?;
+/// Read all the rows of a trimmed csv file with headers, as used by the configuration files.
+pub async fn read_records<T: DeserializeOwned, R: AsyncRead + Unpin + Send>(
+    reader: R,
+) -> Result<Vec<T>> {
+    let rows = read_numbered_records(reader).await?;
+    Ok(rows.into_iter().map(|(_, row)| row).collect())
+}
 
-    while let Some(record) = csv_receiver.recv().await {
-        output.write_all(record.as_bytes()).await?;
+/// Same as `read_records`, with the line of each row.
+async fn read_numbered_records<T: DeserializeOwned, R: AsyncRead + Unpin + Send>(
+    reader: R,
+) -> Result<Vec<(u64, T)>> {
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_reader(reader);
+
+    let headers = rdr.byte_headers().await?.clone();
+    let mut record = csv_async::ByteRecord::new();
+    let mut rows = Vec::new();
+    while rdr.read_byte_record(&mut record).await? {
+        let line = record.position().map_or(0, |p| p.line());
+        rows.push((line, record.deserialize(Some(&headers))?));
     }
+    Ok(rows)
+}
 
-    output.flush().await?;
+/// Read an accounts report in the default or per currency layout.
+/// A wallet listed twice is refused, one of the rows would silently win.
+pub async fn read_balances<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    default_currency: Currency,
+) -> Result<Vec<WalletBalance>> {
+    let mut wallets = HashSet::new();
+    let mut balances = Vec::new();
+    for (line, row) in read_numbered_records::<AccountRecord, _>(reader).await? {
+        let balance = row.into_balance(default_currency)?;
+        if !wallets.insert((balance.client, balance.currency)) {
+            return Err(PaymentEngineError::InvalidRecord(
+                line,
+                format!(
+                    "duplicated balance of client {} in {}",
+                    balance.client, balance.currency
+                ),
+            ));
+        }
+        balances.push(balance);
+    }
+    Ok(balances)
+}
 
+/// Seed the accounts from a report of a previous run.
+/// Fails on the first invalid row, a half loaded state is worse than none.
+pub async fn send_opening_balances<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    default_currency: Currency,
+) -> Result<()> {
+    let balances = read_balances(reader, default_currency).await?;
+    // Disputes aren't carried over, nothing could ever release held funds.
+    if let Some(balance) = balances.iter().find(|b| !b.held.is_zero()) {
+        return Err(PaymentEngineError::InvalidBalance(
+            balance.client,
+            "held funds without their disputes",
+        ));
+    }
+    // Nothing owed is carried over either, a run never takes more than the available funds.
+    if let Some(balance) = balances.iter().find(|b| b.available < Decimal::ZERO) {
+        return Err(PaymentEngineError::InvalidBalance(
+            balance.client,
+            "negative available funds",
+        ));
+    }
+    for balance in balances {
+        engine_sender
+            .send(PaymentEngineCommand::OpenBalance(balance))
+            .await?;
+    }
     Ok(())
 }
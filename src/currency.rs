@@ -0,0 +1,80 @@
+/// ISO 4217 currency codes.
+/// Rows without a currency are booked in a default currency, `XXX` being the ISO code for
+/// "no currency involved" so single currency inputs behave exactly as before.
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::errors::PaymentEngineError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const NONE: Currency = Currency(*b"XXX");
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters can be stored, see `FromStr`.
+        std::str::from_utf8(&self.0).unwrap_or("XXX")
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl FromStr for Currency {
+    type Err = PaymentEngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code: [u8; 3] = s
+            .trim()
+            .as_bytes()
+            .try_into()
+            .map_err(|_| PaymentEngineError::InvalidCurrency(s.to_string()))?;
+        if !code.iter().all(u8::is_ascii_alphabetic) {
+            return Err(PaymentEngineError::InvalidCurrency(s.to_string()));
+        }
+
+        Ok(Self(code.map(|c| c.to_ascii_uppercase())))
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = PaymentEngineError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn currency_from_str() {
+        assert_eq!("EUR".parse::<Currency>().unwrap().to_string(), "EUR");
+        assert_eq!("usd".parse::<Currency>().unwrap().to_string(), "USD");
+        assert_eq!(Currency::default().to_string(), "XXX");
+
+        for invalid in ["", "EU", "EURO", "E1R", "€"] {
+            assert_eq!(
+                invalid.parse::<Currency>(),
+                Err(PaymentEngineError::InvalidCurrency(invalid.to_string()))
+            );
+        }
+    }
+}
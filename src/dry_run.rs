@@ -0,0 +1,178 @@
+/// Validate-only runs: the input goes through the same reading and validation as a normal run
+/// and the transaction ids are checked, but nothing reaches the engine so no balance is computed.
+/// The checks of the ids are static: any reused id is a duplicate, whatever happened to the first
+/// transaction, and a dispute must reference an earlier transaction crediting its client. The
+/// clients are checked against the known clients like in a normal run.
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    engine::EngineConfig,
+    errors::{AccountOperationError, Result},
+    tasks::{
+        command::PaymentEngineCommand,
+        producer::{CommandReader, ReadStats, Rejection},
+        source::TransactionSource,
+    },
+    transaction::{Transaction, TransactionId},
+};
+
+pub const DRY_RUN_SUMMARY_HEADER: &str = "records,accepted,rejected\n";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRunReport {
+    /// Records read from the input, rejected ones included.
+    pub records: usize,
+    /// Records per `type`, unreadable records being left out.
+    pub rows_per_type: BTreeMap<&'static str, usize>,
+    pub rejections: Vec<Rejection>,
+}
+
+impl DryRunReport {
+    pub fn accepted(&self) -> usize {
+        self.records - self.rejections.len()
+    }
+
+    /// Figures of the input for the run summary, the rows rejected by the dry run included.
+    pub fn read_stats(&self) -> ReadStats {
+        ReadStats {
+            rows: self.records,
+            rows_per_type: self.rows_per_type.clone(),
+            rejections: self.rejections.clone(),
+            engine_rejections: Vec::new(),
+        }
+    }
+
+    /// Write the `records,accepted,rejected` summary.
+    pub async fn write_summary_csv<T: AsyncWrite + Unpin>(&self, mut output: T) -> Result<()> {
+        output.write_all(DRY_RUN_SUMMARY_HEADER.as_bytes()).await?;
+        output
+            .write_all(
+                format!(
+                    "{},{},{}\n",
+                    self.records,
+                    self.accepted(),
+                    self.rejections.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        output.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Check a command against the known clients and the transactions accepted so far.
+fn check_ids(
+    transactions: &mut HashMap<TransactionId, Transaction>,
+    config: &EngineConfig,
+    command: &PaymentEngineCommand,
+) -> Result<()> {
+    match command {
+        PaymentEngineCommand::TransactionCommand(tx_cmd) => {
+            config.check_client(tx_cmd.tx.account_id())?;
+            if let Some(to) = tx_cmd.tx.counterparty() {
+                config.check_client(to)?;
+            }
+            let id = tx_cmd.tx.id();
+            if transactions.contains_key(&id) {
+                return Err(AccountOperationError::DuplicatedTransaction(id).into());
+            }
+            transactions.insert(id, tx_cmd.tx.clone());
+        }
+        PaymentEngineCommand::DisputeCommand(dispute_cmd) => {
+            let dispute = &dispute_cmd.dispute;
+            match transactions.get(&dispute.tx_id()) {
+                Some(tx) if tx.is_credit_for(dispute.account_id()) => {}
+                _ => return Err(AccountOperationError::TransactionNotFound(dispute.tx_id()).into()),
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Read and check the whole input without processing it.
+pub async fn dry_run<S: TransactionSource>(
+    mut reader: CommandReader<S>,
+    config: &EngineConfig,
+) -> Result<DryRunReport> {
+    let mut report = DryRunReport::default();
+    let mut transactions = HashMap::new();
+    while let Some((position, command)) = reader.next_command().await? {
+        if let Err(e) = check_ids(&mut transactions, config, &command) {
+            log::error!("Failed to check record at {}: {}", position, e);
+            report.rejections.push(Rejection::new(position, &e));
+        }
+    }
+
+    let stats = reader.into_stats();
+    report.records = stats.rows;
+    report.rows_per_type = stats.rows_per_type;
+    report.rejections.extend(stats.rejections);
+    report.rejections.sort_by_key(|r| r.position.line);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::KnownClients, currency::Currency, tasks::source::CsvSource,
+        validation::RecordValidator,
+    };
+
+    #[tokio::test]
+    async fn dry_run_rejections() -> Result<()> {
+        let data = b"\
+type,client,tx,amount,to_client
+deposit,1,1,10,
+withdrawal,1,1,5,
+dispute,1,2,,
+transfer,1,3,2,2
+dispute,1,3,,
+dispute,2,3,,
+withdrawal,1,4,-1,
+resolve,2,3,,
+deposit,3,5,1,
+"
+        .as_slice();
+        let reader = CommandReader::new(
+            CsvSource::new(data),
+            Currency::default(),
+            RecordValidator::default(),
+        );
+        let config = EngineConfig {
+            known_clients: Some(KnownClients::new([1, 2])),
+            strict_clients: true,
+            ..EngineConfig::default()
+        };
+        let report = dry_run(reader, &config).await?;
+
+        let rejections: Vec<_> = report
+            .rejections
+            .iter()
+            .map(|r| (r.position.line, r.code))
+            .collect();
+        assert_eq!(
+            rejections,
+            vec![
+                (3, "duplicate_transaction"),
+                (4, "unknown_transaction"),
+                (6, "unknown_transaction"),
+                (8, "negative_amount"),
+                (10, "unknown_client"),
+            ]
+        );
+        assert_eq!(report.rows_per_type.get("deposit"), Some(&2));
+
+        let mut output = Vec::new();
+        report.write_summary_csv(&mut output).await?;
+        assert_eq!(output, b"records,accepted,rejected\n9,4,5\n");
+
+        Ok(())
+    }
+}
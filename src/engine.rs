@@ -1,75 +1,551 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use rust_decimal::Decimal;
 use tokio::{sync::mpsc, task::JoinHandle};
 
 use crate::{
     account::{Account, AccountId},
-    errors::Result,
+    clients::KnownClients,
+    currency::Currency,
+    errors::{AccountOperationError, PaymentEngineError, Result},
+    fees::FeeSchedule,
+    fx::FxRates,
+    id_set::{RejectedIdPolicy, TransactionIdRegistry, TransactionIdStatus},
+    metrics::{Metrics, Stage},
+    risk::RiskRules,
+    stats::OperationStats,
     tasks::{
-        command::{DisputeCommandData, PaymentEngineCommand, TransactionCommandData},
+        command::{
+            CommandOutcome, CommandReply, DisputeCommandAction, DisputeCommandData,
+            PaymentEngineCommand, ReplySender, TransactionCommandAction, TransactionCommandData,
+            TransactionOutcome,
+        },
         worker::AccountWorker,
     },
-    transaction::TransactionId,
+    tiers::AccountTiers,
+    transaction::{Dispute, DisputeResolution, Transaction, TransactionId},
 };
 
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Interval between two samples of the worker channels, going through every worker.
+const WORKER_QUEUES_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time limits of disputes, counted on the input timestamps. Rows without timestamp escape them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeLimits {
+    /// A dispute filed more than this many days after its transaction is rejected.
+    pub filing_days: Option<u32>,
+    /// A dispute still open this many days after being opened gets `default_resolution`.
+    pub resolution_days: Option<u32>,
+    pub default_resolution: DisputeResolution,
+}
+
+impl Default for DisputeLimits {
+    fn default() -> Self {
+        Self {
+            filing_days: None,
+            resolution_days: None,
+            default_resolution: DisputeResolution::Cancelled,
+        }
+    }
+}
+
+impl DisputeLimits {
+    pub fn filing_deadline(&self, transaction_at: i64) -> Option<i64> {
+        self.filing_days
+            .map(|days| transaction_at.saturating_add(i64::from(days) * SECONDS_PER_DAY))
+    }
+
+    pub fn resolution_deadline(&self, opened_at: i64) -> Option<i64> {
+        self.resolution_days
+            .map(|days| opened_at.saturating_add(i64::from(days) * SECONDS_PER_DAY))
+    }
+}
+
+/// Engine wide settings.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    pub rejected_id_policy: RejectedIdPolicy,
+    /// Currency reported for each account in the default report mode.
+    pub default_currency: Currency,
+    pub fx_rates: FxRates,
+    pub fees: FeeSchedule,
+    pub dispute_limits: DisputeLimits,
+    pub risk_rules: RiskRules,
+    pub tiers: AccountTiers,
+    pub known_clients: Option<KnownClients>,
+    /// Reject the rows of clients missing from `known_clients` instead of only warning.
+    pub strict_clients: bool,
+    /// Prometheus metrics recorded by the engine and the workers, if exported.
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl EngineConfig {
+    /// Check a client against the registry, if any.
+    pub fn check_client(
+        &self,
+        account_id: AccountId,
+    ) -> std::result::Result<(), AccountOperationError> {
+        let Some(known_clients) = &self.known_clients else {
+            return Ok(());
+        };
+        if known_clients.contains(account_id) {
+            return Ok(());
+        }
+        if self.strict_clients {
+            return Err(AccountOperationError::UnknownClient(account_id));
+        }
+        log::warn!("Client {} is not a known client", account_id);
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct PaymentEngine {
     pub receiver: mpsc::Receiver<PaymentEngineCommand>,
     account_workers: HashMap<AccountId, mpsc::Sender<PaymentEngineCommand>>,
     worker_joins: Vec<(AccountId, JoinHandle<Result<()>>)>,
-    processed_transaction_ids: HashSet<TransactionId>,
+    config: Arc<EngineConfig>,
+    transaction_ids: TransactionIdRegistry,
+    // Outcomes are unbounded so a worker never blocks on the engine while the engine is blocked
+    // on that worker's full channel.
+    outcome_sender: mpsc::UnboundedSender<TransactionOutcome>,
+    outcome_receiver: mpsc::UnboundedReceiver<TransactionOutcome>,
+    /// Worker in charge of each transaction dispatched and not reported back yet. The engine
+    /// keeps a sender of the outcomes itself, a dead worker wouldn't close the channel.
+    pending_outcomes: HashMap<TransactionId, AccountId>,
+    /// The same pending transactions indexed by the account in charge of them.
+    pending_accounts: HashMap<AccountId, HashSet<TransactionId>>,
+    /// Accounts with a command applied, the other workers were only opened by failed rows.
+    active_accounts: HashSet<AccountId>,
+    /// Applied transfers with the amount not charged back yet, a chargeback on the destination
+    /// has to refund the source. Fully charged back transfers are dropped.
+    transfers: HashMap<TransactionId, Transaction>,
+    /// Latest input timestamp, the stream's clock.
+    clock: Option<i64>,
+    /// Resolution deadlines of the disputes opened with a timestamp:
+    /// `(deadline, account_id, tx_id, opened_at)`, earliest first.
+    dispute_deadlines: BinaryHeap<Reverse<(i64, AccountId, TransactionId, i64)>>,
+    /// Transfers and the commands refused before reaching a worker, the workers count the rest.
+    stats: OperationStats,
+    /// Last sample of the worker channels, for the metrics.
+    worker_queues_sampled_at: Option<Instant>,
 }
 
 impl PaymentEngine {
     pub fn new(receiver: mpsc::Receiver<PaymentEngineCommand>) -> Self {
+        Self::new_with_config(receiver, EngineConfig::default())
+    }
+
+    pub fn new_with_config(
+        receiver: mpsc::Receiver<PaymentEngineCommand>,
+        config: EngineConfig,
+    ) -> Self {
+        let (outcome_sender, outcome_receiver) = mpsc::unbounded_channel();
         Self {
             receiver,
             account_workers: HashMap::new(),
             worker_joins: Vec::new(),
-            processed_transaction_ids: HashSet::new(),
+            transaction_ids: TransactionIdRegistry::new(config.rejected_id_policy),
+            config: Arc::new(config),
+            outcome_sender,
+            outcome_receiver,
+            pending_outcomes: HashMap::new(),
+            pending_accounts: HashMap::new(),
+            active_accounts: HashSet::new(),
+            transfers: HashMap::new(),
+            clock: None,
+            dispute_deadlines: BinaryHeap::new(),
+            stats: OperationStats::default(),
+            worker_queues_sampled_at: None,
         }
     }
 
     pub async fn handle(&mut self, cmd: PaymentEngineCommand) -> Result<()> {
+        let Some(metrics) = self.config.metrics.clone() else {
+            return self.handle_command(cmd).await;
+        };
+        self.observe_queues(&metrics);
+        let name = cmd.name();
+        let started = Instant::now();
+        let result = self.handle_command(cmd).await;
+        metrics.observe_command(Stage::Engine, name, started.elapsed(), &result);
+        metrics.set_active_workers(self.account_workers.len());
+        result
+    }
+
+    /// Sample the depth of the engine channel, the command being handled included, and every
+    /// `WORKER_QUEUES_SAMPLE_INTERVAL` of the worker channels.
+    fn observe_queues(&mut self, metrics: &Metrics) {
+        metrics.observe_queue(
+            "engine",
+            self.receiver.len() + 1,
+            self.receiver.max_capacity(),
+        );
+        if self
+            .worker_queues_sampled_at
+            .is_some_and(|at| at.elapsed() < WORKER_QUEUES_SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        self.worker_queues_sampled_at = Some(Instant::now());
+        metrics.observe_worker_queues(self.account_workers.values().map(|sender| {
+            (
+                sender.max_capacity() - sender.capacity(),
+                sender.max_capacity(),
+            )
+        }));
+    }
+
+    async fn handle_command(&mut self, cmd: PaymentEngineCommand) -> Result<()> {
         log::debug!("command received: {:?}", cmd);
+        if let Some(timestamp) = cmd.timestamp() {
+            self.advance_clock(timestamp).await;
+        }
+
         match cmd {
             PaymentEngineCommand::TransactionCommand(tx) => self.handle_transaction(tx).await,
             PaymentEngineCommand::DisputeCommand(d) => self.handle_dispute(d).await,
-            PaymentEngineCommand::SendAccountsToCSV(sender) => {
-                self.handle_send_accounts_to_csv(sender).await
+            PaymentEngineCommand::OpenBalance(balance) => {
+                self.config.check_client(balance.client)?;
+                self.active_accounts.insert(balance.client);
+                self.send_to_worker(balance.client, PaymentEngineCommand::OpenBalance(balance))
+                    .await
+            }
+            PaymentEngineCommand::SendAccounts(sender, mode) => {
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendAccounts(sender.clone(), mode))
+                        .await?;
+                }
+                Ok(())
+            }
+            PaymentEngineCommand::SendDisputeStats(sender) => {
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendDisputeStats(sender.clone()))
+                        .await?;
+                }
+                Ok(())
+            }
+            PaymentEngineCommand::SendRiskAlerts(sender) => {
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendRiskAlerts(sender.clone()))
+                        .await?;
+                }
+                Ok(())
+            }
+            PaymentEngineCommand::SendWalletStatements(sender) => {
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendWalletStatements(sender.clone()))
+                        .await?;
+                }
+                Ok(())
+            }
+            PaymentEngineCommand::SendLedger(sender) => {
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendLedger(sender.clone()))
+                        .await?;
+                }
+                Ok(())
+            }
+            PaymentEngineCommand::SendRunStats(sender) => {
+                sender.send(self.stats.clone()).await?;
+                for (_, worker_sender) in self.account_workers.iter() {
+                    worker_sender
+                        .send(PaymentEngineCommand::SendRunStats(sender.clone()))
+                        .await?;
+                }
+                Ok(())
             }
         }?;
 
         Ok(())
     }
 
-    async fn handle_send_accounts_to_csv(&self, chan: mpsc::Sender<String>) -> Result<()> {
-        chan.send(String::from("client,available,held,total,locked\n"))
-            .await?;
-        for (_, worker_sender) in self.account_workers.iter() {
-            worker_sender
-                .send(PaymentEngineCommand::SendAccountsToCSV(chan.clone()))
-                .await?;
+    /// Move the stream's clock forward and resolve the disputes left open past their deadline.
+    async fn advance_clock(&mut self, timestamp: i64) {
+        if self.clock.is_some_and(|clock| clock >= timestamp) {
+            return;
+        }
+        self.clock = Some(timestamp);
+
+        while let Some(&Reverse((deadline, account_id, tx_id, opened_at))) =
+            self.dispute_deadlines.peek()
+        {
+            if deadline > timestamp {
+                break;
+            }
+            self.dispute_deadlines.pop();
+
+            let d = Dispute::new(account_id, tx_id).with_timestamp(Some(opened_at));
+            let cmd = DisputeCommandData::new(DisputeCommandAction::ExpireDispute, d);
+            if let Err(e) = self.handle_dispute(cmd).await {
+                log::error!("Failed to expire dispute of transaction {}: {}", tx_id, e);
+            }
         }
-        Ok(())
     }
 
     async fn handle_transaction(&mut self, cmd: TransactionCommandData) -> Result<()> {
         let transaction_id = cmd.tx.id();
 
-        if self.processed_transaction_ids.contains(&transaction_id) {
-            panic!("duplicated tx")
+        let client_check = self.config.check_client(cmd.tx.account_id()).and_then(|_| {
+            match cmd.tx.counterparty() {
+                Some(to) => self.config.check_client(to),
+                None => Ok(()),
+            }
+        });
+        if let Err(e) = client_check {
+            self.stats.reject(&e);
+            if let Some(reply) = &cmd.reply {
+                reply.send(Err(e.clone())).await;
+            }
+            return Err(e.into());
+        }
+
+        self.collect_outcomes();
+        if self.transaction_ids.policy() == RejectedIdPolicy::Reusable {
+            // The id may become available again if the pending transaction gets rejected.
+            self.wait_for_outcome(transaction_id).await;
+        }
+
+        if !self.transaction_ids.is_available(transaction_id) {
+            let e = AccountOperationError::DuplicatedTransaction(transaction_id);
+            self.stats.reject(&e);
+            if let Some(reply) = &cmd.reply {
+                reply.send(Err(e.clone())).await;
+            }
+            return Err(e.into());
+        }
+
+        // Mark the id before dispatching, the worker may report back before `send` returns.
+        self.transaction_ids.mark_seen(transaction_id);
+
+        if cmd.action == TransactionCommandAction::Transfer {
+            return self.handle_transfer(cmd).await;
         }
 
         let account_id = cmd.tx.account_id();
-        let send_cmd = PaymentEngineCommand::TransactionCommand(cmd);
-        match self.account_workers.get(&account_id) {
-            Some(s) => s.send(send_cmd).await?,
-            None => self.create_account_worker(account_id, send_cmd).await?,
+        self.pending_outcomes.insert(transaction_id, account_id);
+        self.pending_accounts
+            .entry(account_id)
+            .or_default()
+            .insert(transaction_id);
+        let sent = self
+            .send_to_worker(account_id, PaymentEngineCommand::TransactionCommand(cmd))
+            .await;
+        if sent.is_err() {
+            self.record_outcome(&TransactionOutcome::Rejected(transaction_id));
+        }
+
+        sent
+    }
+
+    /// Record every outcome already reported by the workers without waiting.
+    fn collect_outcomes(&mut self) {
+        while let Ok(outcome) = self.outcome_receiver.try_recv() {
+            self.record_outcome(&outcome);
+        }
+    }
+
+    fn record_outcome(&mut self, outcome: &TransactionOutcome) {
+        let transaction_id = outcome.tx_id();
+        if let Some(account_id) = self.pending_outcomes.remove(&transaction_id) {
+            if let Some(pending) = self.pending_accounts.get_mut(&account_id) {
+                pending.remove(&transaction_id);
+                if pending.is_empty() {
+                    self.pending_accounts.remove(&account_id);
+                }
+            }
+            if let TransactionOutcome::Applied(_) = outcome {
+                self.active_accounts.insert(account_id);
+            }
+        }
+        self.transaction_ids.record(outcome);
+    }
+
+    /// Whether a command has been applied on an account, waiting for the outcomes still pending
+    /// on it. Accounts only opened by failed rows neither take disputes nor receive transfers.
+    async fn is_active(&mut self, account_id: AccountId) -> bool {
+        self.collect_outcomes();
+        while !self.active_accounts.contains(&account_id) {
+            if !self.pending_accounts.contains_key(&account_id) {
+                return false;
+            }
+            self.next_outcome(account_id).await;
+        }
+        true
+    }
+
+    /// Wait until the worker in charge of a pending transaction reports its outcome. A worker
+    /// gone before reporting it leaves the transaction rejected.
+    async fn wait_for_outcome(&mut self, transaction_id: TransactionId) {
+        while self.transaction_ids.status(transaction_id) == Some(TransactionIdStatus::Seen) {
+            let Some(&account_id) = self.pending_outcomes.get(&transaction_id) else {
+                break;
+            };
+            self.next_outcome(account_id).await;
+        }
+    }
+
+    /// Record the next outcome reported by any worker, or reject every transaction pending on
+    /// an account once its worker has stopped and dropped its end of the channel.
+    async fn next_outcome(&mut self, account_id: AccountId) {
+        let Some(worker) = self.account_workers.get(&account_id).cloned() else {
+            self.reject_pending(account_id);
+            return;
+        };
+        tokio::select! {
+            biased;
+            Some(outcome) = self.outcome_receiver.recv() => self.record_outcome(&outcome),
+            _ = worker.closed() => {
+                self.collect_outcomes();
+                self.reject_pending(account_id);
+            }
+        }
+    }
+
+    fn reject_pending(&mut self, account_id: AccountId) {
+        let pending = self
+            .pending_accounts
+            .remove(&account_id)
+            .unwrap_or_default();
+        for transaction_id in pending {
+            log::error!(
+                "Account worker {} stopped before reporting transaction {}",
+                account_id,
+                transaction_id
+            );
+            self.record_outcome(&TransactionOutcome::Rejected(transaction_id));
+        }
+    }
+
+    /// Apply a transfer on both accounts, or on none of them.
+    /// The engine waits for each leg so no other command can slip in between.
+    async fn handle_transfer(&mut self, cmd: TransactionCommandData) -> Result<()> {
+        let tx = cmd.tx;
+        let result = self.apply_transfer(&tx).await;
+
+        let outcome = match result {
+            Ok(Ok(_)) => {
+                self.active_accounts.insert(tx.account_id());
+                self.transfers.insert(tx.id(), tx.clone());
+                TransactionOutcome::Applied(tx.id())
+            }
+            _ => TransactionOutcome::Rejected(tx.id()),
+        };
+        self.record_outcome(&outcome);
+
+        let reply = result?;
+        match &reply {
+            Ok(_) => self.stats.applied += 1,
+            Err(e) => self.stats.reject(e),
+        }
+        if let Some(sender) = &cmd.reply {
+            sender.send(reply.clone()).await;
+        }
+        reply.map(|_| ()).map_err(PaymentEngineError::from)
+    }
+
+    async fn apply_transfer(&mut self, tx: &Transaction) -> Result<CommandReply> {
+        let to = match tx.counterparty() {
+            Some(to) if to != tx.account_id() => to,
+            Some(_) => {
+                return Ok(Err(AccountOperationError::InvalidTransfer(
+                    tx.id(),
+                    "source and destination are the same client",
+                )))
+            }
+            None => {
+                return Ok(Err(AccountOperationError::InvalidTransfer(
+                    tx.id(),
+                    "has no destination",
+                )))
+            }
+        };
+        // Like a dispute, a transfer never opens an account.
+        if !self.is_active(to).await {
+            return Ok(Err(AccountOperationError::UnknownClient(to)));
+        }
+
+        let leg = |action| {
+            PaymentEngineCommand::TransactionCommand(TransactionCommandData::new(
+                action,
+                tx.clone(),
+            ))
+        };
+
+        let debit = self
+            .request(
+                tx.account_id(),
+                leg(TransactionCommandAction::TransferDebit),
+            )
+            .await?;
+        if debit.is_err() {
+            return Ok(debit);
+        }
+
+        let credit = self
+            .request(to, leg(TransactionCommandAction::TransferCredit))
+            .await?;
+        if credit.is_err() {
+            let rollback = self
+                .request(
+                    tx.account_id(),
+                    leg(TransactionCommandAction::TransferRollback),
+                )
+                .await?;
+            if let Err(e) = rollback {
+                log::error!("Failed to rollback transfer {}: {}", tx.id(), e);
+            }
         }
 
-        self.processed_transaction_ids.insert(transaction_id);
+        Ok(credit)
+    }
+
+    /// Send a command to a worker and wait for its outcome.
+    async fn request(
+        &mut self,
+        account_id: AccountId,
+        cmd: PaymentEngineCommand,
+    ) -> Result<CommandReply> {
+        let (sender, mut receiver) = mpsc::channel(1);
+        self.send_to_worker(account_id, cmd.with_reply(ReplySender::new(sender)))
+            .await?;
+        receiver.recv().await.ok_or_else(|| {
+            PaymentEngineError::TokioMpscError(format!(
+                "Account worker {} dropped a command without reply",
+                account_id
+            ))
+        })
+    }
 
+    async fn send_to_worker(
+        &mut self,
+        account_id: AccountId,
+        cmd: PaymentEngineCommand,
+    ) -> Result<()> {
+        match self.account_workers.get(&account_id) {
+            Some(s) => {
+                if let Some(metrics) = &self.config.metrics {
+                    // The engine is about to wait on this worker
+                    if s.capacity() == 0 {
+                        metrics.observe_saturated("workers");
+                    }
+                }
+                s.send(cmd).await?
+            }
+            None => self.create_account_worker(account_id, cmd).await?,
+        }
         Ok(())
     }
 
@@ -79,11 +555,22 @@ impl PaymentEngine {
         cmd: PaymentEngineCommand,
     ) -> Result<()> {
         let (sender, receiver) = mpsc::channel(32);
-        let mut account_worker = AccountWorker::new(receiver, Account::new(account_id));
+        let mut account_worker = AccountWorker::new(
+            receiver,
+            Account::new_with_currency(account_id, self.config.default_currency)
+                .with_limits(self.config.tiers.limits(account_id)),
+            self.config.clone(),
+            self.outcome_sender.clone(),
+        );
         let join = tokio::spawn(async move {
             while let Some(cmd) = account_worker.receiver.recv().await {
+                let started = Instant::now();
+                let result = account_worker.handle(&cmd).await;
+                if let Some(metrics) = &account_worker.config().metrics {
+                    metrics.observe_command(Stage::Worker, cmd.name(), started.elapsed(), &result);
+                }
                 // Do not abort worker on command handling errors
-                if let Err(e) = account_worker.handle(&cmd).await {
+                if let Err(e) = result {
                     log::error!(
                         "AccountWorker with id: {} failed to handle command {:?}: {}",
                         account_worker.get_id(),
@@ -103,12 +590,103 @@ impl PaymentEngine {
 
     async fn handle_dispute(&mut self, cmd: DisputeCommandData) -> Result<()> {
         let account_id = cmd.dispute.account_id();
-        let send_cmd = PaymentEngineCommand::DisputeCommand(cmd);
-        match self.account_workers.get(&account_id) {
-            Some(s) => s.send(send_cmd).await?,
-            None => self.create_account_worker(account_id, send_cmd).await?,
+        let tx_id = cmd.dispute.tx_id();
+
+        // A dispute can only target an existing account, it never opens one.
+        if !self.is_active(account_id).await {
+            let e = AccountOperationError::UnknownClient(account_id);
+            if cmd.action != DisputeCommandAction::ExpireDispute {
+                self.stats.reject(&e);
+            }
+            if let Some(reply) = &cmd.reply {
+                reply.send(Err(e.clone())).await;
+            }
+            return Err(e.into());
         }
-        Ok(())
+        if cmd.action == DisputeCommandAction::OpenDispute {
+            if let Some(opened_at) = cmd.dispute.timestamp() {
+                if let Some(deadline) = self.config.dispute_limits.resolution_deadline(opened_at) {
+                    self.dispute_deadlines
+                        .push(Reverse((deadline, account_id, tx_id, opened_at)));
+                }
+            }
+        }
+
+        let charges_back = match cmd.action {
+            DisputeCommandAction::ChargebackDispute => true,
+            DisputeCommandAction::ExpireDispute => {
+                self.config.dispute_limits.default_resolution == DisputeResolution::ChargedBack
+            }
+            _ => false,
+        };
+        if charges_back {
+            if let Some(transfer) = self.transfers.get(&tx_id) {
+                if transfer.counterparty() == Some(account_id) {
+                    let transfer = transfer.clone();
+                    return self.handle_transfer_chargeback(cmd, transfer).await;
+                }
+            }
+        }
+
+        self.send_to_worker(account_id, PaymentEngineCommand::DisputeCommand(cmd))
+            .await
+    }
+
+    /// Charging back a transfer reverses it: the destination loses the funds, the source gets
+    /// them back.
+    async fn handle_transfer_chargeback(
+        &mut self,
+        mut cmd: DisputeCommandData,
+        transfer: Transaction,
+    ) -> Result<()> {
+        let caller_reply = cmd.reply.take();
+        let account_id = cmd.dispute.account_id();
+        let reply = self
+            .request(account_id, PaymentEngineCommand::DisputeCommand(cmd))
+            .await?;
+
+        // An expired dispute closed in the meantime has nothing to refund.
+        let charged_back = match reply {
+            Ok(CommandOutcome::DisputeUpdated(_, _, amount)) if !amount.is_zero() => Some(amount),
+            _ => None,
+        };
+        if let Some(amount) = charged_back {
+            // A partial chargeback only refunds its part, the transfer stays for the rest.
+            let refunded = Transaction::new_transfer(
+                transfer.id(),
+                transfer.account_id(),
+                transfer.counterparty().unwrap_or_default(),
+                amount,
+                transfer.currency(),
+            );
+            let refund = PaymentEngineCommand::TransactionCommand(TransactionCommandData::new(
+                TransactionCommandAction::TransferRefund,
+                refunded,
+            ));
+            if let Err(e) = self.request(transfer.account_id(), refund).await? {
+                log::error!("Failed to refund transfer {}: {}", transfer.id(), e);
+            }
+
+            let remaining = transfer.amount() - amount;
+            if remaining > Decimal::ZERO {
+                let remaining = Transaction::new_transfer(
+                    transfer.id(),
+                    transfer.account_id(),
+                    transfer.counterparty().unwrap_or_default(),
+                    remaining,
+                    transfer.currency(),
+                )
+                .with_timestamp(transfer.timestamp());
+                self.transfers.insert(transfer.id(), remaining);
+            } else {
+                self.transfers.remove(&transfer.id());
+            }
+        }
+
+        if let Some(sender) = caller_reply {
+            sender.send(reply.clone()).await;
+        }
+        reply.map(|_| ()).map_err(PaymentEngineError::from)
     }
 
     pub async fn shutdown(&mut self) {
@@ -131,22 +709,1278 @@ impl PaymentEngine {
 mod tests {
     use super::*;
     use crate::errors::Result;
-    use crate::transaction::{Transaction, TransactionKind};
+    use crate::export::collect_ledger;
+    use crate::fees::{FeeOperation, FeeRule};
+    use crate::report::{send_accounts_report, AccountsReportMode, DelimitedSink};
+    use crate::risk::{RiskAction, RiskRule, RiskRuleKind};
+    use crate::tasks::command::{send_and_wait, DisputeCommandAction};
+    use crate::tasks::{producer::TransactionProducer, source::CsvSource};
+    use crate::transaction::{DisputeStatus, TransactionKind};
     use rust_decimal_macros::dec;
 
+    /// Run an engine in the background like the CLI does.
+    fn spawn_engine(config: EngineConfig) -> (mpsc::Sender<PaymentEngineCommand>, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(2);
+        let mut engine = PaymentEngine::new_with_config(receiver, config);
+        let join = tokio::spawn(async move {
+            while let Some(cmd) = engine.receiver.recv().await {
+                let _ = engine.handle(cmd).await;
+            }
+            engine.shutdown().await;
+        });
+        (sender, join)
+    }
+
+    /// Command of a transaction as built by the reader.
+    fn command(tx: Transaction) -> PaymentEngineCommand {
+        PaymentEngineCommand::TransactionCommand(tx.into())
+    }
+
+    fn deposit(id: TransactionId, account_id: AccountId, amount: Decimal) -> PaymentEngineCommand {
+        command(Transaction::new(
+            TransactionKind::Deposit,
+            id,
+            account_id,
+            amount,
+        ))
+    }
+
+    fn withdrawal(
+        id: TransactionId,
+        account_id: AccountId,
+        amount: Decimal,
+    ) -> PaymentEngineCommand {
+        command(Transaction::new(
+            TransactionKind::Withdrawal,
+            id,
+            account_id,
+            amount,
+        ))
+    }
+
+    /// Transfer in the default currency.
+    fn transfer(
+        id: TransactionId,
+        from: AccountId,
+        to: AccountId,
+        amount: Decimal,
+    ) -> PaymentEngineCommand {
+        command(Transaction::new_transfer(
+            id,
+            from,
+            to,
+            amount,
+            Currency::default(),
+        ))
+    }
+
+    /// Dispute command on the whole of a transaction.
+    fn dispute(
+        action: DisputeCommandAction,
+        account_id: AccountId,
+        tx_id: TransactionId,
+    ) -> PaymentEngineCommand {
+        partial_dispute(action, account_id, tx_id, None)
+    }
+
+    /// Dispute command on part of a transaction, what is left of it without amount.
+    fn partial_dispute(
+        action: DisputeCommandAction,
+        account_id: AccountId,
+        tx_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> PaymentEngineCommand {
+        PaymentEngineCommand::DisputeCommand(DisputeCommandData::new(
+            action,
+            Dispute::new_partial(account_id, tx_id, amount),
+        ))
+    }
+
+    /// Accounts report of a running engine as csv.
+    async fn accounts_report(
+        sender: &mpsc::Sender<PaymentEngineCommand>,
+        mode: AccountsReportMode,
+    ) -> Result<String> {
+        let mut output = Vec::new();
+        send_accounts_report(sender.clone(), &mut DelimitedSink::csv(&mut output), mode).await?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    /// Dispute report of a running engine as csv.
+    async fn dispute_report(sender: &mpsc::Sender<PaymentEngineCommand>) -> Result<String> {
+        let mut output = Vec::new();
+        crate::analytics::send_dispute_report(sender.clone(), &mut output).await?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    /// Rows of a csv report without the header, sorted as the workers answer in any order.
+    fn sorted_rows(output: &str) -> Vec<&str> {
+        let mut rows: Vec<_> = output.lines().skip(1).collect();
+        rows.sort();
+        rows
+    }
+
     #[tokio::test]
     async fn test_engine_send_accounts_to_csv() -> Result<()> {
-        let cmd = PaymentEngineCommand::TransactionCommand(
-            Transaction::new(TransactionKind::Deposit, 0, 0, dec!(0)).try_into()?,
-        );
-
         let (_, receiver) = mpsc::channel(2);
         let mut engine = PaymentEngine::new(receiver);
 
-        engine.handle(cmd).await?;
+        engine.handle(deposit(0, 0, dec!(0))).await?;
         assert_eq!(engine.worker_joins.len(), 1);
         assert!(engine.account_workers.contains_key(&0));
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_engine_rejects_duplicated_transaction_id() -> Result<()> {
+        let (_, receiver) = mpsc::channel(2);
+        let mut engine = PaymentEngine::new(receiver);
+
+        engine.handle(deposit(1, 0, dec!(1))).await?;
+
+        let result = engine.handle(deposit(1, 1, dec!(1))).await;
+        assert_eq!(
+            result,
+            Err(AccountOperationError::DuplicatedTransaction(1).into())
+        );
+        assert!(!engine.account_workers.contains_key(&1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_rejected_id_policy() -> Result<()> {
+        for (policy, expected) in [
+            (
+                RejectedIdPolicy::Reserved,
+                Err(AccountOperationError::DuplicatedTransaction(2).into()),
+            ),
+            (RejectedIdPolicy::Reusable, Ok(())),
+        ] {
+            let (_, receiver) = mpsc::channel(2);
+            let config = EngineConfig {
+                rejected_id_policy: policy,
+                ..EngineConfig::default()
+            };
+            let mut engine = PaymentEngine::new_with_config(receiver, config);
+
+            engine.handle(deposit(1, 0, dec!(1))).await?;
+            // Rejected by the worker with insufficient funds
+            engine.handle(withdrawal(2, 0, dec!(5))).await?;
+            engine.wait_for_outcome(2).await;
+            assert_eq!(
+                engine.transaction_ids.status(2),
+                Some(TransactionIdStatus::Rejected)
+            );
+
+            assert_eq!(engine.handle(deposit(2, 1, dec!(1))).await, expected);
+            assert!(engine.handle(deposit(1, 1, dec!(1))).await.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_outcome_of_stopped_worker() -> Result<()> {
+        let (_, receiver) = mpsc::channel(2);
+        let config = EngineConfig {
+            rejected_id_policy: RejectedIdPolicy::Reusable,
+            ..EngineConfig::default()
+        };
+        let mut engine = PaymentEngine::new_with_config(receiver, config);
+
+        engine.handle(deposit(1, 0, dec!(1))).await?;
+        // The worker stops before taking the deposit, the engine keeps an outcome sender alive.
+        engine.worker_joins[0].1.abort();
+        engine.wait_for_outcome(1).await;
+        assert_eq!(
+            engine.transaction_ids.status(1),
+            Some(TransactionIdStatus::Rejected)
+        );
+        assert!(engine.pending_outcomes.is_empty());
+        assert!(engine.pending_accounts.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_replies_command_outcomes() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        let reply = send_and_wait(&sender, deposit(1, 0, dec!(2))).await?;
+        assert_eq!(reply, Ok(CommandOutcome::TransactionApplied(1)));
+
+        let reply = send_and_wait(&sender, withdrawal(2, 0, dec!(3))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+
+        let reply = send_and_wait(&sender, deposit(1, 0, dec!(2))).await?;
+        assert_eq!(reply, Err(AccountOperationError::DuplicatedTransaction(1)));
+
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 0, 1)).await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                1,
+                DisputeStatus::InProgress,
+                dec!(2)
+            ))
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_conversion() -> Result<()> {
+        let (eur, usd, jpy) = ("EUR".parse()?, "USD".parse()?, "JPY".parse()?);
+        let mut config = EngineConfig::default();
+        config.fx_rates.insert(eur, usd, dec!(1.1), 0);
+        config.fx_rates.insert(eur, usd, dec!(1.2), 100);
+
+        let (sender, engine_join) = spawn_engine(config);
+
+        let reply = send_and_wait(
+            &sender,
+            command(Transaction::new_with_currency(
+                TransactionKind::Deposit,
+                1,
+                0,
+                dec!(10),
+                eur,
+            )),
+        )
+        .await?;
+        assert_eq!(reply, Ok(CommandOutcome::TransactionApplied(1)));
+
+        // Converted at the rate in effect at the conversion's time, not the latest one
+        let reply = send_and_wait(
+            &sender,
+            command(Transaction::new_conversion(2, 0, dec!(4), eur, usd)).with_timestamp(Some(50)),
+        )
+        .await?;
+        assert_eq!(reply, Ok(CommandOutcome::TransactionApplied(2)));
+        let reply = send_and_wait(
+            &sender,
+            command(Transaction::new_conversion(3, 0, dec!(4), eur, jpy)).with_timestamp(Some(50)),
+        )
+        .await?;
+        assert_eq!(reply, Err(AccountOperationError::MissingFxRate(eur, jpy)));
+        let reply = send_and_wait(
+            &sender,
+            command(Transaction::new_conversion(4, 0, dec!(4), eur, eur)).with_timestamp(Some(50)),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::InvalidConversion(
+                4,
+                "same source and target currency"
+            ))
+        );
+
+        let mut output = Vec::new();
+        for mode in [
+            AccountsReportMode::PerCurrency,
+            AccountsReportMode::BaseCurrency(usd),
+        ] {
+            send_accounts_report(sender.clone(), &mut DelimitedSink::csv(&mut output), mode)
+                .await?;
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n\
+            0,EUR,6,0,6,false\n\
+            0,USD,4.4,0,4.4,false\n\
+            client,available,held,total,locked\n\
+            0,11.6,0,11.6,false\n"
+        );
+
+        // An account that can't be converted fails the report instead of going missing
+        let result = send_accounts_report(
+            sender.clone(),
+            &mut DelimitedSink::csv(Vec::new()),
+            AccountsReportMode::BaseCurrency(jpy),
+        )
+        .await;
+        assert_eq!(
+            result,
+            Err(AccountOperationError::MissingFxRate(eur, jpy).into())
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_conversion_rollback() -> Result<()> {
+        let (xxx, eur) = (Currency::default(), "EUR".parse()?);
+        let mut config = EngineConfig::default();
+        config.fx_rates.insert(xxx, eur, dec!(3), 0);
+        config.tiers.insert(
+            crate::tiers::DEFAULT_TIER.to_string(),
+            crate::tiers::AccountLimits {
+                max_balance: Some(dec!(100)),
+                ..Default::default()
+            },
+        );
+        let (sender, engine_join) = spawn_engine(config);
+
+        // Opened above the limit, the debit leg must come back even so
+        let data = b"client,available,held,total,locked\n0,200,0,200,false\n".as_slice();
+        crate::csv::send_opening_balances(data, sender.clone(), xxx).await?;
+        let reply = send_and_wait(
+            &sender,
+            command(Transaction::new_conversion(1, 0, dec!(50), xxx, eur)),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::BalanceLimitExceeded(0, dec!(100)))
+        );
+
+        let output = accounts_report(&sender, AccountsReportMode::PerCurrency).await?;
+        assert_eq!(
+            output,
+            "client,currency,available,held,total,locked\n0,EUR,0,0,0,false\n0,XXX,200,0,200,false\n"
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_fees() -> Result<()> {
+        let rule = |operation, flat| FeeRule {
+            client: None,
+            operation,
+            currency: None,
+            min_amount: None,
+            flat: Some(flat),
+            percent: None,
+        };
+        let config = EngineConfig {
+            fees: FeeSchedule::new(vec![
+                rule(FeeOperation::Withdrawal, dec!(1)),
+                rule(FeeOperation::Chargeback, dec!(15)),
+            ]),
+            ..EngineConfig::default()
+        };
+
+        let (sender, engine_join) = spawn_engine(config);
+
+        send_and_wait(&sender, deposit(1, 0, dec!(5))).await??;
+        send_and_wait(&sender, deposit(2, 0, dec!(10))).await??;
+        let reply = send_and_wait(&sender, withdrawal(3, 0, dec!(14.5))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+        send_and_wait(&sender, withdrawal(4, 0, dec!(4))).await??;
+        send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 0, 1)).await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 0, 1),
+        )
+        .await??;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        // 5 + 10 - (4 + 1) - 5, the 15 chargeback fee only takes the 5 left
+        assert_eq!(output, "client,available,held,total,locked\n0,0,0,0,true\n");
+
+        let fees: Vec<_> = collect_ledger(sender.clone())
+            .await?
+            .iter()
+            .map(|entry| (entry.transaction.id(), entry.fee))
+            .collect();
+        assert_eq!(fees, [(1, dec!(5)), (2, dec!(0)), (4, dec!(1))]);
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_inactive_accounts() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        send_and_wait(&sender, deposit(1, 0, dec!(5))).await??;
+        // Only opened by a failed withdrawal
+        let reply = send_and_wait(&sender, withdrawal(2, 1, dec!(5))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 1, 2)).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(1)));
+        let reply = send_and_wait(&sender, transfer(3, 0, 1, dec!(1))).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(1)));
+
+        // A dispute right behind the first deposit waits for it
+        sender.send(deposit(4, 1, dec!(5))).await?;
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 1, 4)).await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                4,
+                DisputeStatus::InProgress,
+                dec!(5)
+            ))
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_transfer() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        send_and_wait(&sender, deposit(1, 0, dec!(10))).await??;
+        send_and_wait(&sender, deposit(2, 2, dec!(1))).await??;
+        send_and_wait(&sender, deposit(7, 1, dec!(1))).await??;
+
+        let reply = send_and_wait(&sender, transfer(3, 0, 1, dec!(11))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+        let reply = send_and_wait(&sender, transfer(4, 0, 0, dec!(1))).await?;
+        assert!(matches!(
+            reply,
+            Err(AccountOperationError::InvalidTransfer(4, _))
+        ));
+
+        // A transfer doesn't open the destination's account
+        let reply = send_and_wait(&sender, transfer(8, 0, 3, dec!(1))).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(3)));
+
+        let reply = send_and_wait(&sender, transfer(5, 0, 1, dec!(6))).await?;
+        assert_eq!(reply, Ok(CommandOutcome::TransactionApplied(5)));
+        let reply = send_and_wait(&sender, transfer(5, 0, 1, dec!(1))).await?;
+        assert_eq!(reply, Err(AccountOperationError::DuplicatedTransaction(5)));
+
+        // The source cannot dispute a transfer it sent
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 0, 5)).await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::DisputeIsNotDeposit(
+                TransactionKind::Transfer
+            ))
+        );
+
+        // Lock client 2 with a chargeback, a transfer to it is rolled back
+        send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 2, 2)).await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 2, 2),
+        )
+        .await??;
+        let reply = send_and_wait(&sender, transfer(6, 0, 2, dec!(1))).await?;
+        assert_eq!(reply, Err(AccountOperationError::AccountLocked(2)));
+
+        // Charging back the transfer on the destination gives the funds back to the source
+        send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 1, 5)).await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 1, 5),
+        )
+        .await??;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        let rows = sorted_rows(&output);
+        assert_eq!(
+            rows,
+            vec!["0,10,0,10,false", "1,1,0,1,true", "2,0,0,0,true"]
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_drops_charged_back_transfers() -> Result<()> {
+        let (_, receiver) = mpsc::channel(2);
+        let mut engine = PaymentEngine::new(receiver);
+
+        for (id, account_id) in [(1, 0), (2, 1), (3, 2)] {
+            engine.handle(deposit(id, account_id, dec!(10))).await?;
+        }
+        engine.handle(transfer(4, 0, 1, dec!(6))).await?;
+        engine.handle(transfer(5, 0, 2, dec!(1))).await?;
+        assert_eq!(engine.transfers.len(), 2);
+
+        // A partial chargeback keeps the rest of the transfer
+        engine
+            .handle(partial_dispute(
+                DisputeCommandAction::OpenDispute,
+                1,
+                4,
+                Some(dec!(4)),
+            ))
+            .await?;
+        engine
+            .handle(dispute(DisputeCommandAction::ChargebackDispute, 1, 4))
+            .await?;
+        assert_eq!(
+            engine.transfers.get(&4).map(Transaction::amount),
+            Some(dec!(2))
+        );
+
+        engine
+            .handle(dispute(DisputeCommandAction::OpenDispute, 2, 5))
+            .await?;
+        engine
+            .handle(dispute(DisputeCommandAction::ChargebackDispute, 2, 5))
+            .await?;
+        assert!(!engine.transfers.contains_key(&5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_partial_disputes() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        send_and_wait(&sender, deposit(1, 0, dec!(10))).await??;
+
+        // A refused dispute isn't recorded
+        let reply = send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::OpenDispute, 0, 1, Some(dec!(11))),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::DisputeAmountExceeded(1, dec!(10)))
+        );
+        let ledger = collect_ledger(sender.clone()).await?;
+        assert_eq!(ledger[0].dispute, None);
+
+        let reply = send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::OpenDispute, 0, 1, Some(dec!(4))),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                1,
+                DisputeStatus::InProgress,
+                dec!(4)
+            ))
+        );
+        let reply = send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::OpenDispute, 0, 1, Some(dec!(7))),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::DisputeAmountExceeded(1, dec!(6)))
+        );
+        // Without amount, the dispute takes what is left
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 0, 1)).await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                1,
+                DisputeStatus::InProgress,
+                dec!(6)
+            ))
+        );
+
+        let reply = send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::ChargebackDispute, 0, 1, Some(dec!(4))),
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                1,
+                DisputeStatus::InProgress,
+                dec!(4)
+            ))
+        );
+        // The chargeback locked the account, the rest of the dispute can still be closed
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::CancelDispute, 0, 1)).await?;
+        assert_eq!(
+            reply,
+            Ok(CommandOutcome::DisputeUpdated(
+                1,
+                DisputeStatus::Resolved(DisputeResolution::Cancelled),
+                dec!(6)
+            ))
+        );
+
+        // A partial chargeback of a transfer only refunds its part to the source
+        for (id, account_id, amount) in [(2, 2, dec!(10)), (4, 3, dec!(1))] {
+            send_and_wait(&sender, deposit(id, account_id, amount)).await??;
+        }
+        send_and_wait(&sender, transfer(3, 2, 3, dec!(6))).await??;
+        send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::OpenDispute, 3, 3, Some(dec!(2))),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 3, 3),
+        )
+        .await??;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        let rows = sorted_rows(&output);
+        assert_eq!(rows, vec!["0,6,0,6,true", "2,6,0,6,false", "3,5,0,5,true"]);
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_dispute_stages() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        let status = |reply: CommandReply| match reply {
+            Ok(CommandOutcome::DisputeUpdated(_, status, amount)) => Ok((status, amount)),
+            Err(e) => Err(e),
+            Ok(outcome) => panic!("Unexpected outcome {:?}", outcome),
+        };
+
+        for account_id in [1, 2] {
+            send_and_wait(&sender, deposit(account_id.into(), account_id, dec!(10))).await??;
+            send_and_wait(
+                &sender,
+                dispute(
+                    DisputeCommandAction::OpenDispute,
+                    account_id,
+                    account_id.into(),
+                ),
+            )
+            .await??;
+            send_and_wait(
+                &sender,
+                dispute(
+                    DisputeCommandAction::ChargebackDispute,
+                    account_id,
+                    account_id.into(),
+                ),
+            )
+            .await??;
+        }
+
+        // Client 1 contests part of the chargeback and wins the arbitration
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::PreArbitrateDispute, 1, 1),
+        )
+        .await?;
+        assert!(matches!(
+            reply,
+            Err(AccountOperationError::TransactionStateMismatch(1, _))
+        ));
+        let reply = send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::RepresentDispute, 1, 1, Some(dec!(6))),
+        )
+        .await?;
+        assert_eq!(status(reply), Ok((DisputeStatus::Representment, dec!(6))));
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 1, 1),
+        )
+        .await?;
+        assert!(matches!(
+            reply,
+            Err(AccountOperationError::TransactionStateMismatch(1, _))
+        ));
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::PreArbitrateDispute, 1, 1),
+        )
+        .await?;
+        assert_eq!(status(reply), Ok((DisputeStatus::PreArbitration, dec!(6))));
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ArbitrateDispute, 1, 1),
+        )
+        .await?;
+        assert_eq!(status(reply), Ok((DisputeStatus::Arbitration, dec!(6))));
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::CancelDispute, 1, 1)).await?;
+        assert_eq!(
+            status(reply),
+            Ok((
+                DisputeStatus::Resolved(DisputeResolution::Cancelled),
+                dec!(6)
+            ))
+        );
+        // A chargeback is only represented once
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::RepresentDispute, 1, 1),
+        )
+        .await?;
+        assert!(matches!(
+            reply,
+            Err(AccountOperationError::TransactionStateMismatch(1, _))
+        ));
+
+        // Client 2 representment is accepted right away
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::RepresentDispute, 2, 2),
+        )
+        .await??;
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 2, 2)).await?;
+        assert!(matches!(
+            reply,
+            Err(AccountOperationError::TransactionStateMismatch(2, _))
+        ));
+        send_and_wait(&sender, dispute(DisputeCommandAction::CancelDispute, 2, 2)).await??;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        let rows = sorted_rows(&output);
+        assert_eq!(rows, vec!["1,6,0,6,true", "2,10,0,10,true"]);
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_rejection_report() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig {
+            dispute_limits: DisputeLimits {
+                filing_days: Some(1),
+                ..DisputeLimits::default()
+            },
+            ..EngineConfig::default()
+        });
+
+        let data = b"\
+type,client,tx,amount,timestamp
+deposit,1,1,10,0
+withdrawal,1,2,20,10
+refund,1,3,1,20
+dispute,1,1,,100000
+deposit,1,4,5,100001
+"
+        .as_slice();
+        let stats = TransactionProducer::new(CsvSource::new(data), sender.clone())
+            .with_engine_rejections()
+            .start()
+            .await?;
+
+        // Rows refused by the workers come with their position, between the unreadable ones
+        let rejections: Vec<_> = stats
+            .all_rejections()
+            .iter()
+            .map(|r| (r.position.line, r.code))
+            .collect();
+        assert_eq!(
+            rejections,
+            [
+                (3, "insufficient_funds"),
+                (4, "invalid_record"),
+                (5, "dispute_window_expired")
+            ]
+        );
+        assert_eq!(stats.rejections.len(), 1);
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_dispute_time_limits() -> Result<()> {
+        const DAY: i64 = SECONDS_PER_DAY;
+        let (sender, engine_join) = spawn_engine(EngineConfig {
+            dispute_limits: DisputeLimits {
+                filing_days: Some(10),
+                resolution_days: Some(5),
+                default_resolution: DisputeResolution::ChargedBack,
+            },
+            ..EngineConfig::default()
+        });
+
+        for account_id in [1, 2, 3] {
+            send_and_wait(
+                &sender,
+                deposit(account_id.into(), account_id, dec!(10)).with_timestamp(Some(0)),
+            )
+            .await??;
+        }
+
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 2, 2).with_timestamp(Some(DAY)),
+        )
+        .await??;
+        // Closed then opened again, the first deadline doesn't apply anymore
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 3, 3).with_timestamp(Some(2 * DAY)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::CancelDispute, 3, 3).with_timestamp(Some(3 * DAY)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 3, 3).with_timestamp(Some(4 * DAY)),
+        )
+        .await??;
+
+        // The clock passes the deadline of client 2 dispute, it's charged back by default
+        send_and_wait(
+            &sender,
+            deposit(4, 4, dec!(10)).with_timestamp(Some(8 * DAY)),
+        )
+        .await??;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        let rows = sorted_rows(&output);
+        assert_eq!(
+            rows,
+            vec![
+                "1,10,0,10,false",
+                "2,0,0,0,true",
+                "3,0,10,10,false",
+                "4,10,0,10,false"
+            ]
+        );
+
+        // The expiry is kept with the dispute and counted in the dispute report
+        let expired: Vec<_> = collect_ledger(sender.clone())
+            .await?
+            .iter()
+            .map(|entry| (entry.client, entry.dispute_expired_at))
+            .collect();
+        assert_eq!(
+            expired,
+            [(1, None), (2, Some(6 * DAY)), (3, None), (4, None)]
+        );
+        let output = dispute_report(&sender).await?;
+        assert!(output
+            .lines()
+            .any(|line| line.starts_with("2,1,1,0,1,1,0,")));
+
+        let reply = send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 1, 1).with_timestamp(Some(11 * DAY)),
+        )
+        .await?;
+        assert_eq!(reply, Err(AccountOperationError::DisputeWindowExpired(1)));
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_dispute_report() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        send_and_wait(&sender, deposit(1, 1, dec!(10))).await??;
+        send_and_wait(&sender, deposit(2, 1, dec!(5))).await??;
+        send_and_wait(&sender, deposit(3, 2, dec!(10))).await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 2, 3).with_timestamp(Some(0)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::CancelDispute, 2, 3).with_timestamp(Some(50)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 1, 1).with_timestamp(Some(100)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::OpenDispute, 1, 2).with_timestamp(Some(200)),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 1, 1).with_timestamp(Some(400)),
+        )
+        .await??;
+        // Partial disputes open together on a transaction count as one
+        send_and_wait(&sender, deposit(4, 2, dec!(10))).await??;
+        for amount in [dec!(4), dec!(6)] {
+            send_and_wait(
+                &sender,
+                partial_dispute(DisputeCommandAction::OpenDispute, 2, 4, Some(amount))
+                    .with_timestamp(Some(0)),
+            )
+            .await??;
+        }
+        send_and_wait(
+            &sender,
+            dispute(DisputeCommandAction::ChargebackDispute, 2, 4).with_timestamp(Some(100)),
+        )
+        .await??;
+
+        let output = dispute_report(&sender).await?;
+        assert_eq!(
+            output,
+            format!(
+                "{}{}{}{}",
+                crate::analytics::DISPUTE_REPORT_HEADER,
+                "1,2,2,0,1,0,1,5,10,1,0.5,300\n",
+                "2,2,2,1,1,0,0,0,10,1,0.5,75\n",
+                "all,4,4,1,2,0,1,5,20,1,0.5,150\n"
+            )
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_risk_rules() -> Result<()> {
+        let rule = |rule, action, limit, window| RiskRule {
+            rule,
+            action,
+            limit: Some(limit),
+            count: None,
+            window,
+        };
+        let (sender, engine_join) = spawn_engine(EngineConfig {
+            risk_rules: RiskRules::new(vec![
+                rule(RiskRuleKind::MaxAmount, RiskAction::Reject, dec!(100), None),
+                rule(
+                    RiskRuleKind::WithdrawalAfterDeposit,
+                    RiskAction::Flag,
+                    dec!(50),
+                    Some(60),
+                ),
+            ]),
+            ..EngineConfig::default()
+        });
+
+        let reply =
+            send_and_wait(&sender, deposit(1, 0, dec!(200)).with_timestamp(Some(0))).await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::RiskRuleViolated(
+                1,
+                RiskRuleKind::MaxAmount
+            ))
+        );
+        send_and_wait(&sender, deposit(2, 0, dec!(60)).with_timestamp(Some(0))).await??;
+        // Flagged but applied
+        let reply =
+            send_and_wait(&sender, withdrawal(3, 0, dec!(10)).with_timestamp(Some(30))).await?;
+        assert_eq!(reply, Ok(CommandOutcome::TransactionApplied(3)));
+        // Flagged but failed, no alert
+        let reply =
+            send_and_wait(&sender, withdrawal(4, 0, dec!(90)).with_timestamp(Some(40))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+        let conversion =
+            Transaction::new_conversion(5, 0, dec!(150), Currency::default(), "EUR".parse()?);
+        let reply = send_and_wait(&sender, command(conversion)).await?;
+        assert_eq!(
+            reply,
+            Err(AccountOperationError::RiskRuleViolated(
+                5,
+                RiskRuleKind::MaxAmount
+            ))
+        );
+
+        // The rules only run on operations the account would take, no alert on a locked one
+        send_and_wait(
+            &sender,
+            partial_dispute(DisputeCommandAction::OpenDispute, 0, 2, Some(dec!(10))),
+        )
+        .await??;
+        send_and_wait(
+            &sender,
+            partial_dispute(
+                DisputeCommandAction::ChargebackDispute,
+                0,
+                2,
+                Some(dec!(10)),
+            ),
+        )
+        .await??;
+        let reply =
+            send_and_wait(&sender, deposit(6, 0, dec!(200)).with_timestamp(Some(50))).await?;
+        assert_eq!(reply, Err(AccountOperationError::AccountLocked(0)));
+
+        let mut output = Vec::new();
+        crate::risk::send_alerts_csv(sender.clone(), &mut output).await?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,rule,action\n\
+            0,1,max_amount,reject\n\
+            0,3,withdrawal_after_deposit,flag\n\
+            0,5,max_amount,reject\n"
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_known_clients() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig {
+            known_clients: Some(KnownClients::new([1, 2, 3])),
+            strict_clients: true,
+            ..EngineConfig::default()
+        });
+
+        let reply = send_and_wait(&sender, deposit(1, 4, dec!(10))).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(4)));
+        send_and_wait(&sender, deposit(2, 1, dec!(10))).await??;
+        let reply = send_and_wait(&sender, transfer(3, 1, 4, dec!(1))).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(4)));
+
+        // A failed row opens no account worth reporting, a dispute opens none at all
+        let reply = send_and_wait(&sender, withdrawal(4, 2, dec!(10))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+        let reply =
+            send_and_wait(&sender, dispute(DisputeCommandAction::OpenDispute, 3, 2)).await?;
+        assert_eq!(reply, Err(AccountOperationError::UnknownClient(3)));
+
+        // Neither do opening balances
+        let data = b"client,available,held,total,locked\n5,3,0,3,false\n".as_slice();
+        crate::csv::send_opening_balances(data, sender.clone(), Currency::default()).await?;
+
+        let output = accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,10,0,10,false\n"
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_opening_balances() -> Result<()> {
+        let (sender, engine_join) = spawn_engine(EngineConfig::default());
+
+        let data = b"\
+client,currency,available,held,total,locked
+1,XXX,15,0,15,false
+1,EUR,2.5,0,2.5,false
+2,XXX,1,0,1,true
+"
+        .as_slice();
+        crate::csv::send_opening_balances(data, sender.clone(), Currency::default()).await?;
+
+        send_and_wait(&sender, withdrawal(1, 1, dec!(10))).await??;
+        let reply = send_and_wait(&sender, deposit(2, 2, dec!(10))).await?;
+        assert_eq!(reply, Err(AccountOperationError::AccountLocked(2)));
+
+        let output = accounts_report(&sender, AccountsReportMode::PerCurrency).await?;
+        let mut rows: Vec<_> = output.lines().collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                "1,EUR,2.5,0,2.5,false",
+                "1,XXX,5,0,5,false",
+                "2,XXX,1,0,1,true",
+                "client,currency,available,held,total,locked",
+            ]
+        );
+
+        let data = b"\
+client,available,held,total,locked
+3,10,0,5,false
+"
+        .as_slice();
+        assert_eq!(
+            crate::csv::send_opening_balances(data, sender.clone(), Currency::default())
+                .await
+                .err(),
+            Some(PaymentEngineError::InvalidBalance(
+                3,
+                "total is not available + held"
+            ))
+        );
+        let data = b"\
+client,available,held,total,locked
+3,10,5,15,false
+"
+        .as_slice();
+        assert_eq!(
+            crate::csv::send_opening_balances(data, sender.clone(), Currency::default())
+                .await
+                .err(),
+            Some(PaymentEngineError::InvalidBalance(
+                3,
+                "held funds without their disputes"
+            ))
+        );
+        let data = b"\
+client,available,held,total,locked
+3,-1,0,-1,true
+"
+        .as_slice();
+        assert_eq!(
+            crate::csv::send_opening_balances(data, sender.clone(), Currency::default())
+                .await
+                .err(),
+            Some(PaymentEngineError::InvalidBalance(
+                3,
+                "negative available funds"
+            ))
+        );
+        let data = b"\
+client,currency,available,held,total,locked
+3,EUR,10,0,10,false
+4,EUR,10,0,10,false
+3,EUR,5,0,5,false
+"
+        .as_slice();
+        assert_eq!(
+            crate::csv::send_opening_balances(data, sender.clone(), Currency::default())
+                .await
+                .err(),
+            Some(PaymentEngineError::InvalidRecord(
+                4,
+                "duplicated balance of client 3 in EUR".to_string()
+            ))
+        );
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_metrics() -> Result<()> {
+        let metrics = Arc::new(Metrics::new()?);
+        let (sender, engine_join) = spawn_engine(EngineConfig {
+            metrics: Some(metrics.clone()),
+            ..EngineConfig::default()
+        });
+
+        send_and_wait(&sender, deposit(1, 1, dec!(5))).await??;
+        let reply = send_and_wait(&sender, deposit(1, 1, dec!(5))).await?;
+        assert_eq!(reply, Err(AccountOperationError::DuplicatedTransaction(1)));
+        // Only opened by a failed withdrawal, its worker runs all the same
+        let reply = send_and_wait(&sender, withdrawal(2, 2, dec!(5))).await?;
+        assert_eq!(reply, Err(AccountOperationError::InsufficientFunds));
+        // The workers record a command once handled, a report goes after them
+        accounts_report(&sender, AccountsReportMode::DefaultCurrency).await?;
+
+        let rendered = String::from_utf8(metrics.render()?).unwrap();
+        for line in [
+            "payment_engine_commands_total{command=\"deposit\",stage=\"engine\"} 2",
+            "payment_engine_commands_total{command=\"deposit\",stage=\"worker\"} 1",
+            "payment_engine_errors_total{error=\"DuplicatedTransaction\",stage=\"engine\"} 1",
+            "payment_engine_errors_total{error=\"InsufficientFunds\",stage=\"worker\"} 1",
+            "payment_engine_channel_capacity{channel=\"engine\"} 2",
+            "payment_engine_active_workers 2",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{} not in {}",
+                line,
+                rendered
+            );
+        }
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_run_stats() -> Result<()> {
+        let (xxx, eur) = (Currency::default(), "EUR".parse()?);
+        let mut config = EngineConfig {
+            fees: FeeSchedule::new(vec![FeeRule {
+                client: None,
+                operation: FeeOperation::Withdrawal,
+                currency: None,
+                min_amount: None,
+                flat: Some(dec!(1)),
+                percent: None,
+            }]),
+            ..EngineConfig::default()
+        };
+        config.fx_rates.insert(xxx, eur, dec!(2), 0);
+        let (sender, engine_join) = spawn_engine(config);
+
+        for command in [
+            deposit(1, 1, dec!(10)),
+            deposit(2, 1, dec!(5)),
+            withdrawal(3, 1, dec!(4)),
+            withdrawal(4, 1, dec!(40)),
+            deposit(3, 1, dec!(1)),
+            deposit(5, 2, dec!(3)),
+            deposit(8, 2, dec!(10)),
+            transfer(6, 2, 1, dec!(2)),
+            command(Transaction::new_conversion(7, 2, dec!(4), xxx, eur)),
+            dispute(DisputeCommandAction::OpenDispute, 1, 1),
+            dispute(DisputeCommandAction::ChargebackDispute, 1, 1),
+            dispute(DisputeCommandAction::OpenDispute, 2, 5),
+            dispute(DisputeCommandAction::OpenDispute, 3, 1),
+        ] {
+            let _ = send_and_wait(&sender, command).await?;
+        }
+
+        let stats = crate::stats::collect_operation_stats(sender.clone()).await?;
+        assert_eq!(stats.applied, 10);
+        assert_eq!(
+            stats.rejected,
+            [
+                ("DuplicatedTransaction", 1),
+                ("InsufficientFunds", 1),
+                ("UnknownClient", 1)
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!((stats.accounts, stats.locked_accounts), (2, 1));
+        assert_eq!(stats.deposited, [(xxx, dec!(28))].into_iter().collect());
+        assert_eq!(stats.withdrawn, [(xxx, dec!(4))].into_iter().collect());
+        assert_eq!(stats.transferred, [(xxx, dec!(2))].into_iter().collect());
+        assert_eq!(stats.converted, [(xxx, dec!(4))].into_iter().collect());
+        assert_eq!(stats.fees, [(xxx, dec!(1))].into_iter().collect());
+        assert_eq!(stats.held, [(xxx, dec!(3))].into_iter().collect());
+        assert_eq!(stats.charged_back, [(xxx, dec!(10))].into_iter().collect());
+
+        drop(sender);
+        engine_join.await?;
+
+        Ok(())
+    }
 }
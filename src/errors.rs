@@ -2,12 +2,17 @@ use std::convert::Infallible;
 
 use crate::{
     account::AccountId,
+    currency::Currency,
+    fees::FeeOperation,
+    risk::RiskRuleKind,
     transaction::{TransactionId, TransactionKind},
 };
+use rust_decimal::Decimal;
 use thiserror::Error;
 use tokio::sync::mpsc;
 
 pub type Result<T> = std::result::Result<T, PaymentEngineError>;
+pub type AccountOperationResult<T> = std::result::Result<T, AccountOperationError>;
 
 #[derive(Debug, Error, PartialEq)]
 pub enum PaymentEngineError {
@@ -15,6 +20,10 @@ pub enum PaymentEngineError {
     #[error("Failed to process account operation: {0}")]
     AccountProcessError(#[from] AccountOperationError),
 
+    /// Input records refused by the validation layer.
+    #[error("Invalid record: {0}")]
+    RecordValidationError(#[from] RecordValidationError),
+
     /// Command line errors.
     #[error("Command line failed: {0}")]
     CommandLineError(String),
@@ -25,11 +34,44 @@ pub enum PaymentEngineError {
     #[error("CSV reader error: {0}")]
     CSVReaderError(String),
 
+    #[error("Invalid CSV header: {0}")]
+    InvalidHeader(String),
+
+    #[error("Invalid record at line {0}: {1}")]
+    InvalidRecord(u64, String),
+
+    #[error("Parquet export error: {0}")]
+    ExportError(String),
+
+    #[error("Metrics error: {0}")]
+    MetricsError(String),
+
     #[error("TokioMpscError: {0}")]
     TokioMpscError(String),
 
     #[error("Invalid amount format")]
     InvalidAmountFormat(),
+
+    #[error("Invalid ISO 4217 currency code: '{0}'")]
+    InvalidCurrency(String),
+
+    #[error("Conversion without target currency")]
+    MissingConversionCurrency(),
+
+    #[error("Transfer without destination client")]
+    MissingTransferDestination(),
+
+    #[error("Unknown tier '{0}' for client {1}")]
+    UnknownTier(String, AccountId),
+
+    #[error("Invalid balance for client {0}: {1}")]
+    InvalidBalance(AccountId, &'static str),
+
+    #[error("{0} records rejected")]
+    RejectedRecords(usize),
+
+    #[error("{0} wallets don't match the expected balances")]
+    ReconciliationMismatch(usize),
 }
 
 impl From<std::io::Error> for PaymentEngineError {
@@ -44,6 +86,24 @@ impl From<csv_async::Error> for PaymentEngineError {
     }
 }
 
+impl From<arrow_schema::ArrowError> for PaymentEngineError {
+    fn from(e: arrow_schema::ArrowError) -> Self {
+        Self::ExportError(format!("{}", e))
+    }
+}
+
+impl From<parquet::errors::ParquetError> for PaymentEngineError {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Self::ExportError(format!("{}", e))
+    }
+}
+
+impl From<prometheus::Error> for PaymentEngineError {
+    fn from(e: prometheus::Error) -> Self {
+        Self::MetricsError(format!("{}", e))
+    }
+}
+
 impl<T> From<mpsc::error::SendError<T>> for PaymentEngineError {
     fn from(e: mpsc::error::SendError<T>) -> Self {
         Self::TokioMpscError(format!("Error with PaymentsEngineCommand: {}", e))
@@ -99,4 +159,131 @@ pub enum AccountOperationError {
 
     #[error("Dispute for transaction {0} not found")]
     TransactionDisputeNotFound(TransactionId),
+
+    #[error("No exchange rate from {0} to {1}")]
+    MissingFxRate(Currency, Currency),
+
+    #[error("Invalid transfer {0}: {1}")]
+    InvalidTransfer(TransactionId, &'static str),
+
+    #[error("Invalid conversion {0}: {1}")]
+    InvalidConversion(TransactionId, &'static str),
+
+    #[error("Dispute amount for transaction {0} exceeds the {1} available")]
+    DisputeAmountExceeded(TransactionId, Decimal),
+
+    #[error("Dispute of transaction {0} filed after the time limit")]
+    DisputeWindowExpired(TransactionId),
+
+    #[error("Transaction {0} rejected by risk rule {1}")]
+    RiskRuleViolated(TransactionId, RiskRuleKind),
+
+    #[error("Account {0} balance would exceed its limit of {1}")]
+    BalanceLimitExceeded(AccountId, Decimal),
+
+    #[error("Account {0} withdrawal exceeds its limit of {1}")]
+    WithdrawalLimitExceeded(AccountId, Decimal),
+
+    #[error("Account {0} is not allowed to dispute transactions")]
+    DisputesNotAllowed(AccountId),
+
+    #[error("Unknown client {0}")]
+    UnknownClient(AccountId),
+
+    #[error("{0} fee of {1} overflows")]
+    FeeOverflow(FeeOperation, Decimal),
+}
+
+impl AccountOperationError {
+    /// Name of the variant, to count the errors by reason.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::InsufficientFunds => "InsufficientFunds",
+            Self::NonPositiveAmount => "NonPositiveAmount",
+            Self::AccountLocked(_) => "AccountLocked",
+            Self::OverflowInWallet => "OverflowInWallet",
+            Self::InfallibleError(_) => "InfallibleError",
+            Self::WrongAccountId(..) => "WrongAccountId",
+            Self::DuplicatedTransaction(_) => "DuplicatedTransaction",
+            Self::TransactionNotFound(_) => "TransactionNotFound",
+            Self::DisputeIsNotDeposit(_) => "DisputeIsNotDeposit",
+            Self::TransactionStateMismatch(..) => "TransactionStateMismatch",
+            Self::TransactionDisputeNotFound(_) => "TransactionDisputeNotFound",
+            Self::MissingFxRate(..) => "MissingFxRate",
+            Self::InvalidTransfer(..) => "InvalidTransfer",
+            Self::InvalidConversion(..) => "InvalidConversion",
+            Self::DisputeAmountExceeded(..) => "DisputeAmountExceeded",
+            Self::DisputeWindowExpired(_) => "DisputeWindowExpired",
+            Self::RiskRuleViolated(..) => "RiskRuleViolated",
+            Self::BalanceLimitExceeded(..) => "BalanceLimitExceeded",
+            Self::WithdrawalLimitExceeded(..) => "WithdrawalLimitExceeded",
+            Self::DisputesNotAllowed(_) => "DisputesNotAllowed",
+            Self::UnknownClient(_) => "UnknownClient",
+            Self::FeeOverflow(..) => "FeeOverflow",
+        }
+    }
+
+    /// Code of the error in the rejection report, the dry run using the same codes for the
+    /// duplicated and unknown transactions and the unknown clients it detects.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::NonPositiveAmount => "non_positive_amount",
+            Self::AccountLocked(_) => "account_locked",
+            Self::OverflowInWallet => "overflow_in_wallet",
+            Self::InfallibleError(_) => "infallible_error",
+            Self::WrongAccountId(..) => "wrong_account_id",
+            Self::DuplicatedTransaction(_) => "duplicate_transaction",
+            Self::TransactionNotFound(_) => "unknown_transaction",
+            Self::DisputeIsNotDeposit(_) => "dispute_is_not_deposit",
+            Self::TransactionStateMismatch(..) => "transaction_state_mismatch",
+            Self::TransactionDisputeNotFound(_) => "dispute_not_found",
+            Self::MissingFxRate(..) => "missing_fx_rate",
+            Self::InvalidTransfer(..) => "invalid_transfer",
+            Self::InvalidConversion(..) => "invalid_conversion",
+            Self::DisputeAmountExceeded(..) => "dispute_amount_exceeded",
+            Self::DisputeWindowExpired(_) => "dispute_window_expired",
+            Self::RiskRuleViolated(..) => "risk_rule_violated",
+            Self::BalanceLimitExceeded(..) => "balance_limit_exceeded",
+            Self::WithdrawalLimitExceeded(..) => "withdrawal_limit_exceeded",
+            Self::DisputesNotAllowed(_) => "disputes_not_allowed",
+            Self::UnknownClient(_) => "unknown_client",
+            Self::FeeOverflow(..) => "fee_overflow",
+        }
+    }
+}
+
+/// Errors of the input validation layer, each with a stable code for the rejection report.
+#[derive(Debug, Error, PartialEq)]
+pub enum RecordValidationError {
+    #[error("Missing amount")]
+    MissingAmount,
+
+    #[error("Negative amount {0}")]
+    NegativeAmount(Decimal),
+
+    #[error("Zero amount")]
+    ZeroAmount,
+
+    #[error("Amount {0} has more than {1} decimal places")]
+    ExcessiveScale(Decimal, u32),
+
+    #[error("Column '{0}' not allowed on '{1}' rows")]
+    UnexpectedField(&'static str, &'static str),
+
+    #[error("Client {0} out of the {1}-{2} range")]
+    ClientOutOfRange(AccountId, AccountId, AccountId),
+}
+
+impl RecordValidationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MissingAmount => "missing_amount",
+            Self::NegativeAmount(_) => "negative_amount",
+            Self::ZeroAmount => "zero_amount",
+            Self::ExcessiveScale(..) => "excessive_scale",
+            Self::UnexpectedField(..) => "unexpected_field",
+            Self::ClientOutOfRange(..) => "client_out_of_range",
+        }
+    }
 }
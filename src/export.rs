@@ -0,0 +1,438 @@
+/// Parquet export of the accounts report and of the transaction ledger, for the analytics
+/// warehouse. The schemas are stable whatever the report mode:
+/// - amounts are `decimal(38, 4)`, rounded half to even past 4 decimals. Amounts too large to
+///   keep 4 decimals fail the export.
+/// - currencies, transaction kinds and statuses are dictionary encoded strings.
+/// - timestamps are UTC seconds.
+use std::sync::Arc;
+
+use arrow_array::{
+    types::{Int16Type, Int8Type},
+    ArrayRef, BooleanArray, Decimal128Array, DictionaryArray, RecordBatch, TimestampSecondArray,
+    UInt16Array, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use rust_decimal::{Decimal, RoundingStrategy};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    account::{AccountId, WalletBalance},
+    errors::{PaymentEngineError, Result},
+    report::{AccountReportSink, AccountsReportMode},
+    tasks::command::PaymentEngineCommand,
+    transaction::{
+        DisputeResolution, DisputeStatus, Transaction, TransactionKind, TransactionStatus,
+    },
+};
+
+pub const DECIMAL_PRECISION: u8 = 38;
+pub const DECIMAL_SCALE: u32 = 4;
+const TIMEZONE: &str = "UTC";
+
+/// A transaction as recorded by an account, transfers being recorded by both accounts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LedgerEntry {
+    pub client: AccountId,
+    pub transaction: Transaction,
+    /// Stage of the dispute of the transaction, if it has ever been disputed.
+    pub dispute: Option<DisputeStatus>,
+    /// Deadline at which the disputes of the transaction were left open and resolved by default.
+    pub dispute_expired_at: Option<i64>,
+    /// Fees the account paid for the transaction, chargeback fees included, in its currency.
+    pub fee: Decimal,
+}
+
+fn decimal_type() -> DataType {
+    DataType::Decimal128(DECIMAL_PRECISION, DECIMAL_SCALE as i8)
+}
+
+fn dictionary_type(key: DataType) -> DataType {
+    DataType::Dictionary(Box::new(key), Box::new(DataType::Utf8))
+}
+
+pub fn accounts_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("currency", dictionary_type(DataType::Int16), false),
+        Field::new("available", decimal_type(), false),
+        Field::new("held", decimal_type(), false),
+        Field::new("total", decimal_type(), false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+pub fn ledger_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("tx", DataType::UInt32, false),
+        Field::new("kind", dictionary_type(DataType::Int8), false),
+        Field::new("currency", dictionary_type(DataType::Int16), false),
+        Field::new("amount", decimal_type(), false),
+        // Credit leg of a conversion
+        Field::new("to_currency", dictionary_type(DataType::Int16), true),
+        Field::new("to_amount", decimal_type(), true),
+        // Source and destination of a transfer, `client` being either of them
+        Field::new("source", DataType::UInt16, false),
+        Field::new("counterparty", DataType::UInt16, true),
+        Field::new("charged_back", decimal_type(), false),
+        Field::new("fee", decimal_type(), false),
+        Field::new("status", dictionary_type(DataType::Int8), false),
+        Field::new("dispute_status", dictionary_type(DataType::Int8), true),
+        Field::new(
+            "dispute_expired_at",
+            DataType::Timestamp(TimeUnit::Second, Some(TIMEZONE.into())),
+            true,
+        ),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Second, Some(TIMEZONE.into())),
+            true,
+        ),
+    ]))
+}
+
+fn decimal_value(amount: Decimal) -> Result<i128> {
+    let mut scaled =
+        amount.round_dp_with_strategy(DECIMAL_SCALE, RoundingStrategy::MidpointNearestEven);
+    // Rescaling gives up on the scale when the digits don't fit
+    scaled.rescale(DECIMAL_SCALE);
+    if scaled.scale() != DECIMAL_SCALE {
+        return Err(PaymentEngineError::ExportError(format!(
+            "{} doesn't fit {} decimals",
+            amount, DECIMAL_SCALE
+        )));
+    }
+    Ok(scaled.mantissa())
+}
+
+fn decimal_array<I: IntoIterator<Item = Option<Decimal>>>(amounts: I) -> Result<ArrayRef> {
+    let values = amounts
+        .into_iter()
+        .map(|amount| amount.map(decimal_value).transpose())
+        .collect::<Result<Vec<_>>>()?;
+    let array = Decimal128Array::from(values)
+        .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE as i8)?;
+    Ok(Arc::new(array))
+}
+
+fn kind_name(kind: TransactionKind) -> &'static str {
+    match kind {
+        TransactionKind::Deposit => "deposit",
+        TransactionKind::Withdrawal => "withdrawal",
+        TransactionKind::Conversion => "conversion",
+        TransactionKind::Transfer => "transfer",
+    }
+}
+
+fn status_name(status: &TransactionStatus) -> &'static str {
+    match status {
+        TransactionStatus::ChargedBack => "charged_back",
+        TransactionStatus::Created => "created",
+        TransactionStatus::DisputeInProgress => "dispute_in_progress",
+        TransactionStatus::Processed => "processed",
+    }
+}
+
+fn dispute_status_name(status: &DisputeStatus) -> &'static str {
+    match status {
+        DisputeStatus::Created => "created",
+        DisputeStatus::InProgress => "in_progress",
+        DisputeStatus::Representment => "representment",
+        DisputeStatus::PreArbitration => "pre_arbitration",
+        DisputeStatus::Arbitration => "arbitration",
+        DisputeStatus::Resolved(DisputeResolution::Cancelled) => "cancelled",
+        DisputeStatus::Resolved(DisputeResolution::ChargedBack) => "charged_back",
+    }
+}
+
+/// Write a single batch as a Parquet file.
+async fn write_parquet<W: AsyncWrite + Unpin>(batch: RecordBatch, mut output: W) -> Result<()> {
+    // The Parquet writer is synchronous, the file is built in memory first
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    output.write_all(&buffer).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+pub fn accounts_batch(balances: &[WalletBalance]) -> Result<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(balances.iter().map(|b| b.client).collect::<UInt16Array>()),
+        Arc::new(
+            balances
+                .iter()
+                .map(|b| b.currency.to_string())
+                .collect::<Vec<_>>()
+                .iter()
+                .map(String::as_str)
+                .collect::<DictionaryArray<Int16Type>>(),
+        ),
+        decimal_array(balances.iter().map(|b| Some(b.available)))?,
+        decimal_array(balances.iter().map(|b| Some(b.held)))?,
+        decimal_array(balances.iter().map(|b| Some(b.total)))?,
+        Arc::new(
+            balances
+                .iter()
+                .map(|b| Some(b.locked))
+                .collect::<BooleanArray>(),
+        ),
+    ];
+    Ok(RecordBatch::try_new(accounts_schema(), columns)?)
+}
+
+pub fn ledger_batch(entries: &[LedgerEntry]) -> Result<RecordBatch> {
+    let txs = || entries.iter().map(|e| &e.transaction);
+    let currencies: Vec<_> = txs().map(|tx| tx.currency().to_string()).collect();
+    let to_currencies: Vec<_> = txs()
+        .map(|tx| tx.credit.as_ref().map(|leg| leg.currency.to_string()))
+        .collect();
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(entries.iter().map(|e| e.client).collect::<UInt16Array>()),
+        Arc::new(txs().map(Transaction::id).collect::<UInt32Array>()),
+        Arc::new(
+            txs()
+                .map(|tx| kind_name(tx.kind()))
+                .collect::<DictionaryArray<Int8Type>>(),
+        ),
+        Arc::new(
+            currencies
+                .iter()
+                .map(String::as_str)
+                .collect::<DictionaryArray<Int16Type>>(),
+        ),
+        decimal_array(txs().map(|tx| Some(tx.amount())))?,
+        Arc::new(
+            to_currencies
+                .iter()
+                .map(Option::as_deref)
+                .collect::<DictionaryArray<Int16Type>>(),
+        ),
+        decimal_array(txs().map(|tx| tx.credit.as_ref().map(|leg| leg.amount)))?,
+        Arc::new(txs().map(Transaction::account_id).collect::<UInt16Array>()),
+        Arc::new(
+            txs()
+                .map(Transaction::counterparty)
+                .collect::<UInt16Array>(),
+        ),
+        decimal_array(txs().map(|tx| Some(tx.charged_back)))?,
+        decimal_array(entries.iter().map(|e| Some(e.fee)))?,
+        Arc::new(
+            txs()
+                .map(|tx| status_name(&tx.status))
+                .collect::<DictionaryArray<Int8Type>>(),
+        ),
+        Arc::new(
+            entries
+                .iter()
+                .map(|e| e.dispute.as_ref().map(dispute_status_name))
+                .collect::<DictionaryArray<Int8Type>>(),
+        ),
+        Arc::new(
+            entries
+                .iter()
+                .map(|e| e.dispute_expired_at)
+                .collect::<TimestampSecondArray>()
+                .with_timezone(TIMEZONE),
+        ),
+        Arc::new(
+            txs()
+                .map(Transaction::timestamp)
+                .collect::<TimestampSecondArray>()
+                .with_timezone(TIMEZONE),
+        ),
+    ];
+    Ok(RecordBatch::try_new(ledger_schema(), columns)?)
+}
+
+/// Accounts report as a Parquet file, written once all the accounts are in.
+pub struct ParquetSink<W: AsyncWrite + Unpin + Send> {
+    output: W,
+    balances: Vec<WalletBalance>,
+}
+
+impl<W: AsyncWrite + Unpin + Send> ParquetSink<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            balances: Vec::new(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AccountReportSink for ParquetSink<W> {
+    async fn start(&mut self, _mode: AccountsReportMode) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, balance: &WalletBalance) -> Result<()> {
+        self.balances.push(balance.clone());
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.balances.sort_by_key(|b| (b.client, b.currency));
+        let batch = accounts_batch(&self.balances)?;
+        write_parquet(batch, &mut self.output).await
+    }
+}
+
+/// Ledger of every account, ordered by client and transaction.
+pub async fn collect_ledger(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+) -> Result<Vec<LedgerEntry>> {
+    let (ledger_sender, mut ledger_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendLedger(ledger_sender))
+        .await?;
+
+    let mut entries = Vec::new();
+    while let Some(entry) = ledger_receiver.recv().await {
+        entries.push(entry);
+    }
+    entries.sort_by_key(|e: &LedgerEntry| (e.client, e.transaction.id()));
+    Ok(entries)
+}
+
+pub async fn send_ledger_parquet<W: AsyncWrite + Unpin>(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    output: W,
+) -> Result<()> {
+    let entries = collect_ledger(engine_sender).await?;
+    write_parquet(ledger_batch(&entries)?, output).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+    use arrow_array::{cast::AsArray, types::Decimal128Type, Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_decimal_macros::dec;
+
+    fn read_parquet(data: Vec<u8>) -> RecordBatch {
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(data))
+            .unwrap()
+            .build()
+            .unwrap();
+        reader.next().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn parquet_accounts() -> Result<()> {
+        let eur: Currency = "EUR".parse()?;
+        let mut output = Vec::new();
+        let mut sink = ParquetSink::new(&mut output);
+        sink.start(AccountsReportMode::PerCurrency).await?;
+        for (client, available) in [(2, dec!(1.23456)), (1, dec!(-0.5))] {
+            sink.write(&WalletBalance {
+                client,
+                currency: eur,
+                available,
+                held: dec!(0),
+                total: available,
+                locked: false,
+            })
+            .await?;
+        }
+        sink.finish().await?;
+
+        let batch = read_parquet(output);
+        assert_eq!(batch.schema(), accounts_schema());
+        assert_eq!(
+            batch
+                .column(0)
+                .as_primitive::<arrow_array::types::UInt16Type>()
+                .values(),
+            &[1, 2]
+        );
+        let available = batch.column(2).as_primitive::<Decimal128Type>();
+        assert_eq!(available.value(0), -5000);
+        // Rounded half to even
+        assert_eq!(available.value(1), 12346);
+
+        let balance = WalletBalance {
+            client: 1,
+            currency: eur,
+            available: Decimal::MAX,
+            held: dec!(0),
+            total: Decimal::MAX,
+            locked: false,
+        };
+        assert!(matches!(
+            accounts_batch(&[balance]),
+            Err(PaymentEngineError::ExportError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parquet_ledger() -> Result<()> {
+        let mut deposit = Transaction::new(TransactionKind::Deposit, 1, 1, dec!(10))
+            .with_timestamp(Some(1700000000));
+        deposit.status = TransactionStatus::ChargedBack;
+        deposit.charged_back = dec!(10);
+        let entries = [
+            LedgerEntry {
+                client: 1,
+                transaction: deposit,
+                dispute: Some(DisputeStatus::Resolved(DisputeResolution::ChargedBack)),
+                dispute_expired_at: Some(1700432000),
+                fee: dec!(15),
+            },
+            LedgerEntry {
+                client: 1,
+                transaction: Transaction::new_transfer(2, 1, 2, dec!(1), Currency::default()),
+                dispute: None,
+                dispute_expired_at: None,
+                fee: dec!(0),
+            },
+        ];
+
+        let mut output = Vec::new();
+        write_parquet(ledger_batch(&entries)?, &mut output).await?;
+
+        let batch = read_parquet(output);
+        assert_eq!(batch.schema(), ledger_schema());
+        let dictionary = |name: &str, row: usize| {
+            let column = batch.column_by_name(name).unwrap();
+            let column = column.as_dictionary::<Int8Type>();
+            column.is_valid(row).then(|| {
+                let key = column.keys().value(row) as usize;
+                column.values().as_string::<i32>().value(key).to_string()
+            })
+        };
+        assert_eq!(dictionary("kind", 1).as_deref(), Some("transfer"));
+        assert_eq!(dictionary("status", 0).as_deref(), Some("charged_back"));
+        assert_eq!(
+            dictionary("dispute_status", 0).as_deref(),
+            Some("charged_back")
+        );
+        assert_eq!(dictionary("dispute_status", 1), None);
+        let source = batch.column_by_name("source").unwrap();
+        assert_eq!(
+            source
+                .as_primitive::<arrow_array::types::UInt16Type>()
+                .values(),
+            &[1, 1]
+        );
+        let counterparty = batch.column_by_name("counterparty").unwrap();
+        assert!(counterparty.is_null(0));
+        assert_eq!(
+            counterparty
+                .as_primitive::<arrow_array::types::UInt16Type>()
+                .value(1),
+            2
+        );
+
+        Ok(())
+    }
+}
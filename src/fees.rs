@@ -0,0 +1,182 @@
+/// Fee schedules loaded from a local CSV file.
+/// Each row is a rule `client,operation,currency,min_amount,flat,percent`:
+/// - `client` empty for the default schedule, set to override it for one client.
+/// - `currency` empty to match any currency.
+/// - `min_amount` is the lower bound of a tier, the highest tier below the amount wins.
+/// - the fee is `flat + amount * percent / 100`, rounded to 4 decimal places.
+/// - a chargeback fee is limited to the available funds, it never makes them negative.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::{
+    account::AccountId,
+    csv::read_records,
+    currency::Currency,
+    errors::{AccountOperationError, AccountOperationResult, Result},
+    fx::DEFAULT_SCALE,
+    transaction::TransactionId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeOperation {
+    Deposit,
+    Withdrawal,
+    Chargeback,
+}
+
+impl Display for FeeOperation {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            FeeOperation::Deposit => write!(f, "Deposit"),
+            FeeOperation::Withdrawal => write!(f, "Withdrawal"),
+            FeeOperation::Chargeback => write!(f, "Chargeback"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FeeRule {
+    #[serde(default)]
+    pub client: Option<AccountId>,
+    pub operation: FeeOperation,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub min_amount: Option<Decimal>,
+    #[serde(default)]
+    pub flat: Option<Decimal>,
+    #[serde(default)]
+    pub percent: Option<Decimal>,
+}
+
+impl FeeRule {
+    fn applies_to(&self, operation: FeeOperation, currency: Currency, amount: Decimal) -> bool {
+        self.operation == operation
+            && self.currency.is_none_or(|c| c == currency)
+            && self.min_amount.unwrap_or(Decimal::ZERO) <= amount
+    }
+
+    /// `None` if the fee overflows.
+    fn fee(&self, amount: Decimal) -> Option<Decimal> {
+        let flat = self.flat.unwrap_or(Decimal::ZERO);
+        let percent = self.percent.unwrap_or(Decimal::ZERO);
+        let fee = amount
+            .checked_mul(percent)?
+            .checked_div(Decimal::ONE_HUNDRED)?
+            .checked_add(flat)?;
+        Some(fee.round_dp_with_strategy(DEFAULT_SCALE, RoundingStrategy::MidpointNearestEven))
+    }
+}
+
+/// A fee taken from an account, kept apart from the transaction that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeePosting {
+    pub tx_id: TransactionId,
+    pub operation: FeeOperation,
+    pub currency: Currency,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedule {
+    rules: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    pub fn new(rules: Vec<FeeRule>) -> Self {
+        Self { rules }
+    }
+
+    pub async fn from_csv<R: AsyncRead + Unpin + Send>(reader: R) -> Result<Self> {
+        Ok(Self::new(read_records(reader).await?))
+    }
+
+    /// Fee of an operation for a client.
+    /// Client rules of an operation replace the default ones, then the most specific currency
+    /// and the highest tier win.
+    pub fn fee(
+        &self,
+        client: AccountId,
+        operation: FeeOperation,
+        currency: Currency,
+        amount: Decimal,
+    ) -> AccountOperationResult<Decimal> {
+        let has_override = self
+            .rules
+            .iter()
+            .any(|r| r.client == Some(client) && r.operation == operation);
+        let client_filter = if has_override { Some(client) } else { None };
+
+        self.rules
+            .iter()
+            .filter(|r| r.client == client_filter && r.applies_to(operation, currency, amount))
+            .max_by_key(|r| (r.currency.is_some(), r.min_amount.unwrap_or(Decimal::ZERO)))
+            .map_or(Ok(Decimal::ZERO), |r| {
+                r.fee(amount)
+                    .ok_or(AccountOperationError::FeeOverflow(operation, amount))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn fee_schedule_from_csv() -> Result<()> {
+        let data = b"\
+client,operation,currency,min_amount,flat,percent
+,withdrawal,,,0.5,
+,withdrawal,,1000,,0.1
+,withdrawal,EUR,,0.25,
+,chargeback,,,15,
+7,withdrawal,,,,
+"
+        .as_slice();
+        let fees = FeeSchedule::from_csv(data).await?;
+        let (xxx, eur) = (Currency::default(), "EUR".parse()?);
+
+        assert_eq!(
+            fees.fee(1, FeeOperation::Withdrawal, xxx, dec!(10))?,
+            dec!(0.5)
+        );
+        assert_eq!(
+            fees.fee(1, FeeOperation::Withdrawal, xxx, dec!(2000))?,
+            dec!(2)
+        );
+        assert_eq!(
+            fees.fee(1, FeeOperation::Withdrawal, eur, dec!(10))?,
+            dec!(0.25)
+        );
+        assert_eq!(
+            fees.fee(1, FeeOperation::Chargeback, xxx, dec!(10))?,
+            dec!(15)
+        );
+        assert_eq!(fees.fee(1, FeeOperation::Deposit, xxx, dec!(10))?, dec!(0));
+        // Client 7 has free withdrawals but pays chargebacks
+        assert_eq!(
+            fees.fee(7, FeeOperation::Withdrawal, xxx, dec!(2000))?,
+            dec!(0)
+        );
+        assert_eq!(
+            fees.fee(7, FeeOperation::Chargeback, xxx, dec!(10))?,
+            dec!(15)
+        );
+
+        let fees = FeeSchedule::from_csv(b"operation,percent\nwithdrawal,100\n".as_slice()).await?;
+        assert_eq!(
+            fees.fee(1, FeeOperation::Withdrawal, xxx, Decimal::MAX),
+            Err(AccountOperationError::FeeOverflow(
+                FeeOperation::Withdrawal,
+                Decimal::MAX
+            ))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+/// Foreign exchange rates loaded from a local CSV file.
+/// Each row gives the rate of a currency pair from an effective time (unix seconds), the latest
+/// effective rate wins. Converted amounts are rounded explicitly to `scale` decimal places with
+/// banker's rounding so repeated conversions do not drift in one direction.
+use std::collections::HashMap;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::{csv::read_records, currency::Currency, errors::Result};
+
+pub const DEFAULT_SCALE: u32 = 4;
+
+#[derive(Debug, Clone, Deserialize)]
+struct FxRateRecord {
+    from: Currency,
+    to: Currency,
+    rate: Decimal,
+    /// Rows without effective time are always effective.
+    #[serde(default)]
+    effective: Option<i64>,
+}
+
+/// A rate applied to an amount.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FxConversion {
+    pub rate: Decimal,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct FxRates {
+    /// Rates per pair, sorted by effective time.
+    rates: HashMap<(Currency, Currency), Vec<(i64, Decimal)>>,
+    scale: u32,
+}
+
+impl Default for FxRates {
+    fn default() -> Self {
+        Self {
+            rates: HashMap::new(),
+            scale: DEFAULT_SCALE,
+        }
+    }
+}
+
+impl FxRates {
+    pub fn new(scale: u32) -> Self {
+        Self {
+            scale,
+            ..Self::default()
+        }
+    }
+
+    pub async fn from_csv<R: AsyncRead + Unpin + Send>(reader: R, scale: u32) -> Result<Self> {
+        let mut rates = Self::new(scale);
+        for rate in read_records::<FxRateRecord, _>(reader).await? {
+            rates.insert(
+                rate.from,
+                rate.to,
+                rate.rate,
+                rate.effective.unwrap_or(i64::MIN),
+            );
+        }
+
+        Ok(rates)
+    }
+
+    pub fn insert(&mut self, from: Currency, to: Currency, rate: Decimal, effective: i64) {
+        let pair_rates = self.rates.entry((from, to)).or_default();
+        let index = pair_rates.partition_point(|(t, _)| *t <= effective);
+        pair_rates.insert(index, (effective, rate));
+    }
+
+    /// Rate of `from` in `to` at a given time, the latest one without time.
+    /// Falls back to the inverse of the reverse pair.
+    pub fn rate(&self, from: Currency, to: Currency, at: Option<i64>) -> Option<Decimal> {
+        if from == to {
+            return Some(Decimal::ONE);
+        }
+
+        let lookup = |pair| {
+            let pair_rates: &Vec<(i64, Decimal)> = self.rates.get(&pair)?;
+            let effective = match at {
+                Some(at) => pair_rates.partition_point(|(t, _)| *t <= at),
+                None => pair_rates.len(),
+            };
+            effective.checked_sub(1).map(|i| pair_rates[i].1)
+        };
+
+        lookup((from, to)).or_else(|| {
+            lookup((to, from))
+                .filter(|r| !r.is_zero())
+                .and_then(|r| Decimal::ONE.checked_div(r))
+        })
+    }
+
+    pub fn convert(
+        &self,
+        amount: Decimal,
+        from: Currency,
+        to: Currency,
+        at: Option<i64>,
+    ) -> Option<FxConversion> {
+        let rate = self.rate(from, to, at)?;
+        let amount = amount
+            .checked_mul(rate)?
+            .round_dp_with_strategy(self.scale, RoundingStrategy::MidpointNearestEven);
+        Some(FxConversion { rate, amount })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn fx_rates_from_csv() -> Result<()> {
+        let data = b"\
+from,to,rate,effective
+EUR,USD,1.10,
+EUR,USD,1.20,100
+USD,JPY,150,
+"
+        .as_slice();
+        let rates = FxRates::from_csv(data, 2).await?;
+        let (eur, usd, jpy) = ("EUR".parse()?, "USD".parse()?, "JPY".parse()?);
+
+        assert_eq!(rates.rate(eur, usd, None), Some(dec!(1.20)));
+        assert_eq!(rates.rate(eur, usd, Some(99)), Some(dec!(1.10)));
+        assert_eq!(rates.rate(eur, eur, None), Some(dec!(1)));
+        assert_eq!(rates.rate(eur, jpy, None), None);
+        assert_eq!(
+            rates.convert(dec!(300), jpy, usd, None),
+            Some(FxConversion {
+                rate: dec!(1) / dec!(150),
+                amount: dec!(2.00)
+            })
+        );
+        // 10.125 is a midpoint, rounded to the even neighbour
+        assert_eq!(
+            rates
+                .convert(dec!(8.4375), eur, usd, None)
+                .map(|c| c.amount),
+            Some(dec!(10.12))
+        );
+
+        Ok(())
+    }
+}
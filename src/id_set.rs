@@ -0,0 +1,321 @@
+/// Compact set of transaction ids.
+/// `TransactionId` is a `u32` so ids are split like in a roaring bitmap: the high 16 bits select
+/// a page, the low 16 bits an entry inside it. A page holds a sorted array of its ids while it has
+/// few of them and a bitset (8KiB per 65536 ids) once the array would be larger, so sparse ids
+/// cost a couple of bytes each and dense ones a bit each, instead of tens of bytes with a
+/// `HashSet`.
+use std::collections::HashMap;
+
+use crate::{tasks::command::TransactionOutcome, transaction::TransactionId};
+
+const PAGE_BITS: u32 = 16;
+const WORDS_PER_PAGE: usize = (1 << PAGE_BITS) / u64::BITS as usize;
+/// Largest array page, a bitset takes the same room.
+const MAX_ARRAY_LEN: usize = WORDS_PER_PAGE * 4;
+
+#[derive(Debug)]
+enum Page {
+    /// Sorted low bits of the ids.
+    Array(Vec<u16>),
+    /// One bit per id with the number of bits set.
+    Bitset(Box<[u64; WORDS_PER_PAGE]>, usize),
+}
+
+impl Page {
+    fn len(&self) -> usize {
+        match self {
+            Page::Array(ids) => ids.len(),
+            Page::Bitset(_, len) => *len,
+        }
+    }
+
+    fn locate(low: u16) -> (usize, u64) {
+        let low = low as usize;
+        (low / u64::BITS as usize, 1 << (low % u64::BITS as usize))
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match self {
+            Page::Array(ids) => ids.binary_search(&low).is_ok(),
+            Page::Bitset(words, _) => {
+                let (word, mask) = Self::locate(low);
+                words[word] & mask != 0
+            }
+        }
+    }
+
+    fn insert(&mut self, low: u16) -> bool {
+        match self {
+            Page::Array(ids) => {
+                let Err(index) = ids.binary_search(&low) else {
+                    return false;
+                };
+                if ids.len() < MAX_ARRAY_LEN {
+                    ids.insert(index, low);
+                    return true;
+                }
+                let mut words = Box::new([0; WORDS_PER_PAGE]);
+                for id in ids.iter() {
+                    let (word, mask) = Self::locate(*id);
+                    words[word] |= mask;
+                }
+                *self = Page::Bitset(words, ids.len());
+                self.insert(low)
+            }
+            Page::Bitset(words, len) => {
+                let (word, mask) = Self::locate(low);
+                if words[word] & mask != 0 {
+                    return false;
+                }
+                words[word] |= mask;
+                *len += 1;
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, low: u16) -> bool {
+        match self {
+            Page::Array(ids) => match ids.binary_search(&low) {
+                Ok(index) => {
+                    ids.remove(index);
+                    true
+                }
+                Err(_) => false,
+            },
+            Page::Bitset(words, len) => {
+                let (word, mask) = Self::locate(low);
+                if words[word] & mask == 0 {
+                    return false;
+                }
+                words[word] &= !mask;
+                *len -= 1;
+                // Back to an array with some slack, a page on the edge doesn't flip each time
+                if *len <= MAX_ARRAY_LEN / 2 {
+                    let ids: Vec<u16> = (0..=u16::MAX)
+                        .filter(|id| {
+                            let (word, mask) = Self::locate(*id);
+                            words[word] & mask != 0
+                        })
+                        .collect();
+                    *self = Page::Array(ids);
+                }
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TransactionIdSet {
+    pages: HashMap<u16, Page>,
+    len: usize,
+}
+
+impl TransactionIdSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn split(id: TransactionId) -> (u16, u16) {
+        ((id >> PAGE_BITS) as u16, id as u16)
+    }
+
+    pub fn contains(&self, id: TransactionId) -> bool {
+        let (page, low) = Self::split(id);
+        self.pages.get(&page).is_some_and(|p| p.contains(low))
+    }
+
+    /// Adds an id to the set.
+    /// Returns `false` if the id was already present, like `HashSet::insert`.
+    pub fn insert(&mut self, id: TransactionId) -> bool {
+        let (page, low) = Self::split(id);
+        let inserted = self
+            .pages
+            .entry(page)
+            .or_insert_with(|| Page::Array(Vec::new()))
+            .insert(low);
+        self.len += usize::from(inserted);
+        inserted
+    }
+
+    /// Removes an id from the set.
+    /// Returns `false` if the id was not present. Empty pages are released.
+    pub fn remove(&mut self, id: TransactionId) -> bool {
+        let (page, low) = Self::split(id);
+        let Some(p) = self.pages.get_mut(&page) else {
+            return false;
+        };
+        if !p.remove(low) {
+            return false;
+        }
+        self.len -= 1;
+        if p.len() == 0 {
+            self.pages.remove(&page);
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Where a transaction id stands from the engine's point of view.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionIdStatus {
+    /// Dispatched to an account worker, outcome not known yet.
+    Seen,
+    Applied,
+    Rejected,
+}
+
+/// Whether the id of a rejected transaction can be submitted again.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RejectedIdPolicy {
+    /// Any id that reached an account worker is burnt, even if the transaction was rejected.
+    #[default]
+    Reserved,
+    /// A rejected id can be reused by a later transaction.
+    Reusable,
+}
+
+/// Single authority on transaction id uniqueness across all clients.
+#[derive(Debug, Default)]
+pub struct TransactionIdRegistry {
+    policy: RejectedIdPolicy,
+    seen: TransactionIdSet,
+    applied: TransactionIdSet,
+    rejected: TransactionIdSet,
+}
+
+impl TransactionIdRegistry {
+    pub fn new(policy: RejectedIdPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> RejectedIdPolicy {
+        self.policy
+    }
+
+    pub fn status(&self, id: TransactionId) -> Option<TransactionIdStatus> {
+        if self.seen.contains(id) {
+            Some(TransactionIdStatus::Seen)
+        } else if self.applied.contains(id) {
+            Some(TransactionIdStatus::Applied)
+        } else if self.rejected.contains(id) {
+            Some(TransactionIdStatus::Rejected)
+        } else {
+            None
+        }
+    }
+
+    /// Can a new transaction use this id?
+    pub fn is_available(&self, id: TransactionId) -> bool {
+        match self.status(id) {
+            None => true,
+            Some(TransactionIdStatus::Rejected) => self.policy == RejectedIdPolicy::Reusable,
+            Some(TransactionIdStatus::Seen) | Some(TransactionIdStatus::Applied) => false,
+        }
+    }
+
+    pub fn mark_seen(&mut self, id: TransactionId) {
+        self.rejected.remove(id);
+        self.seen.insert(id);
+    }
+
+    pub fn record(&mut self, outcome: &TransactionOutcome) {
+        let id = outcome.tx_id();
+        self.seen.remove(id);
+        match outcome {
+            TransactionOutcome::Applied(_) => self.applied.insert(id),
+            TransactionOutcome::Rejected(_) => self.rejected.insert(id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_set_insert_and_contains() {
+        let mut set = TransactionIdSet::new();
+        assert!(!set.contains(0));
+
+        for id in [0, 1, 63, 64, 65535, 65536, u32::MAX] {
+            assert!(set.insert(id));
+            assert!(set.contains(id));
+        }
+        assert!(!set.insert(65536));
+        assert!(!set.contains(2));
+        assert_eq!(set.len(), 7);
+        // 0..65535 share a page, 65536 and u32::MAX have their own.
+        assert_eq!(set.pages.len(), 3);
+    }
+
+    #[test]
+    fn id_set_remove_releases_empty_pages() {
+        let mut set = TransactionIdSet::new();
+        set.insert(70000);
+        assert!(!set.remove(70001));
+        assert!(set.remove(70000));
+        assert!(!set.contains(70000));
+        assert!(set.is_empty());
+        assert!(set.pages.is_empty());
+    }
+
+    #[test]
+    fn id_set_switches_page_layout() {
+        let mut set = TransactionIdSet::new();
+        let ids = (0..=MAX_ARRAY_LEN as u32).map(|i| i * 2);
+        for id in ids.clone() {
+            assert!(set.insert(id));
+        }
+        assert!(matches!(set.pages[&0], Page::Bitset(_, len) if len == MAX_ARRAY_LEN + 1));
+        assert!(set.contains(2 * MAX_ARRAY_LEN as u32));
+        assert!(!set.contains(1));
+
+        for id in ids.clone().take(MAX_ARRAY_LEN / 2 + 1) {
+            assert!(set.remove(id));
+        }
+        assert!(matches!(&set.pages[&0], Page::Array(ids) if ids.len() == MAX_ARRAY_LEN / 2));
+        assert!(ids.skip(MAX_ARRAY_LEN / 2 + 1).all(|id| set.contains(id)));
+        assert_eq!(set.len(), MAX_ARRAY_LEN / 2);
+    }
+
+    #[test]
+    fn registry_reserves_rejected_ids_by_default() {
+        let mut registry = TransactionIdRegistry::default();
+        assert!(registry.is_available(1));
+
+        registry.mark_seen(1);
+        assert_eq!(registry.status(1), Some(TransactionIdStatus::Seen));
+        assert!(!registry.is_available(1));
+
+        registry.record(&TransactionOutcome::Rejected(1));
+        assert_eq!(registry.status(1), Some(TransactionIdStatus::Rejected));
+        assert!(!registry.is_available(1));
+    }
+
+    #[test]
+    fn registry_can_reuse_rejected_ids() {
+        let mut registry = TransactionIdRegistry::new(RejectedIdPolicy::Reusable);
+
+        registry.mark_seen(1);
+        registry.record(&TransactionOutcome::Rejected(1));
+        assert!(registry.is_available(1));
+
+        registry.mark_seen(1);
+        registry.record(&TransactionOutcome::Applied(1));
+        assert_eq!(registry.status(1), Some(TransactionIdStatus::Applied));
+        assert!(!registry.is_available(1));
+    }
+}
@@ -1,6 +1,22 @@
 pub mod account;
+pub mod analytics;
+pub mod cli;
+pub mod clients;
 pub mod csv;
+pub mod currency;
+pub mod dry_run;
 pub mod engine;
 pub mod errors;
+pub mod export;
+pub mod fees;
+pub mod fx;
+pub mod id_set;
+pub mod metrics;
+pub mod reconcile;
+pub mod report;
+pub mod risk;
+pub mod stats;
 pub mod tasks;
+pub mod tiers;
 pub mod transaction;
+pub mod validation;
@@ -1,27 +1,113 @@
 use payment_engine::{
-    csv::send_accounts_csv_to_stdout,
+    analytics::send_dispute_report,
+    cli::CliOptions,
+    clients::KnownClients,
+    csv::{read_balances, send_opening_balances},
+    dry_run::dry_run,
     engine::PaymentEngine,
     errors::{PaymentEngineError, Result},
-    tasks::producer::TransactionProducer,
+    export::send_ledger_parquet,
+    fees::FeeSchedule,
+    fx::{FxRates, DEFAULT_SCALE},
+    metrics::{serve_metrics, Metrics},
+    reconcile::send_reconciliation_report,
+    report::{send_accounts_report, WriterSink},
+    risk::{send_alerts_csv, RiskRules},
+    stats::{collect_operation_stats, OperationStats, RunStats},
+    tasks::{
+        producer::{write_rejections_csv, CommandReader, TransactionProducer},
+        source::ReaderSource,
+    },
+    tiers::AccountTiers,
 };
 
-use tokio::{fs::File, io::stdout, sync::mpsc};
+use std::{sync::Arc, time::Instant};
+
+use tokio::{
+    fs::File,
+    io::{stdout, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    let started = Instant::now();
+
+    let options = CliOptions::parse(std::env::args())?;
+
+    let mut config = options.engine_config();
+    if let Some(path) = &options.fx_rates_path {
+        config.fx_rates = FxRates::from_csv(File::open(path).await?, DEFAULT_SCALE).await?;
+    }
+    if let Some(path) = &options.fees_path {
+        config.fees = FeeSchedule::from_csv(File::open(path).await?).await?;
+    }
+    if let Some(path) = &options.known_clients_path {
+        config.known_clients = Some(KnownClients::from_csv(File::open(path).await?).await?);
+    }
+    if let Some(path) = &options.tiers_path {
+        config.tiers = AccountTiers::from_csv(File::open(path).await?).await?;
+    }
+    if let Some(path) = &options.risk_rules_path {
+        config.risk_rules = RiskRules::from_csv(File::open(path).await?).await?;
+    }
 
-    let csv_path = std::env::args().nth(1).ok_or_else(|| {
-        PaymentEngineError::CommandLineError(format!(
-            "Missing input file name. Usage: {} <filename>.csv",
-            std::env::args().next().unwrap()
-        ))
-    })?;
+    if let Some(addr) = &options.metrics_addr {
+        let metrics = Arc::new(Metrics::new()?);
+        let listener = TcpListener::bind(addr).await?;
+        log::info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        // Served until the run ends
+        tokio::spawn(serve_metrics(listener, metrics.clone()));
+        config.metrics = Some(metrics);
+    }
 
-    let csv_file = File::open(csv_path).await?;
+    // Read before processing, a bad file should fail fast
+    let expected_balances = match &options.expected_balances_path {
+        Some(path) => Some(read_balances(File::open(path).await?, options.default_currency).await?),
+        None => None,
+    };
+
+    let input_file = File::open(&options.input_path).await?;
+
+    let mut output: Box<dyn AsyncWrite + Unpin + Send> = match &options.output_path {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(stdout()),
+    };
+
+    if options.dry_run {
+        let reader = CommandReader::new(
+            ReaderSource::new(input_file, options.input_format()),
+            options.default_currency,
+            options.validator.clone(),
+        );
+        let report = dry_run(reader, &config).await?;
+        if let Some(path) = &options.rejections_path {
+            write_rejections_csv(&report.rejections, File::create(path).await?).await?;
+        }
+        report.write_summary_csv(&mut output).await?;
+        // Nothing was processed, only the input figures are filled
+        write_stats(
+            &options,
+            &RunStats::new(
+                &report.read_stats(),
+                OperationStats::default(),
+                started.elapsed(),
+            ),
+        )
+        .await?;
+        if !report.rejections.is_empty() {
+            return Err(PaymentEngineError::RejectedRecords(report.rejections.len()));
+        }
+        return Ok(());
+    }
 
     let (engine_sender, engine_receiver) = mpsc::channel(512);
-    let mut engine = PaymentEngine::new(engine_receiver);
+    let mut engine = PaymentEngine::new_with_config(engine_receiver, config);
     let engine_join = tokio::spawn(async move {
         while let Some(command) = engine.receiver.recv().await {
             // Do not abort engine on command processing errors
@@ -41,11 +127,72 @@ async fn main() -> Result<()> {
         Ok(())
     });
 
-    let producer = TransactionProducer::new(csv_file, engine_sender.clone());
-    producer.start().await?;
+    if let Some(path) = &options.opening_balances_path {
+        send_opening_balances(
+            File::open(path).await?,
+            engine_sender.clone(),
+            options.default_currency,
+        )
+        .await?;
+    }
+
+    let mut producer = TransactionProducer::new_with_validator(
+        ReaderSource::new(input_file, options.input_format()),
+        engine_sender.clone(),
+        options.default_currency,
+        options.validator.clone(),
+    );
+    if options.rejections_path.is_some() {
+        producer = producer.with_engine_rejections();
+    }
+    let read_stats = producer.start().await?;
+    if let Some(path) = &options.rejections_path {
+        write_rejections_csv(&read_stats.all_rejections(), File::create(path).await?).await?;
+    }
 
-    let mut stdout = stdout();
-    send_accounts_csv_to_stdout(engine_sender, &mut stdout).await?;
+    let mut mismatches = 0;
+    if let Some(expected) = expected_balances {
+        mismatches =
+            send_reconciliation_report(engine_sender.clone(), expected, &mut output).await?;
+    } else if options.dispute_report {
+        send_dispute_report(engine_sender.clone(), &mut output).await?;
+    } else {
+        let mut sink = WriterSink::new(&mut output, options.output_format());
+        send_accounts_report(engine_sender.clone(), &mut sink, options.report_mode()).await?;
+    }
+    if let Some(path) = &options.ledger_path {
+        send_ledger_parquet(engine_sender.clone(), File::create(path).await?).await?;
+    }
+    if let Some(path) = &options.alerts_path {
+        send_alerts_csv(engine_sender.clone(), File::create(path).await?).await?;
+    }
+    if options.stats || options.stats_json_path.is_some() {
+        let operations = collect_operation_stats(engine_sender.clone()).await?;
+        write_stats(
+            &options,
+            &RunStats::new(&read_stats, operations, started.elapsed()),
+        )
+        .await?;
+    }
+    // The engine stops once the last sender is gone
+    drop(engine_sender);
+
+    let engine_result: Result<()> = engine_join.await?;
+    engine_result?;
+
+    if mismatches > 0 {
+        return Err(PaymentEngineError::ReconciliationMismatch(mismatches));
+    }
+    Ok(())
+}
 
-    engine_join.await?
+/// Print the run summary and write its JSON, as asked.
+async fn write_stats(options: &CliOptions, stats: &RunStats) -> Result<()> {
+    if options.stats {
+        eprint!("{}", stats);
+    }
+    if let Some(path) = &options.stats_json_path {
+        stats.write_json(File::create(path).await?).await?;
+    }
+    Ok(())
 }
@@ -0,0 +1,339 @@
+/// Prometheus metrics of the engine, served in the text format on a local HTTP endpoint while
+/// the engine runs. The engine and the workers record their commands, errors and latencies; the
+/// engine channel depth is sampled each time the engine takes a command, so a saturated engine
+/// channel shows as `channel_queue_depth` reaching `channel_capacity`. The worker channels are
+/// sampled on an interval and a send to a full worker channel counts as a saturation.
+/// The HTTP side only answers `GET /metrics`, it doesn't need a web framework.
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+    time::Duration,
+};
+
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::errors::{AccountOperationError, PaymentEngineError, Result};
+
+const NAMESPACE: &str = "payment_engine";
+
+/// Longest request line read, the rest of the request is ignored anyway.
+const MAX_REQUEST_LINE: u64 = 1024;
+/// Time given to a client to send its request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Latency buckets in seconds, commands usually take microseconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+/// Where a command is handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Engine,
+    Worker,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Engine => "engine",
+            Stage::Worker => "worker",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    errors: IntCounterVec,
+    latency: HistogramVec,
+    queue_depth: IntGaugeVec,
+    queue_depth_max: IntGaugeVec,
+    queue_capacity: IntGaugeVec,
+    saturated: IntCounterVec,
+    active_workers: IntGauge,
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let metrics = Self {
+            registry: Registry::new(),
+            commands: IntCounterVec::new(
+                opts("commands_total", "Commands handled, by stage and command."),
+                &["stage", "command"],
+            )?,
+            errors: IntCounterVec::new(
+                opts(
+                    "errors_total",
+                    "Commands refused, by stage and AccountOperationError variant.",
+                ),
+                &["stage", "error"],
+            )?,
+            latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "command_duration_seconds",
+                    "Time spent handling a command, by stage and command.",
+                )
+                .namespace(NAMESPACE)
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["stage", "command"],
+            )?,
+            queue_depth: IntGaugeVec::new(
+                opts(
+                    "channel_queue_depth",
+                    "Commands waiting in the engine channel or, summed, in the worker channels.",
+                ),
+                &["channel"],
+            )?,
+            queue_depth_max: IntGaugeVec::new(
+                opts(
+                    "channel_queue_depth_max",
+                    "Highest queue depth seen, of a single channel for the workers.",
+                ),
+                &["channel"],
+            )?,
+            queue_capacity: IntGaugeVec::new(
+                opts(
+                    "channel_capacity",
+                    "Capacity of the engine channel or, summed, of the worker channels.",
+                ),
+                &["channel"],
+            )?,
+            saturated: IntCounterVec::new(
+                opts(
+                    "channel_saturated_total",
+                    "Commands sent to a full channel, their senders were blocked.",
+                ),
+                &["channel"],
+            )?,
+            active_workers: IntGauge::with_opts(opts(
+                "active_workers",
+                "Account workers running.",
+            ))?,
+        };
+
+        let collectors: [Box<dyn Collector>; 8] = [
+            Box::new(metrics.commands.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.latency.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.queue_depth_max.clone()),
+            Box::new(metrics.queue_capacity.clone()),
+            Box::new(metrics.saturated.clone()),
+            Box::new(metrics.active_workers.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    /// Record a handled command and its outcome.
+    pub fn observe_command<T>(
+        &self,
+        stage: Stage,
+        command: &'static str,
+        elapsed: Duration,
+        result: &Result<T>,
+    ) {
+        self.commands
+            .with_label_values(&[stage.name(), command])
+            .inc();
+        self.latency
+            .with_label_values(&[stage.name(), command])
+            .observe(elapsed.as_secs_f64());
+        if let Err(PaymentEngineError::AccountProcessError(e)) = result {
+            self.observe_error(stage, e);
+        }
+    }
+
+    pub fn observe_error(&self, stage: Stage, e: &AccountOperationError) {
+        self.errors
+            .with_label_values(&[stage.name(), e.name()])
+            .inc();
+    }
+
+    /// Record the depth of a channel, `depth` counting the command just taken from it.
+    pub fn observe_queue(&self, channel: &'static str, depth: usize, capacity: usize) {
+        let (depth, capacity) = (depth as i64, capacity as i64);
+        self.queue_depth.with_label_values(&[channel]).set(depth);
+        self.queue_capacity
+            .with_label_values(&[channel])
+            .set(capacity);
+        self.observe_max_depth(channel, depth);
+        if capacity > 0 && depth >= capacity {
+            self.observe_saturated(channel);
+        }
+    }
+
+    /// Record the `(depth, capacity)` of each worker channel: the summed depth and capacity and
+    /// the deepest channel. Their saturations are counted on send, see `observe_saturated`.
+    pub fn observe_worker_queues<I: IntoIterator<Item = (usize, usize)>>(&self, queues: I) {
+        let (depth, capacity, deepest) = queues.into_iter().fold(
+            (0, 0, 0),
+            |(depth, capacity, deepest), (queue_depth, queue_capacity)| {
+                (
+                    depth + queue_depth,
+                    capacity + queue_capacity,
+                    deepest.max(queue_depth),
+                )
+            },
+        );
+        self.queue_depth
+            .with_label_values(&["workers"])
+            .set(depth as i64);
+        self.queue_capacity
+            .with_label_values(&["workers"])
+            .set(capacity as i64);
+        self.observe_max_depth("workers", deepest as i64);
+    }
+
+    fn observe_max_depth(&self, channel: &'static str, depth: i64) {
+        let max = self.queue_depth_max.with_label_values(&[channel]);
+        if depth > max.get() {
+            max.set(depth);
+        }
+    }
+
+    /// Record a command sent to a full channel.
+    pub fn observe_saturated(&self, channel: &'static str) {
+        self.saturated.with_label_values(&[channel]).inc();
+    }
+
+    pub fn set_active_workers(&self, count: usize) {
+        self.active_workers.set(count as i64);
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// The request line is all we need, the rest of the request is ignored.
+async fn read_request_line(stream: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    BufReader::new(stream.take(MAX_REQUEST_LINE))
+        .read_until(b'\n', &mut line)
+        .await?;
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+async fn answer(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream))
+        .await
+        .map_err(|_| PaymentEngineError::MetricsError("request timed out".to_string()))??;
+    let mut request_line = request.split_whitespace();
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render()?;
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                TextEncoder::new().format_type(),
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            response
+        }
+        _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+    };
+    stream.write_all(&response).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Answer the scrapes until the task is dropped.
+pub async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("Metrics endpoint failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &metrics).await {
+                log::error!("Metrics endpoint failed to answer: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metrics_endpoint() -> Result<()> {
+        let metrics = Arc::new(Metrics::new()?);
+        metrics.observe_command::<()>(
+            Stage::Worker,
+            "withdrawal",
+            Duration::from_micros(20),
+            &Err(AccountOperationError::InsufficientFunds.into()),
+        );
+        metrics.observe_queue("engine", 512, 512);
+        metrics.observe_queue("engine", 3, 512);
+        metrics.observe_worker_queues([(3, 32), (10, 32)]);
+        metrics.observe_saturated("workers");
+        metrics.set_active_workers(2);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(serve_metrics(listener, metrics));
+
+        // A request line split over several packets
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET /met").await?;
+        stream.flush().await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        stream.write_all(b"rics HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for line in [
+            "payment_engine_commands_total{command=\"withdrawal\",stage=\"worker\"} 1",
+            "payment_engine_errors_total{error=\"InsufficientFunds\",stage=\"worker\"} 1",
+            "payment_engine_command_duration_seconds_count{command=\"withdrawal\",stage=\"worker\"} 1",
+            "payment_engine_channel_queue_depth{channel=\"engine\"} 3",
+            "payment_engine_channel_queue_depth_max{channel=\"engine\"} 512",
+            "payment_engine_channel_saturated_total{channel=\"engine\"} 1",
+            "payment_engine_channel_queue_depth{channel=\"workers\"} 13",
+            "payment_engine_channel_queue_depth_max{channel=\"workers\"} 10",
+            "payment_engine_channel_capacity{channel=\"workers\"} 64",
+            "payment_engine_channel_saturated_total{channel=\"workers\"} 1",
+            "payment_engine_active_workers 2",
+        ] {
+            assert!(response.lines().any(|l| l == line), "{} not in {}", line, response);
+        }
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        server.abort();
+        Ok(())
+    }
+}
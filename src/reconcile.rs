@@ -0,0 +1,186 @@
+/// Reconciliation of the engine balances against expected balances, e.g. from bank statements.
+/// Expected balances use the accounts report layout, with or without `currency`. A wallet missing
+/// on one side counts as empty and unlocked, every wallet that differs is reported with the
+/// transactions that moved it.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    account::{AccountId, WalletBalance},
+    currency::Currency,
+    errors::Result,
+    tasks::command::PaymentEngineCommand,
+    transaction::TransactionId,
+};
+
+pub const RECONCILIATION_HEADER: &str = "client,currency,expected_available,expected_held,\
+expected_total,expected_locked,available,held,total,locked,transactions\n";
+
+/// Balance of a wallet computed by the engine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletStatement {
+    pub balance: WalletBalance,
+    /// Transactions applied to the wallet, in id order.
+    pub transactions: Vec<TransactionId>,
+}
+
+/// A wallet whose expected and computed balances differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub client: AccountId,
+    pub currency: Currency,
+    pub expected: Option<WalletBalance>,
+    pub actual: Option<WalletStatement>,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{},{},", self.client, self.currency)?;
+        write_balance(f, self.expected.as_ref())?;
+        write!(f, ",")?;
+        write_balance(f, self.actual.as_ref().map(|s| &s.balance))?;
+        let transactions = self
+            .actual
+            .iter()
+            .flat_map(|s| s.transactions.iter())
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        writeln!(f, ",{}", transactions.join(" "))
+    }
+}
+
+/// Empty fields for a missing wallet.
+fn write_balance(f: &mut Formatter, balance: Option<&WalletBalance>) -> fmt::Result {
+    match balance {
+        Some(b) => write!(f, "{},{},{},{}", b.available, b.held, b.total, b.locked),
+        None => write!(f, ",,,"),
+    }
+}
+
+fn same_balance(expected: Option<&WalletBalance>, actual: Option<&WalletBalance>) -> bool {
+    let figures = |b: Option<&WalletBalance>| {
+        b.map(|b| (b.available, b.held, b.locked))
+            .unwrap_or_default()
+    };
+    figures(expected) == figures(actual)
+}
+
+/// Mismatching wallets, ordered by client and currency.
+pub fn reconcile(expected: Vec<WalletBalance>, actual: Vec<WalletStatement>) -> Vec<Mismatch> {
+    let mut wallets: BTreeMap<(AccountId, Currency), Mismatch> = BTreeMap::new();
+    fn wallet(
+        wallets: &mut BTreeMap<(AccountId, Currency), Mismatch>,
+        client: AccountId,
+        currency: Currency,
+    ) -> &mut Mismatch {
+        wallets.entry((client, currency)).or_insert(Mismatch {
+            client,
+            currency,
+            expected: None,
+            actual: None,
+        })
+    }
+    for balance in expected {
+        let (client, currency) = (balance.client, balance.currency);
+        wallet(&mut wallets, client, currency).expected = Some(balance);
+    }
+    for statement in actual {
+        let (client, currency) = (statement.balance.client, statement.balance.currency);
+        wallet(&mut wallets, client, currency).actual = Some(statement);
+    }
+
+    wallets
+        .into_values()
+        .filter(|m| !same_balance(m.expected.as_ref(), m.actual.as_ref().map(|s| &s.balance)))
+        .collect()
+}
+
+/// Write the wallets that don't match the expected balances, returning how many there are.
+pub async fn send_reconciliation_report<T: AsyncWrite + Unpin>(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    expected: Vec<WalletBalance>,
+    mut output: T,
+) -> Result<usize> {
+    let (statements_sender, mut statements_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendWalletStatements(
+            statements_sender,
+        ))
+        .await?;
+
+    let mut actual = Vec::new();
+    while let Some(statement) = statements_receiver.recv().await {
+        actual.push(statement);
+    }
+
+    let mismatches = reconcile(expected, actual);
+    output.write_all(RECONCILIATION_HEADER.as_bytes()).await?;
+    for mismatch in mismatches.iter() {
+        output.write_all(mismatch.to_string().as_bytes()).await?;
+    }
+    output.flush().await?;
+
+    Ok(mismatches.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    const XXX: Currency = Currency::NONE;
+
+    fn balance(client: AccountId, available: Decimal, held: Decimal) -> WalletBalance {
+        WalletBalance {
+            client,
+            currency: XXX,
+            available,
+            held,
+            total: available + held,
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn reconcile_balances() {
+        let expected = vec![
+            balance(1, dec!(10), dec!(0)),
+            balance(2, dec!(5), dec!(0)),
+            balance(3, dec!(0), dec!(0)),
+        ];
+        let actual = vec![
+            WalletStatement {
+                balance: balance(1, dec!(10.0), dec!(0)),
+                transactions: vec![1],
+            },
+            WalletStatement {
+                balance: balance(2, dec!(3), dec!(2)),
+                transactions: vec![2, 4],
+            },
+            WalletStatement {
+                balance: balance(4, dec!(1), dec!(0)),
+                transactions: vec![3],
+            },
+        ];
+
+        let rows: Vec<_> = reconcile(expected, actual)
+            .iter()
+            .map(Mismatch::to_string)
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                "2,XXX,5,0,5,false,3,2,5,false,2 4\n",
+                "4,XXX,,,,,1,0,1,false,3\n",
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+/// Accounts report.
+/// Account workers send the balance of their wallets, a sink formats them. New formats only need to
+/// implement `AccountReportSink`.
+use std::{future::Future, path::Path};
+
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    account::{AccountId, WalletBalance},
+    currency::Currency,
+    errors::{PaymentEngineError, Result},
+    export::ParquetSink,
+    tasks::command::PaymentEngineCommand,
+};
+
+/// Layout of the accounts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AccountsReportMode {
+    /// One row per client with its default currency wallet.
+    #[default]
+    DefaultCurrency,
+    /// One row per client per currency.
+    PerCurrency,
+    /// One row per client with every wallet converted in a currency.
+    BaseCurrency(Currency),
+}
+
+impl AccountsReportMode {
+    /// Whether rows tell their currency, only when a client may have several.
+    pub fn has_currency(&self) -> bool {
+        matches!(self, AccountsReportMode::PerCurrency)
+    }
+}
+
+pub trait AccountReportSink {
+    /// Called once before the first row.
+    fn start(&mut self, mode: AccountsReportMode) -> impl Future<Output = Result<()>> + Send;
+
+    fn write(&mut self, balance: &WalletBalance) -> impl Future<Output = Result<()>> + Send;
+
+    /// Called once after the last row.
+    fn finish(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Rows of values separated by `separator`, after a header row.
+pub struct DelimitedSink<W: AsyncWrite + Unpin + Send> {
+    output: W,
+    separator: char,
+    has_currency: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> DelimitedSink<W> {
+    pub fn csv(output: W) -> Self {
+        Self::new(output, ',')
+    }
+
+    pub fn tsv(output: W) -> Self {
+        Self::new(output, '\t')
+    }
+
+    fn new(output: W, separator: char) -> Self {
+        Self {
+            output,
+            separator,
+            has_currency: false,
+        }
+    }
+
+    async fn write_row(&mut self, fields: &[String]) -> Result<()> {
+        let mut row = fields.join(&self.separator.to_string());
+        row.push('\n');
+        self.output.write_all(row.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AccountReportSink for DelimitedSink<W> {
+    async fn start(&mut self, mode: AccountsReportMode) -> Result<()> {
+        self.has_currency = mode.has_currency();
+        let mut header = vec!["client"];
+        if self.has_currency {
+            header.push("currency");
+        }
+        header.extend(["available", "held", "total", "locked"]);
+        let header: Vec<_> = header.into_iter().map(String::from).collect();
+        self.write_row(&header).await
+    }
+
+    async fn write(&mut self, balance: &WalletBalance) -> Result<()> {
+        let mut row = vec![balance.client.to_string()];
+        if self.has_currency {
+            row.push(balance.currency.to_string());
+        }
+        row.extend([
+            balance.available.to_string(),
+            balance.held.to_string(),
+            balance.total.to_string(),
+            balance.locked.to_string(),
+        ]);
+        self.write_row(&row).await
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.output.flush().await?;
+        Ok(())
+    }
+}
+
+/// A decimal written as an exact JSON number rather than a float.
+pub struct JsonDecimal(pub Decimal);
+
+impl Serialize for JsonDecimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        RawValue::from_string(self.0.to_string())
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+/// A JSON object per account, amounts being exact JSON numbers.
+#[derive(Serialize)]
+struct AccountObject {
+    client: AccountId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+    available: JsonDecimal,
+    held: JsonDecimal,
+    total: JsonDecimal,
+    locked: bool,
+}
+
+impl AccountObject {
+    fn to_json(balance: &WalletBalance, has_currency: bool) -> Result<String> {
+        let object = AccountObject {
+            client: balance.client,
+            currency: has_currency.then(|| balance.currency.to_string()),
+            available: JsonDecimal(balance.available),
+            held: JsonDecimal(balance.held),
+            total: JsonDecimal(balance.total),
+            locked: balance.locked,
+        };
+        serde_json::to_string(&object)
+            .map_err(|e| PaymentEngineError::InputOutpoutError(e.to_string()))
+    }
+}
+
+/// A single JSON array of accounts.
+pub struct JsonSink<W: AsyncWrite + Unpin + Send> {
+    output: W,
+    has_currency: bool,
+    first: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> JsonSink<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            has_currency: false,
+            first: true,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AccountReportSink for JsonSink<W> {
+    async fn start(&mut self, mode: AccountsReportMode) -> Result<()> {
+        self.has_currency = mode.has_currency();
+        self.output.write_all(b"[").await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, balance: &WalletBalance) -> Result<()> {
+        let separator = if self.first { "\n" } else { ",\n" };
+        self.first = false;
+        let object = AccountObject::to_json(balance, self.has_currency)?;
+        self.output
+            .write_all(format!("{}{}", separator, object).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.output.write_all(b"\n]\n").await?;
+        self.output.flush().await?;
+        Ok(())
+    }
+}
+
+/// One JSON object per line.
+pub struct JsonLinesSink<W: AsyncWrite + Unpin + Send> {
+    output: W,
+    has_currency: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> JsonLinesSink<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            has_currency: false,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AccountReportSink for JsonLinesSink<W> {
+    async fn start(&mut self, mode: AccountsReportMode) -> Result<()> {
+        self.has_currency = mode.has_currency();
+        Ok(())
+    }
+
+    async fn write(&mut self, balance: &WalletBalance) -> Result<()> {
+        let mut line = AccountObject::to_json(balance, self.has_currency)?;
+        line.push('\n');
+        self.output.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.output.flush().await?;
+        Ok(())
+    }
+}
+
+/// Format of the accounts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Tsv,
+    Json,
+    JsonLines,
+    Parquet,
+}
+
+impl OutputFormat {
+    /// Guess the format from the file extension, CSV unless `.tsv`, `.json`, `.jsonl`, `.ndjson`
+    /// or `.parquet`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => OutputFormat::Tsv,
+            Some("json") => OutputFormat::Json,
+            Some("jsonl" | "ndjson") => OutputFormat::JsonLines,
+            Some("parquet") => OutputFormat::Parquet,
+            _ => OutputFormat::Csv,
+        }
+    }
+}
+
+/// A writer in one of the output formats, picked at runtime.
+pub enum WriterSink<W: AsyncWrite + Unpin + Send> {
+    Delimited(DelimitedSink<W>),
+    Json(JsonSink<W>),
+    JsonLines(JsonLinesSink<W>),
+    Parquet(ParquetSink<W>),
+}
+
+impl<W: AsyncWrite + Unpin + Send> WriterSink<W> {
+    pub fn new(output: W, format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Csv => WriterSink::Delimited(DelimitedSink::csv(output)),
+            OutputFormat::Tsv => WriterSink::Delimited(DelimitedSink::tsv(output)),
+            OutputFormat::Json => WriterSink::Json(JsonSink::new(output)),
+            OutputFormat::JsonLines => WriterSink::JsonLines(JsonLinesSink::new(output)),
+            OutputFormat::Parquet => WriterSink::Parquet(ParquetSink::new(output)),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AccountReportSink for WriterSink<W> {
+    async fn start(&mut self, mode: AccountsReportMode) -> Result<()> {
+        match self {
+            WriterSink::Delimited(sink) => sink.start(mode).await,
+            WriterSink::Json(sink) => sink.start(mode).await,
+            WriterSink::JsonLines(sink) => sink.start(mode).await,
+            WriterSink::Parquet(sink) => sink.start(mode).await,
+        }
+    }
+
+    async fn write(&mut self, balance: &WalletBalance) -> Result<()> {
+        match self {
+            WriterSink::Delimited(sink) => sink.write(balance).await,
+            WriterSink::Json(sink) => sink.write(balance).await,
+            WriterSink::JsonLines(sink) => sink.write(balance).await,
+            WriterSink::Parquet(sink) => sink.write(balance).await,
+        }
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        match self {
+            WriterSink::Delimited(sink) => sink.finish().await,
+            WriterSink::Json(sink) => sink.finish().await,
+            WriterSink::JsonLines(sink) => sink.finish().await,
+            WriterSink::Parquet(sink) => sink.finish().await,
+        }
+    }
+}
+
+/// Write the accounts of every active worker to `sink`, failing on the first account that can't be
+/// reported.
+pub async fn send_accounts_report<S: AccountReportSink>(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    sink: &mut S,
+    mode: AccountsReportMode,
+) -> Result<()> {
+    let (balances_sender, mut balances_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendAccounts(balances_sender, mode))
+        .await?;
+
+    sink.start(mode).await?;
+    while let Some(balance) = balances_receiver.recv().await {
+        sink.write(&balance?).await?;
+    }
+    sink.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    async fn report<S: AccountReportSink>(mut sink: S, mode: AccountsReportMode) -> Result<()> {
+        sink.start(mode).await?;
+        for (client, currency) in [(1, "EUR"), (2, "USD")] {
+            sink.write(&WalletBalance {
+                client,
+                currency: currency.parse()?,
+                available: dec!(1.50),
+                held: dec!(0.5),
+                total: dec!(2.00),
+                locked: client == 2,
+            })
+            .await?;
+        }
+        sink.finish().await
+    }
+
+    #[tokio::test]
+    async fn account_report_sinks() -> Result<()> {
+        let mut output = Vec::new();
+        report(
+            DelimitedSink::csv(&mut output),
+            AccountsReportMode::DefaultCurrency,
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.50,0.5,2.00,false\n2,1.50,0.5,2.00,true\n"
+        );
+
+        let mut output = Vec::new();
+        report(
+            DelimitedSink::tsv(&mut output),
+            AccountsReportMode::PerCurrency,
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client\tcurrency\tavailable\theld\ttotal\tlocked\n\
+            1\tEUR\t1.50\t0.5\t2.00\tfalse\n\
+            2\tUSD\t1.50\t0.5\t2.00\ttrue\n"
+        );
+
+        let mut output = Vec::new();
+        report(JsonSink::new(&mut output), AccountsReportMode::PerCurrency).await?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[\n\
+            {\"client\":1,\"currency\":\"EUR\",\"available\":1.50,\"held\":0.5,\"total\":2.00,\"locked\":false},\n\
+            {\"client\":2,\"currency\":\"USD\",\"available\":1.50,\"held\":0.5,\"total\":2.00,\"locked\":true}\n\
+            ]\n"
+        );
+
+        let mut output = Vec::new();
+        report(
+            JsonLinesSink::new(&mut output),
+            AccountsReportMode::DefaultCurrency,
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"client\":1,\"available\":1.50,\"held\":0.5,\"total\":2.00,\"locked\":false}\n\
+            {\"client\":2,\"available\":1.50,\"held\":0.5,\"total\":2.00,\"locked\":true}\n"
+        );
+
+        // An empty report is still valid JSON
+        let mut output = Vec::new();
+        let mut sink = JsonSink::new(&mut output);
+        sink.start(AccountsReportMode::DefaultCurrency).await?;
+        sink.finish().await?;
+        assert_eq!(String::from_utf8(output).unwrap(), "[\n]\n");
+
+        assert_eq!(OutputFormat::from_path("accounts.tsv"), OutputFormat::Tsv);
+        assert_eq!(OutputFormat::from_path("accounts"), OutputFormat::Csv);
+        assert_eq!(
+            OutputFormat::from_path("accounts.parquet"),
+            OutputFormat::Parquet
+        );
+
+        Ok(())
+    }
+}
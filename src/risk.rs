@@ -0,0 +1,358 @@
+/// Risk rules loaded from a local CSV file, checked by the account worker before a deposit, a
+/// withdrawal, a conversion or a dispute is applied.
+/// Each row is a rule `rule,action,limit,count,window`:
+/// - `max_amount`: a deposit, withdrawal or conversion above `limit`.
+/// - `withdrawal_velocity`: more than `count` withdrawals in `window` seconds.
+/// - `daily_withdrawal_total`: withdrawals in a currency above `limit` in total in `window`
+///   seconds, a day by default.
+/// - `withdrawal_after_deposit`: a withdrawal less than `window` seconds after a deposit of at
+///   least `limit` in the same currency.
+/// - `repeated_disputes`: more than `count` disputes in `window` seconds.
+///
+/// `action` is `reject` to refuse the operation or `flag` to apply it and raise an alert, only
+/// kept if the operation succeeds.
+/// Windows are counted on the input timestamps, rows without timestamp escape windowed rules.
+/// Amounts are compared in the currency of the transaction, operations in other currencies are
+/// left out of totals and of deposits preceding a withdrawal.
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    account::AccountId, csv::read_records, currency::Currency, engine::SECONDS_PER_DAY,
+    errors::Result, tasks::command::PaymentEngineCommand, transaction::TransactionId,
+};
+
+/// Alerts kept by an account, the oldest are dropped past it.
+pub const MAX_ALERTS_PER_ACCOUNT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskRuleKind {
+    MaxAmount,
+    WithdrawalVelocity,
+    DailyWithdrawalTotal,
+    WithdrawalAfterDeposit,
+    RepeatedDisputes,
+}
+
+impl Display for RiskRuleKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            RiskRuleKind::MaxAmount => write!(f, "max_amount"),
+            RiskRuleKind::WithdrawalVelocity => write!(f, "withdrawal_velocity"),
+            RiskRuleKind::DailyWithdrawalTotal => write!(f, "daily_withdrawal_total"),
+            RiskRuleKind::WithdrawalAfterDeposit => write!(f, "withdrawal_after_deposit"),
+            RiskRuleKind::RepeatedDisputes => write!(f, "repeated_disputes"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskAction {
+    Reject,
+    Flag,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RiskRule {
+    pub rule: RiskRuleKind,
+    pub action: RiskAction,
+    #[serde(default)]
+    pub limit: Option<Decimal>,
+    #[serde(default)]
+    pub count: Option<usize>,
+    /// Seconds.
+    #[serde(default)]
+    pub window: Option<i64>,
+}
+
+impl RiskRule {
+    fn window(&self) -> i64 {
+        match self.rule {
+            RiskRuleKind::DailyWithdrawalTotal => self.window.unwrap_or(SECONDS_PER_DAY),
+            _ => self.window.unwrap_or(0),
+        }
+    }
+
+    fn is_broken_by(&self, history: &RiskHistory, event: &RiskEvent) -> bool {
+        let limit = self.limit.unwrap_or(Decimal::MAX);
+        let count = self.count.unwrap_or(usize::MAX);
+        let since = |at: i64| at.saturating_sub(self.window());
+        match (self.rule, event.kind) {
+            (
+                RiskRuleKind::MaxAmount,
+                RiskEventKind::Deposit | RiskEventKind::Withdrawal | RiskEventKind::Conversion,
+            ) => event.amount > limit,
+            (RiskRuleKind::WithdrawalVelocity, RiskEventKind::Withdrawal) => event
+                .at
+                .is_some_and(|at| history.withdrawals_since(since(at)).count() + 1 > count),
+            (RiskRuleKind::DailyWithdrawalTotal, RiskEventKind::Withdrawal) => {
+                event.at.is_some_and(|at| {
+                    // A total too large to add up is over any limit
+                    history
+                        .withdrawals_since(since(at))
+                        .filter(|(currency, _)| *currency == event.currency)
+                        .try_fold(event.amount, |total, (_, amount)| total.checked_add(amount))
+                        .is_none_or(|total| total > limit)
+                })
+            }
+            (RiskRuleKind::WithdrawalAfterDeposit, RiskEventKind::Withdrawal) => {
+                event.at.is_some_and(|at| {
+                    history.deposits.iter().any(|(t, currency, amount)| {
+                        *t >= since(at) && *currency == event.currency && *amount >= limit
+                    })
+                })
+            }
+            (RiskRuleKind::RepeatedDisputes, RiskEventKind::Dispute) => {
+                event.at.is_some_and(|at| {
+                    history.disputes.iter().filter(|t| **t >= since(at)).count() + 1 > count
+                })
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RiskEventKind {
+    Deposit,
+    Withdrawal,
+    /// Only checked against the amount, conversions don't move funds out of the account.
+    Conversion,
+    Dispute,
+}
+
+/// An operation about to be applied to an account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskEvent {
+    pub kind: RiskEventKind,
+    pub currency: Currency,
+    pub amount: Decimal,
+    pub at: Option<i64>,
+}
+
+/// Timestamped operations of an account, only kept as long as a rule window needs them.
+#[derive(Debug, Clone, Default)]
+pub struct RiskHistory {
+    deposits: VecDeque<(i64, Currency, Decimal)>,
+    withdrawals: VecDeque<(i64, Currency, Decimal)>,
+    disputes: VecDeque<i64>,
+}
+
+impl RiskHistory {
+    fn withdrawals_since(&self, since: i64) -> impl Iterator<Item = (Currency, Decimal)> + '_ {
+        self.withdrawals
+            .iter()
+            .filter(move |(t, _, _)| *t >= since)
+            .map(|(_, currency, amount)| (*currency, *amount))
+    }
+
+    /// Remember an applied operation and forget what is older than `window` seconds.
+    pub fn record(&mut self, event: &RiskEvent, window: i64) {
+        let Some(at) = event.at else {
+            return;
+        };
+        match event.kind {
+            RiskEventKind::Deposit => self.deposits.push_back((at, event.currency, event.amount)),
+            RiskEventKind::Withdrawal => {
+                self.withdrawals
+                    .push_back((at, event.currency, event.amount))
+            }
+            RiskEventKind::Dispute => self.disputes.push_back(at),
+            RiskEventKind::Conversion => {}
+        }
+
+        let since = at.saturating_sub(window);
+        self.deposits.retain(|(t, _, _)| *t >= since);
+        self.withdrawals.retain(|(t, _, _)| *t >= since);
+        self.disputes.retain(|t| *t >= since);
+    }
+}
+
+/// A rule broken by an operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskAlert {
+    pub account_id: AccountId,
+    pub tx_id: TransactionId,
+    pub rule: RiskRuleKind,
+    pub action: RiskAction,
+}
+
+impl Display for RiskAlert {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let action = match self.action {
+            RiskAction::Reject => "reject",
+            RiskAction::Flag => "flag",
+        };
+        writeln!(
+            f,
+            "{},{},{},{}",
+            self.account_id, self.tx_id, self.rule, action
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RiskRules {
+    rules: Vec<RiskRule>,
+}
+
+impl RiskRules {
+    pub fn new(rules: Vec<RiskRule>) -> Self {
+        Self { rules }
+    }
+
+    pub async fn from_csv<R: AsyncRead + Unpin + Send>(reader: R) -> Result<Self> {
+        Ok(Self::new(read_records(reader).await?))
+    }
+
+    /// Longest window of the rules, the history doesn't need to go further back.
+    pub fn window(&self) -> i64 {
+        self.rules.iter().map(RiskRule::window).max().unwrap_or(0)
+    }
+
+    /// Rules broken by an operation, rejections first.
+    pub fn check(&self, history: &RiskHistory, event: &RiskEvent) -> Vec<&RiskRule> {
+        let mut broken: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|r| r.is_broken_by(history, event))
+            .collect();
+        broken.sort_by_key(|r| r.action != RiskAction::Reject);
+        broken
+    }
+}
+
+pub const ALERTS_HEADER: &str = "client,tx,rule,action\n";
+
+/// Write the alerts raised by every account.
+pub async fn send_alerts_csv<T: AsyncWrite + Unpin>(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    mut output: T,
+) -> Result<()> {
+    let (alerts_sender, mut alerts_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendRiskAlerts(alerts_sender))
+        .await?;
+
+    output.write_all(ALERTS_HEADER.as_bytes()).await?;
+    while let Some(alert) = alerts_receiver.recv().await {
+        output.write_all(alert.to_string().as_bytes()).await?;
+    }
+    output.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn risk_rules_from_csv() -> Result<()> {
+        let data = b"\
+rule,action,limit,count,window
+max_amount,reject,1000,,
+withdrawal_velocity,flag,,2,60
+daily_withdrawal_total,reject,500,,
+withdrawal_after_deposit,flag,100,,600
+repeated_disputes,reject,,1,3600
+"
+        .as_slice();
+        let rules = RiskRules::from_csv(data).await?;
+        assert_eq!(rules.window(), SECONDS_PER_DAY);
+
+        let event = |kind, amount, at| RiskEvent {
+            kind,
+            currency: Currency::default(),
+            amount,
+            at: Some(at),
+        };
+        let broken = |history: &RiskHistory, event: &RiskEvent| {
+            rules
+                .check(history, event)
+                .iter()
+                .map(|r| r.rule)
+                .collect::<Vec<_>>()
+        };
+        let mut history = RiskHistory::default();
+
+        let deposit = event(RiskEventKind::Deposit, dec!(2000), 0);
+        assert_eq!(broken(&history, &deposit), vec![RiskRuleKind::MaxAmount]);
+        history.record(&event(RiskEventKind::Deposit, dec!(200), 0), rules.window());
+
+        let withdrawal = event(RiskEventKind::Withdrawal, dec!(10), 10);
+        assert_eq!(
+            broken(&history, &withdrawal),
+            vec![RiskRuleKind::WithdrawalAfterDeposit]
+        );
+        history.record(&withdrawal, rules.window());
+        history.record(
+            &event(RiskEventKind::Withdrawal, dec!(10), 20),
+            rules.window(),
+        );
+        assert_eq!(
+            broken(&history, &event(RiskEventKind::Withdrawal, dec!(10), 30)),
+            vec![
+                RiskRuleKind::WithdrawalVelocity,
+                RiskRuleKind::WithdrawalAfterDeposit
+            ]
+        );
+        assert_eq!(
+            broken(&history, &event(RiskEventKind::Withdrawal, dec!(490), 1000)),
+            vec![RiskRuleKind::DailyWithdrawalTotal]
+        );
+        // Operations in other currencies neither add up nor precede the withdrawal
+        let eur = |amount, at| RiskEvent {
+            currency: "EUR".parse().unwrap(),
+            ..event(RiskEventKind::Withdrawal, amount, at)
+        };
+        assert!(broken(&history, &eur(dec!(490), 1000)).is_empty());
+        assert!(broken(&history, &eur(dec!(1), 500)).is_empty());
+        // Rows without timestamp escape windowed rules
+        let untimed = RiskEvent {
+            at: None,
+            ..event(RiskEventKind::Withdrawal, dec!(490), 0)
+        };
+        assert!(broken(&history, &untimed).is_empty());
+
+        assert_eq!(
+            broken(
+                &history,
+                &event(RiskEventKind::Conversion, dec!(2000), 1000)
+            ),
+            vec![RiskRuleKind::MaxAmount]
+        );
+        // Withdrawals adding up past the largest amount break the total
+        let mut large = RiskHistory::default();
+        large.record(
+            &event(RiskEventKind::Withdrawal, Decimal::MAX, 0),
+            rules.window(),
+        );
+        assert_eq!(
+            broken(&large, &event(RiskEventKind::Withdrawal, Decimal::MAX, 10)),
+            vec![RiskRuleKind::MaxAmount, RiskRuleKind::DailyWithdrawalTotal]
+        );
+
+        let dispute = event(RiskEventKind::Dispute, dec!(10), 100);
+        assert!(broken(&history, &dispute).is_empty());
+        history.record(&dispute, rules.window());
+        assert_eq!(
+            broken(&history, &event(RiskEventKind::Dispute, dec!(10), 200)),
+            vec![RiskRuleKind::RepeatedDisputes]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+/// Summary of a run: what was read from the input, what the accounts did with it and how long it
+/// took. Rows rejected before reaching the engine are counted by rejection code, operations
+/// refused by the engine or the accounts by `AccountOperationError` variant.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    currency::Currency,
+    errors::{AccountOperationError, PaymentEngineError, Result},
+    report::JsonDecimal,
+    tasks::{command::PaymentEngineCommand, producer::ReadStats},
+};
+
+/// Figures of the operations of the engine, or of a single account, that can be added up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperationStats {
+    /// Transactions and dispute commands applied.
+    pub applied: usize,
+    /// Transactions and dispute commands refused, by error variant.
+    pub rejected: BTreeMap<&'static str, usize>,
+    /// Accounts with at least one applied command.
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub deposited: BTreeMap<Currency, Decimal>,
+    pub withdrawn: BTreeMap<Currency, Decimal>,
+    /// Transfers counted once, by their source.
+    pub transferred: BTreeMap<Currency, Decimal>,
+    /// Conversions by source currency.
+    pub converted: BTreeMap<Currency, Decimal>,
+    /// Fees taken from the accounts, chargeback fees included.
+    pub fees: BTreeMap<Currency, Decimal>,
+    /// Funds held at the end of the run.
+    pub held: BTreeMap<Currency, Decimal>,
+    pub charged_back: BTreeMap<Currency, Decimal>,
+}
+
+fn add_amounts(into: &mut BTreeMap<Currency, Decimal>, from: BTreeMap<Currency, Decimal>) {
+    for (currency, amount) in from {
+        *into.entry(currency).or_default() += amount;
+    }
+}
+
+impl OperationStats {
+    pub fn reject(&mut self, e: &AccountOperationError) {
+        *self.rejected.entry(e.name()).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: OperationStats) {
+        self.applied += other.applied;
+        for (name, count) in other.rejected {
+            *self.rejected.entry(name).or_default() += count;
+        }
+        self.accounts += other.accounts;
+        self.locked_accounts += other.locked_accounts;
+        add_amounts(&mut self.deposited, other.deposited);
+        add_amounts(&mut self.withdrawn, other.withdrawn);
+        add_amounts(&mut self.transferred, other.transferred);
+        add_amounts(&mut self.converted, other.converted);
+        add_amounts(&mut self.fees, other.fees);
+        add_amounts(&mut self.held, other.held);
+        add_amounts(&mut self.charged_back, other.charged_back);
+    }
+}
+
+/// Add up the figures of the engine and of every account.
+pub async fn collect_operation_stats(
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+) -> Result<OperationStats> {
+    let (stats_sender, mut stats_receiver) = mpsc::channel(12);
+    engine_sender
+        .send(PaymentEngineCommand::SendRunStats(stats_sender))
+        .await?;
+
+    let mut stats = OperationStats::default();
+    while let Some(account_stats) = stats_receiver.recv().await {
+        stats.merge(account_stats);
+    }
+    Ok(stats)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunStats {
+    pub rows: usize,
+    pub rows_per_type: BTreeMap<&'static str, usize>,
+    /// Rows rejected before reaching the engine, by rejection code.
+    pub invalid: BTreeMap<&'static str, usize>,
+    pub operations: OperationStats,
+    pub elapsed: Duration,
+}
+
+impl RunStats {
+    pub fn new(read: &ReadStats, operations: OperationStats, elapsed: Duration) -> Self {
+        let mut invalid = BTreeMap::new();
+        for rejection in read.rejections.iter() {
+            *invalid.entry(rejection.code).or_default() += 1;
+        }
+        Self {
+            rows: read.rows,
+            rows_per_type: read.rows_per_type.clone(),
+            invalid,
+            operations,
+            elapsed,
+        }
+    }
+
+    /// Rows read per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.rows as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        // Exact decimal numbers, like the JSON accounts report
+        let amounts = |amounts: &BTreeMap<Currency, Decimal>| {
+            amounts
+                .iter()
+                .map(|(currency, amount)| (currency.to_string(), JsonDecimal(amount.normalize())))
+                .collect()
+        };
+        let ops = &self.operations;
+        let object = RunStatsObject {
+            rows: self.rows,
+            rows_per_type: &self.rows_per_type,
+            invalid: &self.invalid,
+            applied: ops.applied,
+            rejected: &ops.rejected,
+            accounts: ops.accounts,
+            locked_accounts: ops.locked_accounts,
+            deposited: amounts(&ops.deposited),
+            withdrawn: amounts(&ops.withdrawn),
+            transferred: amounts(&ops.transferred),
+            converted: amounts(&ops.converted),
+            fees: amounts(&ops.fees),
+            held: amounts(&ops.held),
+            charged_back: amounts(&ops.charged_back),
+            elapsed_secs: self.elapsed.as_secs_f64(),
+            rows_per_sec: self.throughput(),
+        };
+        serde_json::to_string_pretty(&object)
+            .map_err(|e| PaymentEngineError::InputOutpoutError(e.to_string()))
+    }
+
+    pub async fn write_json<T: AsyncWrite + Unpin>(&self, mut output: T) -> Result<()> {
+        output
+            .write_all(format!("{}\n", self.to_json()?).as_bytes())
+            .await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+/// The run summary in JSON.
+#[derive(Serialize)]
+struct RunStatsObject<'a> {
+    rows: usize,
+    rows_per_type: &'a BTreeMap<&'static str, usize>,
+    invalid: &'a BTreeMap<&'static str, usize>,
+    applied: usize,
+    rejected: &'a BTreeMap<&'static str, usize>,
+    accounts: usize,
+    locked_accounts: usize,
+    deposited: BTreeMap<String, JsonDecimal>,
+    withdrawn: BTreeMap<String, JsonDecimal>,
+    transferred: BTreeMap<String, JsonDecimal>,
+    converted: BTreeMap<String, JsonDecimal>,
+    fees: BTreeMap<String, JsonDecimal>,
+    held: BTreeMap<String, JsonDecimal>,
+    charged_back: BTreeMap<String, JsonDecimal>,
+    elapsed_secs: f64,
+    rows_per_sec: f64,
+}
+
+fn write_counts<K: Display>(
+    f: &mut Formatter,
+    title: &str,
+    counts: impl Iterator<Item = (K, impl Display)>,
+) -> fmt::Result {
+    let counts: Vec<_> = counts.map(|(k, v)| format!("{} {}", k, v)).collect();
+    if counts.is_empty() {
+        writeln!(f, "{}: -", title)
+    } else {
+        writeln!(f, "{}: {}", title, counts.join(", "))
+    }
+}
+
+impl Display for RunStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let ops = &self.operations;
+        writeln!(f, "rows: {}", self.rows)?;
+        write_counts(f, "rows per type", self.rows_per_type.iter())?;
+        write_counts(f, "invalid", self.invalid.iter())?;
+        writeln!(f, "applied: {}", ops.applied)?;
+        write_counts(f, "rejected", ops.rejected.iter())?;
+        writeln!(
+            f,
+            "accounts: {} ({} locked)",
+            ops.accounts, ops.locked_accounts
+        )?;
+        write_counts(f, "deposited", ops.deposited.iter())?;
+        write_counts(f, "withdrawn", ops.withdrawn.iter())?;
+        write_counts(f, "transferred", ops.transferred.iter())?;
+        write_counts(f, "converted", ops.converted.iter())?;
+        write_counts(f, "fees", ops.fees.iter())?;
+        write_counts(f, "held", ops.held.iter())?;
+        write_counts(f, "charged back", ops.charged_back.iter())?;
+        writeln!(
+            f,
+            "elapsed: {:.3}s ({:.0} rows/s)",
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )
+    }
+}
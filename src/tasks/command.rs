@@ -1,37 +1,260 @@
 /// The main role of having sub-command is just to a clear split of action foreach transaction type.
 /// For example, when we encounter a dispute, we can open/cancel/chargeback.
+use std::fmt::{self, Debug, Formatter};
+
+use rust_decimal::Decimal;
 use tokio::sync::mpsc;
 
-use crate::transaction::{Dispute, Transaction, TransactionKind};
+use crate::{
+    account::WalletBalance,
+    analytics::DisputeStats,
+    errors::{AccountOperationError, AccountOperationResult, PaymentEngineError, Result},
+    export::LedgerEntry,
+    reconcile::WalletStatement,
+    report::AccountsReportMode,
+    risk::RiskAlert,
+    stats::OperationStats,
+    transaction::{Dispute, DisputeStatus, Transaction, TransactionId, TransactionKind},
+};
 
 #[derive(Debug, Clone)]
 pub enum PaymentEngineCommand {
     TransactionCommand(TransactionCommandData),
     DisputeCommand(DisputeCommandData),
-    SendAccountsToCSV(mpsc::Sender<String>),
+    /// Seed a wallet before processing transactions.
+    OpenBalance(WalletBalance),
+    /// A wallet that can't be reported, e.g. without FX rate to the base currency, is sent as an
+    /// error.
+    SendAccounts(
+        mpsc::Sender<AccountOperationResult<WalletBalance>>,
+        AccountsReportMode,
+    ),
+    SendDisputeStats(mpsc::Sender<DisputeStats>),
+    SendRiskAlerts(mpsc::Sender<RiskAlert>),
+    SendWalletStatements(mpsc::Sender<WalletStatement>),
+    SendLedger(mpsc::Sender<LedgerEntry>),
+    SendRunStats(mpsc::Sender<OperationStats>),
+}
+
+impl PaymentEngineCommand {
+    /// Ask for the outcome of this command to be sent back on `reply`.
+    /// Commands without a reply channel are fire-and-forget.
+    pub fn with_reply(self, reply: ReplySender) -> Self {
+        match self {
+            Self::TransactionCommand(data) => Self::TransactionCommand(TransactionCommandData {
+                reply: Some(reply),
+                ..data
+            }),
+            Self::DisputeCommand(data) => Self::DisputeCommand(DisputeCommandData {
+                reply: Some(reply),
+                ..data
+            }),
+            cmd @ (Self::OpenBalance(_)
+            | Self::SendAccounts(..)
+            | Self::SendDisputeStats(_)
+            | Self::SendRiskAlerts(_)
+            | Self::SendWalletStatements(_)
+            | Self::SendLedger(_)
+            | Self::SendRunStats(_)) => cmd,
+        }
+    }
+
+    /// Stamp the command with the time of its input row.
+    pub fn with_timestamp(self, timestamp: Option<i64>) -> Self {
+        match self {
+            Self::TransactionCommand(data) => Self::TransactionCommand(TransactionCommandData {
+                tx: data.tx.with_timestamp(timestamp),
+                ..data
+            }),
+            Self::DisputeCommand(data) => Self::DisputeCommand(DisputeCommandData {
+                dispute: data.dispute.with_timestamp(timestamp),
+                ..data
+            }),
+            cmd @ (Self::OpenBalance(_)
+            | Self::SendAccounts(..)
+            | Self::SendDisputeStats(_)
+            | Self::SendRiskAlerts(_)
+            | Self::SendWalletStatements(_)
+            | Self::SendLedger(_)
+            | Self::SendRunStats(_)) => cmd,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<i64> {
+        match self {
+            Self::TransactionCommand(data) => data.tx.timestamp(),
+            Self::DisputeCommand(data) => data.dispute.timestamp(),
+            Self::OpenBalance(_)
+            | Self::SendAccounts(..)
+            | Self::SendDisputeStats(_)
+            | Self::SendRiskAlerts(_)
+            | Self::SendWalletStatements(_)
+            | Self::SendLedger(_)
+            | Self::SendRunStats(_) => None,
+        }
+    }
+
+    /// Name of the command for the metrics, the action for transactions and disputes.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TransactionCommand(data) => match data.action {
+                TransactionCommandAction::Deposit => "deposit",
+                TransactionCommandAction::Withdraw => "withdrawal",
+                TransactionCommandAction::Convert => "conversion",
+                TransactionCommandAction::Transfer => "transfer",
+                TransactionCommandAction::TransferDebit => "transfer_debit",
+                TransactionCommandAction::TransferCredit => "transfer_credit",
+                TransactionCommandAction::TransferRollback => "transfer_rollback",
+                TransactionCommandAction::TransferRefund => "transfer_refund",
+            },
+            Self::DisputeCommand(data) => match data.action {
+                DisputeCommandAction::OpenDispute => "dispute",
+                DisputeCommandAction::CancelDispute => "resolve",
+                DisputeCommandAction::ChargebackDispute => "chargeback",
+                DisputeCommandAction::RepresentDispute => "representment",
+                DisputeCommandAction::PreArbitrateDispute => "prearbitration",
+                DisputeCommandAction::ArbitrateDispute => "arbitration",
+                DisputeCommandAction::ExpireDispute => "expire_dispute",
+            },
+            Self::OpenBalance(_) => "open_balance",
+            Self::SendAccounts(..) => "send_accounts",
+            Self::SendDisputeStats(_) => "send_dispute_stats",
+            Self::SendRiskAlerts(_) => "send_risk_alerts",
+            Self::SendWalletStatements(_) => "send_wallet_statements",
+            Self::SendLedger(_) => "send_ledger",
+            Self::SendRunStats(_) => "send_run_stats",
+        }
+    }
+
+    pub fn reply(&self) -> Option<&ReplySender> {
+        match self {
+            Self::TransactionCommand(data) => data.reply.as_ref(),
+            Self::DisputeCommand(data) => data.reply.as_ref(),
+            Self::OpenBalance(_)
+            | Self::SendAccounts(..)
+            | Self::SendDisputeStats(_)
+            | Self::SendRiskAlerts(_)
+            | Self::SendWalletStatements(_)
+            | Self::SendLedger(_)
+            | Self::SendRunStats(_) => None,
+        }
+    }
+}
+
+/// What a command did to an account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandOutcome {
+    TransactionApplied(TransactionId),
+    /// The status of the disputes of a transaction and the amount held or released by the command.
+    DisputeUpdated(TransactionId, DisputeStatus, Decimal),
+}
+
+pub type CommandReply = std::result::Result<CommandOutcome, AccountOperationError>;
+
+/// Channel used to acknowledge a command.
+/// Two senders are equal when they target the same channel, so commands can still be compared.
+#[derive(Clone)]
+pub struct ReplySender(mpsc::Sender<CommandReply>);
+
+impl ReplySender {
+    pub fn new(sender: mpsc::Sender<CommandReply>) -> Self {
+        Self(sender)
+    }
+
+    /// Send the reply, a caller that stopped listening is not an error for the engine.
+    pub async fn send(&self, reply: CommandReply) {
+        if self.0.send(reply).await.is_err() {
+            log::debug!("Command reply dropped: receiver closed");
+        }
+    }
+}
+
+impl Debug for ReplySender {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "ReplySender")
+    }
+}
+
+impl PartialEq for ReplySender {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.same_channel(&other.0)
+    }
+}
+
+/// Send a command to the engine and wait until it has been applied or rejected.
+pub async fn send_and_wait(
+    engine_sender: &mpsc::Sender<PaymentEngineCommand>,
+    cmd: PaymentEngineCommand,
+) -> Result<CommandReply> {
+    let (sender, mut receiver) = mpsc::channel(1);
+    engine_sender
+        .send(cmd.with_reply(ReplySender::new(sender)))
+        .await?;
+
+    receiver.recv().await.ok_or_else(|| {
+        PaymentEngineError::TokioMpscError(String::from("Command dropped without reply"))
+    })
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TransactionCommandData {
     pub action: TransactionCommandAction,
     pub tx: Transaction,
+    pub reply: Option<ReplySender>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TransactionCommandAction {
     Deposit,
     Withdraw,
+    Convert,
+    /// Transfer request, split by the engine into the legs below.
+    Transfer,
+    TransferDebit,
+    TransferCredit,
+    /// Undo the debit of a transfer whose credit failed.
+    TransferRollback,
+    /// Give the funds back to the source after the transfer has been charged back.
+    TransferRefund,
+}
+
+impl TransactionCommandAction {
+    pub fn is_transfer(&self) -> bool {
+        matches!(
+            self,
+            TransactionCommandAction::Transfer
+                | TransactionCommandAction::TransferDebit
+                | TransactionCommandAction::TransferCredit
+                | TransactionCommandAction::TransferRollback
+                | TransactionCommandAction::TransferRefund
+        )
+    }
+}
+
+impl TransactionCommandData {
+    pub fn new(action: TransactionCommandAction, tx: Transaction) -> Self {
+        Self {
+            action,
+            tx,
+            reply: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct DisputeCommandData {
     pub action: DisputeCommandAction,
     pub dispute: Dispute,
+    pub reply: Option<ReplySender>,
 }
 
 impl DisputeCommandData {
     pub fn new(action: DisputeCommandAction, dispute: Dispute) -> Self {
-        Self { action, dispute }
+        Self {
+            action,
+            dispute,
+            reply: None,
+        }
     }
 }
 
@@ -40,6 +263,15 @@ pub enum DisputeCommandAction {
     OpenDispute,
     CancelDispute,
     ChargebackDispute,
+    /// The client contests a chargeback.
+    RepresentDispute,
+    /// The representment is rejected.
+    PreArbitrateDispute,
+    /// The scheme is asked to rule.
+    ArbitrateDispute,
+    /// Resolve with the default resolution the dispute opened at the dispute timestamp, if it is
+    /// still open. Sent by the engine once the deadline has passed.
+    ExpireDispute,
 }
 
 impl From<Transaction> for TransactionCommandData {
@@ -47,10 +279,24 @@ impl From<Transaction> for TransactionCommandData {
         let action = match transaction.kind() {
             TransactionKind::Deposit => TransactionCommandAction::Deposit,
             TransactionKind::Withdrawal => TransactionCommandAction::Withdraw,
+            TransactionKind::Conversion => TransactionCommandAction::Convert,
+            TransactionKind::Transfer => TransactionCommandAction::Transfer,
         };
-        Self {
-            action,
-            tx: transaction,
+        Self::new(action, transaction)
+    }
+}
+
+/// What an account worker did with a transaction, reported back to the engine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionOutcome {
+    Applied(TransactionId),
+    Rejected(TransactionId),
+}
+
+impl TransactionOutcome {
+    pub fn tx_id(&self) -> TransactionId {
+        match *self {
+            TransactionOutcome::Applied(id) | TransactionOutcome::Rejected(id) => id,
         }
     }
 }
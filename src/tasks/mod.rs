@@ -1,3 +1,4 @@
 pub mod command;
 pub mod producer;
+pub mod source;
 pub mod worker;
@@ -1,54 +1,300 @@
-use tokio::{io::AsyncRead, sync::mpsc};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Display, Formatter},
+};
 
-use crate::{csv::TransactionRecord, errors::Result};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{self, error::TryRecvError},
+};
 
-use super::command::PaymentEngineCommand;
+use crate::{
+    currency::Currency,
+    errors::{PaymentEngineError, Result},
+    validation::RecordValidator,
+};
 
-pub struct TransactionProducer<R: AsyncRead + Unpin + Send> {
-    reader: R,
-    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+use super::{
+    command::{CommandReply, PaymentEngineCommand, ReplySender},
+    source::{RecordPosition, TransactionSource},
+};
+
+pub const REJECTIONS_HEADER: &str = "line,code,error\n";
+
+/// An input record rejected by the reader or the engine, as written in the rejection report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub position: RecordPosition,
+    /// `invalid_record` for unreadable rows, `invalid_command` for rows that can't make a
+    /// command, the validation error code or the code of the error the engine, or the dry run
+    /// checks, refused the row with.
+    pub code: &'static str,
+    pub reason: String,
 }
 
-impl<R: AsyncRead + Unpin + Send> TransactionProducer<R> {
-    pub fn new(reader: R, engine_sender: mpsc::Sender<PaymentEngineCommand>) -> Self {
+impl Rejection {
+    pub fn new(position: RecordPosition, error: &PaymentEngineError) -> Self {
+        let code = match error {
+            PaymentEngineError::InvalidRecord(..) => "invalid_record",
+            PaymentEngineError::RecordValidationError(e) => e.code(),
+            PaymentEngineError::AccountProcessError(e) => e.code(),
+            _ => "invalid_command",
+        };
         Self {
-            reader,
-            engine_sender,
+            position,
+            code,
+            reason: error.to_string(),
         }
     }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{},{},\"{}\"",
+            self.position.line,
+            self.code,
+            self.reason.replace('"', "\"\"")
+        )
+    }
+}
+
+/// Write the rejection report.
+pub async fn write_rejections_csv<T: AsyncWrite + Unpin>(
+    rejections: &[Rejection],
+    mut output: T,
+) -> Result<()> {
+    output.write_all(REJECTIONS_HEADER.as_bytes()).await?;
+    for rejection in rejections {
+        output.write_all(rejection.to_string().as_bytes()).await?;
+    }
+    output.flush().await?;
+
+    Ok(())
+}
+
+/// Figures of the records read from a source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadStats {
+    /// Records read, rejected ones included.
+    pub rows: usize,
+    /// Records per `type`, unreadable records being left out.
+    pub rows_per_type: BTreeMap<&'static str, usize>,
+    /// Records rejected before reaching the engine.
+    pub rejections: Vec<Rejection>,
+    /// Records the engine rejected, only collected when the producer is asked to.
+    pub engine_rejections: Vec<Rejection>,
+}
+
+impl ReadStats {
+    /// Every rejected record in input order, for the rejection report.
+    pub fn all_rejections(&self) -> Vec<Rejection> {
+        let mut rejections: Vec<_> = self
+            .rejections
+            .iter()
+            .chain(self.engine_rejections.iter())
+            .cloned()
+            .collect();
+        rejections.sort_by_key(|r| r.position.line);
+        rejections
+    }
+}
+
+/// Turns the records of a source into engine commands, rejecting the records that can't be read,
+/// fail validation or can't make a command.
+pub struct CommandReader<S: TransactionSource> {
+    source: S,
+    default_currency: Currency,
+    validator: RecordValidator,
+    stats: ReadStats,
+}
 
-    pub async fn start(self) -> Result<()> {
-        let mut rdr = csv_async::AsyncReaderBuilder::new()
-            .trim(csv_async::Trim::All)
-            .flexible(true)
-            .create_reader(self.reader);
+impl<S: TransactionSource> CommandReader<S> {
+    /// Records without a currency column are booked in `default_currency` and records refused by
+    /// `validator` are rejected.
+    pub fn new(source: S, default_currency: Currency, validator: RecordValidator) -> Self {
+        Self {
+            source,
+            default_currency,
+            validator,
+            stats: ReadStats::default(),
+        }
+    }
 
-        let headers = rdr.byte_headers().await?.clone();
-        let mut record = csv_async::ByteRecord::new();
-        while rdr.read_byte_record(&mut record).await? {
-            let tx_record: TransactionRecord = record.deserialize(Some(&headers))?;
-            match tx_record.clone().try_into() {
-                Ok(cmd) => self.engine_sender.send(cmd).await?,
+    /// Next command, rejected records being skipped. `None` once the source is exhausted. Fails on
+    /// the errors of the source that aren't tied to a record.
+    pub async fn next_command(&mut self) -> Result<Option<(RecordPosition, PaymentEngineCommand)>> {
+        while let Some(record) = self.source.next_record().await {
+            self.stats.rows += 1;
+            let (position, tx_record) = match record {
+                Ok(record) => record,
+                Err(PaymentEngineError::InvalidRecord(line, reason)) => {
+                    // Do not abort on malformed records
+                    let e = PaymentEngineError::InvalidRecord(line, reason);
+                    log::error!("Failed to read record: {}", e);
+                    self.stats
+                        .rejections
+                        .push(Rejection::new(RecordPosition { line }, &e));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            *self
+                .stats
+                .rows_per_type
+                .entry(tx_record.type_.name())
+                .or_default() += 1;
+            let command = self
+                .validator
+                .validate(&tx_record)
+                .map_err(PaymentEngineError::from)
+                .and_then(|_| tx_record.clone().into_command(self.default_currency));
+            match command {
+                Ok(cmd) => return Ok(Some((position, cmd))),
                 Err(e) => {
-                    // Do not abort producer on parsing errors
-                    log::error!("Failed to process record {:?}: {}", tx_record, e);
+                    // Do not abort on parsing errors
+                    log::error!(
+                        "Failed to process record {:?} at {}: {}",
+                        tx_record,
+                        position,
+                        e
+                    );
+                    self.stats.rejections.push(Rejection::new(position, &e));
                 }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn into_stats(self) -> ReadStats {
+        self.stats
+    }
+}
+
+pub struct TransactionProducer<S: TransactionSource> {
+    reader: CommandReader<S>,
+    engine_sender: mpsc::Sender<PaymentEngineCommand>,
+    /// Replies of the engine still expected, in input order, when collecting its rejections.
+    pending_replies: Option<VecDeque<(RecordPosition, mpsc::Receiver<CommandReply>)>>,
+    engine_rejections: Vec<Rejection>,
+}
+
+impl<S: TransactionSource> TransactionProducer<S> {
+    pub fn new(source: S, engine_sender: mpsc::Sender<PaymentEngineCommand>) -> Self {
+        Self::new_with_default_currency(source, engine_sender, Currency::default())
+    }
+
+    /// Records without a currency column are booked in `default_currency`.
+    pub fn new_with_default_currency(
+        source: S,
+        engine_sender: mpsc::Sender<PaymentEngineCommand>,
+        default_currency: Currency,
+    ) -> Self {
+        Self::new_with_validator(
+            source,
+            engine_sender,
+            default_currency,
+            RecordValidator::default(),
+        )
+    }
+
+    /// Records refused by `validator` are rejected before reaching the engine.
+    pub fn new_with_validator(
+        source: S,
+        engine_sender: mpsc::Sender<PaymentEngineCommand>,
+        default_currency: Currency,
+        validator: RecordValidator,
+    ) -> Self {
+        Self {
+            reader: CommandReader::new(source, default_currency, validator),
+            engine_sender,
+            pending_replies: None,
+            engine_rejections: Vec::new(),
+        }
+    }
+
+    /// Ask the engine for the outcome of every command, to report the records it rejects with
+    /// their position. `start` then returns once the engine has replied to all of them.
+    pub fn with_engine_rejections(self) -> Self {
+        Self {
+            pending_replies: Some(VecDeque::new()),
+            ..self
+        }
+    }
+
+    /// Send every record of the source to the engine and return the figures of the input, with
+    /// the records rejected before reaching the engine and, if asked, by the engine. Stops at the
+    /// first error of the source that isn't tied to a record.
+    pub async fn start(mut self) -> Result<ReadStats> {
+        while let Some((position, cmd)) = self.reader.next_command().await? {
+            let Some(pending_replies) = self.pending_replies.as_mut() else {
+                self.engine_sender.send(cmd).await?;
+                continue;
             };
+            let (reply_sender, reply_receiver) = mpsc::channel(1);
+            self.engine_sender
+                .send(cmd.with_reply(ReplySender::new(reply_sender)))
+                .await?;
+            pending_replies.push_back((position, reply_receiver));
+            self.collect_replies();
         }
 
-        Ok(())
+        let pending_replies = self.pending_replies.take().unwrap_or_default();
+        for (position, mut receiver) in pending_replies {
+            if let Some(reply) = receiver.recv().await {
+                self.record_reply(position, reply);
+            }
+        }
+
+        let mut stats = self.reader.into_stats();
+        stats.engine_rejections = self.engine_rejections;
+        Ok(stats)
+    }
+
+    /// Record the replies already sent back, in input order, without waiting.
+    fn collect_replies(&mut self) {
+        while let Some(pending_replies) = self.pending_replies.as_mut() {
+            let Some((_, receiver)) = pending_replies.front_mut() else {
+                return;
+            };
+            let reply = match receiver.try_recv() {
+                Ok(reply) => Some(reply),
+                Err(TryRecvError::Empty) => return,
+                // Dropped without reply, the engine logged why
+                Err(TryRecvError::Disconnected) => None,
+            };
+            let Some((position, _)) = pending_replies.pop_front() else {
+                return;
+            };
+            if let Some(reply) = reply {
+                self.record_reply(position, reply);
+            }
+        }
+    }
+
+    fn record_reply(&mut self, position: RecordPosition, reply: CommandReply) {
+        if let Err(e) = reply {
+            self.engine_rejections
+                .push(Rejection::new(position, &PaymentEngineError::from(e)));
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::transaction::{Transaction, TransactionKind};
+    use crate::{
+        csv::{TransactionRecord, TransactionRecordType},
+        tasks::source::{CsvSource, JsonLinesSource, MemorySource},
+        transaction::{Transaction, TransactionKind},
+    };
 
     use super::*;
 
     use rust_decimal_macros::dec;
 
     #[tokio::test]
+    #[allow(clippy::unnecessary_fallible_conversions)]
     async fn test_deserialize_csv() -> Result<()> {
         let tests = vec![
             b"\
@@ -67,7 +313,7 @@ deposit,         1,     1,  1.664
             Transaction::new(TransactionKind::Deposit, 1, 1, dec!(1.664)).try_into()?;
         for data in tests.into_iter() {
             let (sender, mut receiver) = mpsc::channel(1);
-            let producer = TransactionProducer::new(data, sender);
+            let producer = TransactionProducer::new(CsvSource::new(data), sender);
 
             producer.start().await?;
 
@@ -82,4 +328,167 @@ deposit,         1,     1,  1.664
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_deserialize_csv_currency() -> Result<()> {
+        let data = b"\
+type,client,tx,amount,currency
+deposit,1,1,1.664,eur
+deposit,1,2,2,
+"
+        .as_slice();
+
+        let (sender, mut receiver) = mpsc::channel(2);
+        let usd: Currency = "USD".parse()?;
+        let producer =
+            TransactionProducer::new_with_default_currency(CsvSource::new(data), sender, usd);
+        producer.start().await?;
+
+        for expected in ["EUR".parse()?, usd] {
+            match receiver.recv().await.expect("cmd has not been received") {
+                PaymentEngineCommand::TransactionCommand(tx_cmd) => {
+                    assert_eq!(tx_cmd.tx.currency(), expected)
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_csv_timestamp() -> Result<()> {
+        let data = b"\
+type,client,tx,amount,timestamp
+deposit,1,1,1.664,1700000000
+dispute,1,1,,
+"
+        .as_slice();
+
+        let (sender, mut receiver) = mpsc::channel(2);
+        let producer = TransactionProducer::new(CsvSource::new(data), sender);
+        producer.start().await?;
+
+        for expected in [Some(1700000000), None] {
+            let cmd = receiver.recv().await.expect("cmd has not been received");
+            assert_eq!(cmd.timestamp(), expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_json_lines() -> Result<()> {
+        let data =
+            br#"{"type": "deposit", "client": 1, "tx": 1, "amount": 0.1000000000000000055511}
+
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "1.5", "currency": "EUR"}
+{"type": "dispute", "client": 1, "tx": 1, "timestamp": 1700000000}
+"#
+            .as_slice();
+
+        let (sender, mut receiver) = mpsc::channel(3);
+        let producer = TransactionProducer::new(JsonLinesSource::new(data), sender);
+        producer.start().await?;
+
+        match receiver.recv().await.expect("cmd has not been received") {
+            PaymentEngineCommand::TransactionCommand(tx_cmd) => {
+                assert_eq!(tx_cmd.tx.amount(), dec!(0.1000000000000000055511))
+            }
+            _ => unreachable!(),
+        }
+        match receiver.recv().await.expect("cmd has not been received") {
+            PaymentEngineCommand::TransactionCommand(tx_cmd) => {
+                assert_eq!(tx_cmd.tx.amount(), dec!(1.5));
+                assert_eq!(tx_cmd.tx.currency(), "EUR".parse()?);
+            }
+            _ => unreachable!(),
+        }
+        let cmd = receiver.recv().await.expect("cmd has not been received");
+        assert_eq!(cmd.timestamp(), Some(1700000000));
+
+        let data =
+            b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1}\n{\"type\": 1}\n"
+                .as_slice();
+        let (sender, _receiver) = mpsc::channel(1);
+        let producer = TransactionProducer::new(JsonLinesSource::new(data), sender);
+        let stats = producer.start().await?;
+        assert_eq!(stats.rows, 2);
+        assert_eq!(stats.rejections.len(), 1);
+        assert_eq!(stats.rejections[0].position.line, 2);
+        assert_eq!(stats.rejections[0].code, "invalid_record");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_source() -> Result<()> {
+        let records = [
+            TransactionRecord {
+                currency: Some("EUR".parse()?),
+                ..TransactionRecord::new(TransactionRecordType::Deposit, 1, 1, Some(dec!(2)))
+            },
+            // Not a valid command, only logged
+            TransactionRecord::new(TransactionRecordType::Withdrawal, 1, 2, None),
+            TransactionRecord::new(TransactionRecordType::Dispute, 1, 1, None),
+        ];
+
+        let (sender, mut receiver) = mpsc::channel(3);
+        let producer = TransactionProducer::new(MemorySource::new(records), sender);
+        let stats = producer.start().await?;
+        assert_eq!(stats.rows, 3);
+        assert_eq!(stats.rows_per_type.get("withdrawal"), Some(&1));
+        assert_eq!(stats.rejections.len(), 1);
+        assert_eq!(stats.rejections[0].position.line, 2);
+
+        match receiver.recv().await.expect("cmd has not been received") {
+            PaymentEngineCommand::TransactionCommand(tx_cmd) => {
+                assert_eq!(tx_cmd.tx.currency(), "EUR".parse()?)
+            }
+            _ => unreachable!(),
+        }
+        assert!(matches!(
+            receiver.recv().await,
+            Some(PaymentEngineCommand::DisputeCommand(_))
+        ));
+        assert!(receiver.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejection_report() -> Result<()> {
+        let data = b"\
+type,client,tx,amount
+deposit,1,1,1
+refund,1,2,1
+withdrawal,1,3,
+deposit,1,4,1
+"
+        .as_slice();
+
+        let (sender, mut receiver) = mpsc::channel(2);
+        let producer = TransactionProducer::new(CsvSource::new(data), sender);
+        let rejections = producer.start().await?.rejections;
+        for tx in [1, 4] {
+            match receiver.recv().await.expect("cmd has not been received") {
+                PaymentEngineCommand::TransactionCommand(tx_cmd) => assert_eq!(tx_cmd.tx.id(), tx),
+                _ => unreachable!(),
+            }
+        }
+
+        let mut output = Vec::new();
+        write_rejections_csv(&rejections, &mut output).await?;
+        let output = String::from_utf8(output).expect("utf8");
+        let lines: Vec<_> = output
+            .lines()
+            .map(|l| l.splitn(3, ',').take(2).collect::<Vec<_>>().join(","))
+            .collect();
+        assert_eq!(
+            lines,
+            vec!["line,code", "3,invalid_record", "4,missing_amount"]
+        );
+
+        Ok(())
+    }
 }
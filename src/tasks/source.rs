@@ -0,0 +1,401 @@
+/// Sources of transaction records read by the producer.
+/// A source yields records one by one with their position so errors can point at the input; new
+/// formats or transports only need to implement `TransactionSource`.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    future::Future,
+    path::Path,
+};
+
+use rust_decimal::Decimal;
+use serde::de::Error as _;
+use serde_json::{value::RawValue, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Lines};
+
+use crate::{
+    csv::TransactionRecord,
+    errors::{PaymentEngineError, Result},
+};
+
+/// Where a record comes from in its source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordPosition {
+    /// 1-based line of the input, or index of the record for in-memory sources.
+    pub line: u64,
+}
+
+impl Display for RecordPosition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}", self.line)
+    }
+}
+
+pub type SourcedRecord = (RecordPosition, TransactionRecord);
+
+pub trait TransactionSource {
+    /// Next record, `None` once the source is exhausted. An `InvalidRecord` error only rejects
+    /// the record, any other error ends the source.
+    fn next_record(&mut self) -> impl Future<Output = Option<Result<SourcedRecord>>> + Send;
+}
+
+/// Columns of the transactions CSV with their accepted aliases, matched case insensitively.
+const CSV_COLUMNS: [(&str, &[&str]); 8] = [
+    ("type", &["kind", "transaction_type"]),
+    ("client", &["client_id", "account"]),
+    ("tx", &["tx_id", "transaction", "transaction_id"]),
+    ("amount", &["value"]),
+    ("currency", &[]),
+    ("to_currency", &[]),
+    ("to_client", &[]),
+    ("timestamp", &["time"]),
+];
+
+/// Columns every transactions CSV must have, even if some rows leave them empty.
+const REQUIRED_CSV_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Map the header row to the canonical column names, rejecting unknown, duplicated or missing
+/// columns.
+fn canonical_headers(headers: &csv_async::ByteRecord) -> Result<csv_async::ByteRecord> {
+    let mut canonical = csv_async::ByteRecord::new();
+    for header in headers.iter() {
+        let header = String::from_utf8_lossy(header).trim().to_lowercase();
+        let name = CSV_COLUMNS
+            .iter()
+            .find(|(name, aliases)| *name == header || aliases.contains(&header.as_str()))
+            .map(|(name, _)| *name)
+            .ok_or_else(|| {
+                PaymentEngineError::InvalidHeader(format!("unknown column '{}'", header))
+            })?;
+        if canonical.iter().any(|column| column == name.as_bytes()) {
+            return Err(PaymentEngineError::InvalidHeader(format!(
+                "duplicated column '{}'",
+                name
+            )));
+        }
+        canonical.push_field(name.as_bytes());
+    }
+    for name in REQUIRED_CSV_COLUMNS {
+        if !canonical.iter().any(|column| column == name.as_bytes()) {
+            return Err(PaymentEngineError::InvalidHeader(format!(
+                "missing column '{}'",
+                name
+            )));
+        }
+    }
+    Ok(canonical)
+}
+
+/// CSV with a header row, validated before the first record. Columns may come in any order under
+/// their name or an alias and trailing ones may be missing from a row. Rows that can't be
+/// deserialized are rejected with `InvalidRecord`.
+pub struct CsvSource<R: AsyncRead + Unpin + Send> {
+    reader: csv_async::AsyncReader<R>,
+    headers: Option<csv_async::ByteRecord>,
+    record: csv_async::ByteRecord,
+}
+
+impl<R: AsyncRead + Unpin + Send> CsvSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: csv_async::AsyncReaderBuilder::new()
+                .trim(csv_async::Trim::All)
+                .flexible(true)
+                .create_reader(reader),
+            headers: None,
+            record: csv_async::ByteRecord::new(),
+        }
+    }
+
+    async fn read(&mut self) -> Result<Option<SourcedRecord>> {
+        let headers = match self.headers.take() {
+            Some(headers) => headers,
+            None => canonical_headers(self.reader.byte_headers().await?)?,
+        };
+        let headers = &*self.headers.insert(headers);
+        if !self.reader.read_byte_record(&mut self.record).await? {
+            return Ok(None);
+        }
+        let line = self.record.position().map(|p| p.line()).unwrap_or_default();
+        if self.record.len() > headers.len() {
+            return Err(PaymentEngineError::InvalidRecord(
+                line,
+                format!("{} fields for {} columns", self.record.len(), headers.len()),
+            ));
+        }
+        let record = self
+            .record
+            .deserialize(Some(headers))
+            .map_err(|e| PaymentEngineError::InvalidRecord(line, e.to_string()))?;
+        Ok(Some((RecordPosition { line }, record)))
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> TransactionSource for CsvSource<R> {
+    async fn next_record(&mut self) -> Option<Result<SourcedRecord>> {
+        self.read().await.transpose()
+    }
+}
+
+/// One JSON object per line with the CSV columns as keys. Amounts may be JSON numbers or strings,
+/// numbers keep their exact decimal value. Blank lines are skipped and malformed lines are
+/// rejected with `InvalidRecord`.
+pub struct JsonLinesSource<R: AsyncRead + Unpin + Send> {
+    lines: Lines<BufReader<R>>,
+    line: u64,
+}
+
+impl<R: AsyncRead + Unpin + Send> JsonLinesSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: BufReader::new(reader).lines(),
+            line: 0,
+        }
+    }
+
+    async fn read(&mut self) -> Result<Option<SourcedRecord>> {
+        while let Some(line) = self.lines.next_line().await? {
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = parse_json_record(&line)
+                .map_err(|e| PaymentEngineError::InvalidRecord(self.line, e.to_string()))?;
+            return Ok(Some((RecordPosition { line: self.line }, record)));
+        }
+        Ok(None)
+    }
+}
+
+/// Parse a JSON object into a record. serde_json reads the numbers that don't fit an integer as
+/// floats, so they are passed on as their decimal text and amounts keep their exact value.
+fn parse_json_record(line: &str) -> serde_json::Result<TransactionRecord> {
+    let fields: BTreeMap<String, &RawValue> = serde_json::from_str(line)?;
+    let mut object = Map::new();
+    for (key, raw) in fields {
+        let text = raw.get();
+        let is_number = text.starts_with(|c: char| c == '-' || c.is_ascii_digit());
+        let is_integer = text.parse::<i64>().is_ok() || text.parse::<u64>().is_ok();
+        let value = if is_number && !is_integer {
+            Value::String(decimal_text(text)?)
+        } else {
+            serde_json::from_str(text)?
+        };
+        object.insert(key, value);
+    }
+    serde_json::from_value(Value::Object(object))
+}
+
+/// Text of a JSON number as `Decimal` parses it, exponents being expanded: `1.5e-3` is `0.0015`.
+fn decimal_text(text: &str) -> serde_json::Result<String> {
+    if !text.contains(['e', 'E']) {
+        return Ok(text.to_string());
+    }
+    Decimal::from_scientific(text)
+        .map(|d| d.to_string())
+        .map_err(|e| serde_json::Error::custom(format!("invalid number {}: {}", text, e)))
+}
+
+impl<R: AsyncRead + Unpin + Send> TransactionSource for JsonLinesSource<R> {
+    async fn next_record(&mut self) -> Option<Result<SourcedRecord>> {
+        self.read().await.transpose()
+    }
+}
+
+/// Records already in memory, positions being their 1-based index.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    records: VecDeque<TransactionRecord>,
+    line: u64,
+}
+
+impl MemorySource {
+    pub fn new<I: IntoIterator<Item = TransactionRecord>>(records: I) -> Self {
+        Self {
+            records: records.into_iter().collect(),
+            line: 0,
+        }
+    }
+}
+
+impl TransactionSource for MemorySource {
+    async fn next_record(&mut self) -> Option<Result<SourcedRecord>> {
+        let record = self.records.pop_front()?;
+        self.line += 1;
+        Some(Ok((RecordPosition { line: self.line }, record)))
+    }
+}
+
+/// Format of a transactions file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    JsonLines,
+}
+
+impl InputFormat {
+    /// Guess the format from the file extension, CSV unless `.jsonl` or `.ndjson`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl" | "ndjson") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+/// A reader in one of the file formats, picked at runtime.
+pub enum ReaderSource<R: AsyncRead + Unpin + Send> {
+    Csv(CsvSource<R>),
+    JsonLines(JsonLinesSource<R>),
+}
+
+impl<R: AsyncRead + Unpin + Send> ReaderSource<R> {
+    pub fn new(reader: R, format: InputFormat) -> Self {
+        match format {
+            InputFormat::Csv => ReaderSource::Csv(CsvSource::new(reader)),
+            InputFormat::JsonLines => ReaderSource::JsonLines(JsonLinesSource::new(reader)),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> TransactionSource for ReaderSource<R> {
+    async fn next_record(&mut self) -> Option<Result<SourcedRecord>> {
+        match self {
+            ReaderSource::Csv(source) => source.next_record().await,
+            ReaderSource::JsonLines(source) => source.next_record().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::TransactionRecordType;
+
+    async fn lines<S: TransactionSource>(mut source: S) -> Result<Vec<u64>> {
+        let mut lines = Vec::new();
+        while let Some(record) = source.next_record().await {
+            lines.push(record?.0.line);
+        }
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn sources_positions() -> Result<()> {
+        let data = b"\
+type,client,tx,amount
+deposit,1,1,1
+withdrawal,1,2,1
+"
+        .as_slice();
+        assert_eq!(lines(CsvSource::new(data)).await?, vec![2, 3]);
+
+        let data = b"\
+{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1}
+
+{\"type\": \"withdrawal\", \"client\": 1, \"tx\": 2, \"amount\": 1}
+"
+        .as_slice();
+        assert_eq!(lines(JsonLinesSource::new(data)).await?, vec![1, 3]);
+
+        // More digits than a float holds
+        let record = parse_json_record(
+            "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 0.1000000000000000055}",
+        )
+        .unwrap();
+        assert_eq!(
+            record.amount,
+            Some(rust_decimal_macros::dec!(0.1000000000000000055))
+        );
+        // Exponents are expanded, numbers past the range of amounts are invalid
+        let amount = |number: &str| {
+            parse_json_record(&format!(
+                "{{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": {}}}",
+                number
+            ))
+            .map(|record| record.amount)
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(amount("1e2"), Ok(Some(rust_decimal_macros::dec!(100))));
+        assert_eq!(
+            amount("1.5E-3"),
+            Ok(Some(rust_decimal_macros::dec!(0.0015)))
+        );
+        assert_eq!(amount("2E+1"), Ok(Some(rust_decimal_macros::dec!(20))));
+        assert!(amount("1e30").is_err_and(|e| e.starts_with("invalid number 1e30")));
+
+        let deposit = TransactionRecord::new(TransactionRecordType::Deposit, 1, 1, None);
+        assert_eq!(
+            lines(MemorySource::new([deposit.clone(), deposit])).await?,
+            vec![1, 2]
+        );
+
+        assert_eq!(InputFormat::from_path("tx.jsonl"), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path("tx.csv"), InputFormat::Csv);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn csv_source_headers() -> Result<()> {
+        let data = b"\
+Amount,Transaction_ID,Client_ID,Kind
+1.5,1,2,deposit
+,1,2,dispute
+"
+        .as_slice();
+        let mut source = CsvSource::new(data);
+        let (_, record) = source.next_record().await.expect("record")?;
+        assert_eq!((record.client, record.tx), (2, 1));
+        assert_eq!(record.amount, Some(rust_decimal_macros::dec!(1.5)));
+        assert!(matches!(
+            source.next_record().await,
+            Some(Ok((_, TransactionRecord { amount: None, .. })))
+        ));
+
+        for (data, error) in [
+            (b"type,client,tx\n".as_slice(), "missing column 'amount'"),
+            (
+                b"type,client,tx,amount,fee\n".as_slice(),
+                "unknown column 'fee'",
+            ),
+            (
+                b"type,client,tx,amount,value\n".as_slice(),
+                "duplicated column 'amount'",
+            ),
+        ] {
+            match CsvSource::new(data).next_record().await {
+                Some(Err(PaymentEngineError::InvalidHeader(e))) => assert_eq!(e, error),
+                _ => panic!("{} not detected", error),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn csv_source_invalid_records() -> Result<()> {
+        let data = b"\
+type,client,tx,amount
+deposit,1,1,1
+refund,1,2,1
+deposit,1,3,1,extra
+deposit,x,4,1
+deposit,1,5,1
+"
+        .as_slice();
+        let mut source = CsvSource::new(data);
+        let mut results = Vec::new();
+        while let Some(record) = source.next_record().await {
+            results.push(match record {
+                Ok((position, _)) => Ok(position.line),
+                Err(PaymentEngineError::InvalidRecord(line, _)) => Err(line),
+                Err(e) => return Err(e),
+            });
+        }
+        assert_eq!(results, vec![Ok(2), Err(3), Err(4), Err(5), Ok(6)]);
+
+        Ok(())
+    }
+}
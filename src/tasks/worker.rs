@@ -1,34 +1,79 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use rust_decimal::Decimal;
 use tokio::sync::mpsc;
 
 use crate::{
     account::{Account, AccountId},
+    analytics::DisputeStats,
+    currency::Currency,
+    engine::EngineConfig,
     errors::{
         AccountOperationError::{self, DuplicatedTransaction, WrongAccountId},
-        PaymentEngineError, Result,
+        AccountOperationResult, PaymentEngineError, Result,
     },
+    export::LedgerEntry,
+    fees::{FeeOperation, FeePosting},
+    reconcile::WalletStatement,
+    report::AccountsReportMode,
+    risk::{RiskAction, RiskAlert, RiskEvent, RiskEventKind, RiskHistory, MAX_ALERTS_PER_ACCOUNT},
+    stats::OperationStats,
     transaction::{
-        Dispute, DisputeResolution, DisputeStatus, Transaction, TransactionId, TransactionKind,
-        TransactionStatus,
+        ConversionLeg, Dispute, DisputeResolution, DisputeStatus, Transaction, TransactionId,
+        TransactionKind, TransactionStatus,
     },
 };
 
-use super::command::{DisputeCommandAction, PaymentEngineCommand, TransactionCommandAction};
+use super::command::{
+    CommandOutcome, DisputeCommandAction, DisputeCommandData, PaymentEngineCommand,
+    TransactionCommandAction, TransactionCommandData, TransactionOutcome,
+};
 
 pub struct AccountWorker {
     pub receiver: mpsc::Receiver<PaymentEngineCommand>,
     account: Account,
+    config: Arc<EngineConfig>,
     transactions: HashMap<TransactionId, Transaction>,
     disputes: HashMap<TransactionId, Dispute>,
+    /// Fees taken from the account, in order.
+    fee_postings: Vec<FeePosting>,
+    /// Dispute events, the rest of the figures being computed on demand.
+    dispute_stats: DisputeStats,
+    risk_history: RiskHistory,
+    /// Risk rules broken by the operations of the account, in order, the latest
+    /// `MAX_ALERTS_PER_ACCOUNT` only.
+    alerts: VecDeque<RiskAlert>,
+    /// Whether a command has ever been applied, accounts only opened by failed rows are not
+    /// reported.
+    active: bool,
+    /// Commands applied and refused, transfer legs and expiries left to the engine.
+    operations: OperationStats,
+    outcome_sender: mpsc::UnboundedSender<TransactionOutcome>,
 }
 
 impl AccountWorker {
-    pub fn new(receiver: mpsc::Receiver<PaymentEngineCommand>, account: Account) -> Self {
+    pub fn new(
+        receiver: mpsc::Receiver<PaymentEngineCommand>,
+        account: Account,
+        config: Arc<EngineConfig>,
+        outcome_sender: mpsc::UnboundedSender<TransactionOutcome>,
+    ) -> Self {
         Self {
             receiver,
+            dispute_stats: DisputeStats::new(account.get_id()),
             account,
+            config,
             transactions: HashMap::new(),
             disputes: HashMap::new(),
+            fee_postings: Vec::new(),
+            risk_history: RiskHistory::default(),
+            alerts: VecDeque::new(),
+            active: false,
+            operations: OperationStats::default(),
+            outcome_sender,
         }
     }
 
@@ -36,54 +81,505 @@ impl AccountWorker {
         self.account.get_id()
     }
 
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    pub fn fee_postings(&self) -> &[FeePosting] {
+        &self.fee_postings
+    }
+
+    /// Balance of each wallet with the transactions that moved it, in id order.
+    fn wallet_statements(&self) -> Vec<WalletStatement> {
+        self.account
+            .balances()
+            .map(|balance| {
+                let mut transactions: Vec<_> = self
+                    .transactions
+                    .values()
+                    .filter(|tx| {
+                        tx.currency() == balance.currency
+                            || tx.credit.as_ref().map(|leg| leg.currency) == Some(balance.currency)
+                    })
+                    .map(Transaction::id)
+                    .collect();
+                transactions.sort_unstable();
+                WalletStatement {
+                    balance,
+                    transactions,
+                }
+            })
+            .collect()
+    }
+
+    /// Figures of the account for the run summary.
+    fn run_stats(&self) -> OperationStats {
+        let mut stats = self.operations.clone();
+        if self.active {
+            stats.accounts = 1;
+            if self.account.balances().any(|balance| balance.locked) {
+                stats.locked_accounts = 1;
+            }
+        }
+        for tx in self.transactions.values() {
+            let amounts = match tx.kind() {
+                TransactionKind::Deposit => Some(&mut stats.deposited),
+                TransactionKind::Withdrawal => Some(&mut stats.withdrawn),
+                TransactionKind::Conversion => Some(&mut stats.converted),
+                // Both accounts record a transfer
+                TransactionKind::Transfer if tx.account_id() == self.get_id() => {
+                    Some(&mut stats.transferred)
+                }
+                TransactionKind::Transfer => None,
+            };
+            if let Some(amounts) = amounts {
+                *amounts.entry(tx.currency()).or_default() += tx.amount();
+            }
+            if !tx.charged_back.is_zero() && tx.is_credit_for(self.get_id()) {
+                *stats.charged_back.entry(tx.currency()).or_default() += tx.charged_back;
+            }
+        }
+        for posting in self.fee_postings.iter() {
+            *stats.fees.entry(posting.currency).or_default() += posting.amount;
+        }
+        for balance in self.account.balances() {
+            if !balance.held.is_zero() {
+                *stats.held.entry(balance.currency).or_default() += balance.held;
+            }
+        }
+        stats
+    }
+
+    fn fee(
+        &self,
+        operation: FeeOperation,
+        currency: Currency,
+        amount: Decimal,
+    ) -> AccountOperationResult<Decimal> {
+        self.config
+            .fees
+            .fee(self.account.get_id(), operation, currency, amount)
+    }
+
+    fn post_fee(
+        &mut self,
+        operation: FeeOperation,
+        tx_id: TransactionId,
+        currency: Currency,
+        fee: Decimal,
+    ) {
+        if fee.is_zero() {
+            return;
+        }
+        self.fee_postings.push(FeePosting {
+            tx_id,
+            operation,
+            currency,
+            amount: fee,
+        });
+    }
+
     pub async fn handle(&mut self, command: &PaymentEngineCommand) -> Result<()> {
         let result = match command {
             PaymentEngineCommand::TransactionCommand(ref sub_command) => {
-                if sub_command.tx.account_id() != self.account.get_id() {
-                    return Err(
-                        WrongAccountId(sub_command.tx.account_id(), self.account.get_id()).into(),
-                    );
-                }
-
-                match sub_command.action {
-                    TransactionCommandAction::Deposit => self.handle_deposit(&sub_command.tx),
-                    TransactionCommandAction::Withdraw => self.handle_withdrawal(&sub_command.tx),
+                let result = self.handle_transaction(sub_command);
+                // Transfers span two workers, the engine records their outcome itself.
+                if !sub_command.action.is_transfer() {
+                    let outcome = match result {
+                        Ok(_) => TransactionOutcome::Applied(sub_command.tx.id()),
+                        Err(_) => TransactionOutcome::Rejected(sub_command.tx.id()),
+                    };
+                    // The engine only drops its receiver on shutdown, nobody is left to care then.
+                    let _ = self.outcome_sender.send(outcome);
                 }
+                result
             }
             PaymentEngineCommand::DisputeCommand(ref sub_command) => {
-                if sub_command.dispute.account_id() != self.account.get_id() {
-                    return Err(WrongAccountId(
-                        sub_command.dispute.account_id(),
-                        self.account.get_id(),
-                    )
-                    .into());
-                }
-
-                match sub_command.action {
-                    DisputeCommandAction::OpenDispute => {
-                        self.handle_new_dispute(&sub_command.dispute)
+                self.handle_dispute(sub_command)
+            }
+            PaymentEngineCommand::OpenBalance(balance) => {
+                self.account.open_balance(balance);
+                self.active = true;
+                return Ok(());
+            }
+            PaymentEngineCommand::SendAccounts(..)
+            | PaymentEngineCommand::SendDisputeStats(_)
+            | PaymentEngineCommand::SendWalletStatements(_)
+            | PaymentEngineCommand::SendLedger(_)
+                if !self.active =>
+            {
+                return Ok(());
+            }
+            PaymentEngineCommand::SendAccounts(sender, mode) => {
+                let balances = match mode {
+                    AccountsReportMode::DefaultCurrency => {
+                        let default_currency = self.account.default_currency();
+                        for balance in self.account.balances() {
+                            if balance.currency != default_currency && !balance.total.is_zero() {
+                                log::warn!(
+                                    "Client {} has {} {} left out of the default currency report",
+                                    balance.client,
+                                    balance.total,
+                                    balance.currency
+                                );
+                            }
+                        }
+                        vec![Ok(self.account.balance(default_currency))]
+                    }
+                    AccountsReportMode::PerCurrency => self.account.balances().map(Ok).collect(),
+                    AccountsReportMode::BaseCurrency(currency) => {
+                        vec![self.account.balance_in(*currency, &self.config.fx_rates)]
                     }
-                    DisputeCommandAction::CancelDispute => self
-                        .handle_close_dispute(&sub_command.dispute, DisputeResolution::Cancelled),
-                    DisputeCommandAction::ChargebackDispute => self
-                        .handle_close_dispute(&sub_command.dispute, DisputeResolution::ChargedBack),
+                };
+                for balance in balances {
+                    sender.send(balance).await?;
                 }
+                return Ok(());
             }
-            PaymentEngineCommand::SendAccountsToCSV(sender) => {
-                sender.send(format!("{}", self.account)).await?;
-                Ok(())
+            PaymentEngineCommand::SendDisputeStats(sender) => {
+                sender.send(self.dispute_stats()).await?;
+                return Ok(());
+            }
+            PaymentEngineCommand::SendRiskAlerts(sender) => {
+                for alert in self.alerts.iter() {
+                    sender.send(alert.clone()).await?;
+                }
+                return Ok(());
+            }
+            PaymentEngineCommand::SendWalletStatements(sender) => {
+                for statement in self.wallet_statements() {
+                    sender.send(statement).await?;
+                }
+                return Ok(());
+            }
+            PaymentEngineCommand::SendRunStats(sender) => {
+                sender.send(self.run_stats()).await?;
+                return Ok(());
+            }
+            PaymentEngineCommand::SendLedger(sender) => {
+                let mut fees: HashMap<TransactionId, Decimal> = HashMap::new();
+                for posting in self.fee_postings.iter() {
+                    *fees.entry(posting.tx_id).or_default() += posting.amount;
+                }
+                for tx in self.transactions.values() {
+                    sender
+                        .send(LedgerEntry {
+                            client: self.account.get_id(),
+                            transaction: tx.clone(),
+                            dispute: self.disputes.get(&tx.id()).map(|d| d.status.clone()),
+                            dispute_expired_at: self
+                                .disputes
+                                .get(&tx.id())
+                                .and_then(|d| d.expired_at),
+                            fee: fees.get(&tx.id()).copied().unwrap_or_default(),
+                        })
+                        .await?;
+                }
+                return Ok(());
             }
         };
 
-        result.map_err(PaymentEngineError::from)
+        self.active |= result.is_ok();
+        let counted = match command {
+            PaymentEngineCommand::TransactionCommand(sub_command) => {
+                !sub_command.action.is_transfer()
+            }
+            PaymentEngineCommand::DisputeCommand(sub_command) => {
+                sub_command.action != DisputeCommandAction::ExpireDispute
+            }
+            _ => false,
+        };
+        if counted {
+            match &result {
+                Ok(_) => self.operations.applied += 1,
+                Err(e) => self.operations.reject(e),
+            }
+        }
+        if let Some(reply) = command.reply() {
+            reply.send(result.clone()).await;
+        }
+
+        result.map(|_| ()).map_err(PaymentEngineError::from)
     }
 
-    pub fn handle_deposit(&mut self, transaction: &Transaction) -> Result<()> {
-        if self.transactions.get(&transaction.id()).is_some() {
-            return Err(DuplicatedTransaction(transaction.id()).into());
+    fn handle_transaction(
+        &mut self,
+        sub_command: &TransactionCommandData,
+    ) -> AccountOperationResult<CommandOutcome> {
+        let tx = &sub_command.tx;
+        let account_id = match sub_command.action {
+            TransactionCommandAction::TransferCredit => tx.counterparty(),
+            _ => Some(tx.account_id()),
+        };
+        if account_id != Some(self.account.get_id()) {
+            return Err(WrongAccountId(
+                account_id.unwrap_or(tx.account_id()),
+                self.account.get_id(),
+            ));
+        }
+
+        let risk_event = match sub_command.action {
+            TransactionCommandAction::Deposit | TransactionCommandAction::TransferCredit => {
+                Some(RiskEventKind::Deposit)
+            }
+            TransactionCommandAction::Withdraw | TransactionCommandAction::TransferDebit => {
+                Some(RiskEventKind::Withdrawal)
+            }
+            TransactionCommandAction::Convert => Some(RiskEventKind::Conversion),
+            _ => None,
+        }
+        .map(|kind| RiskEvent {
+            kind,
+            currency: tx.currency(),
+            amount: tx.amount(),
+            at: tx.timestamp(),
+        });
+        // A row refused anyway by the operation neither raises an alert nor counts in the history.
+        if risk_event.is_some() {
+            if self.transactions.contains_key(&tx.id()) {
+                return Err(DuplicatedTransaction(tx.id()));
+            }
+            self.account.is_locked()?;
+        }
+        let flags = match &risk_event {
+            Some(event) => self.check_risk(tx.id(), event)?,
+            None => Vec::new(),
+        };
+
+        match sub_command.action {
+            TransactionCommandAction::Deposit => self.handle_deposit(tx),
+            TransactionCommandAction::Withdraw => self.handle_withdrawal(tx),
+            TransactionCommandAction::Convert => self.handle_conversion(tx),
+            TransactionCommandAction::Transfer => Err(AccountOperationError::InvalidTransfer(
+                tx.id(),
+                "must be split by the engine",
+            )),
+            TransactionCommandAction::TransferDebit => self.handle_transfer_debit(tx),
+            TransactionCommandAction::TransferCredit => self.handle_transfer_credit(tx),
+            TransactionCommandAction::TransferRollback => self.handle_transfer_rollback(tx),
+            TransactionCommandAction::TransferRefund => self.handle_transfer_refund(tx),
+        }?;
+
+        flags.into_iter().for_each(|alert| self.raise_alert(alert));
+        if let Some(event) = risk_event {
+            self.risk_history
+                .record(&event, self.config.risk_rules.window());
         }
+        Ok(CommandOutcome::TransactionApplied(sub_command.tx.id()))
+    }
+
+    /// Check the risk rules broken by an operation. A rejection raises its alert and fails, the
+    /// flags are returned to be raised once the operation is applied.
+    fn check_risk(
+        &mut self,
+        tx_id: TransactionId,
+        event: &RiskEvent,
+    ) -> AccountOperationResult<Vec<RiskAlert>> {
+        let account_id = self.get_id();
+        let alerts: Vec<_> = self
+            .config
+            .risk_rules
+            .check(&self.risk_history, event)
+            .into_iter()
+            .map(|rule| RiskAlert {
+                account_id,
+                tx_id,
+                rule: rule.rule,
+                action: rule.action,
+            })
+            .collect();
 
-        self.account.deposit(transaction.amount())?;
+        let mut flags = Vec::new();
+        for alert in alerts {
+            log::warn!(
+                "Account {}: transaction {} broke risk rule {}",
+                account_id,
+                tx_id,
+                alert.rule
+            );
+            if alert.action == RiskAction::Reject {
+                let rule = alert.rule;
+                self.raise_alert(alert);
+                return Err(AccountOperationError::RiskRuleViolated(tx_id, rule));
+            }
+            flags.push(alert);
+        }
+        Ok(flags)
+    }
+
+    fn raise_alert(&mut self, alert: RiskAlert) {
+        if self.alerts.len() == MAX_ALERTS_PER_ACCOUNT {
+            let dropped = self.alerts.pop_front();
+            log::warn!(
+                "Account {}: too many alerts, dropped {:?}",
+                self.get_id(),
+                dropped
+            );
+        }
+        self.alerts.push_back(alert);
+    }
+
+    fn handle_dispute(
+        &mut self,
+        sub_command: &DisputeCommandData,
+    ) -> AccountOperationResult<CommandOutcome> {
+        let d = &sub_command.dispute;
+        if d.account_id() != self.account.get_id() {
+            return Err(WrongAccountId(d.account_id(), self.account.get_id()));
+        }
+
+        let risk_event =
+            (sub_command.action == DisputeCommandAction::OpenDispute).then(|| RiskEvent {
+                kind: RiskEventKind::Dispute,
+                currency: self
+                    .transactions
+                    .get(&d.tx_id())
+                    .map_or(self.config.default_currency, |tx| tx.currency()),
+                amount: d.amount().unwrap_or_default(),
+                at: d.timestamp(),
+            });
+        let flags = match &risk_event {
+            Some(event) => self.check_risk(d.tx_id(), event)?,
+            None => Vec::new(),
+        };
+
+        let status_before = self.disputes.get(&d.tx_id()).map(|s| s.status.clone());
+        let amount = match sub_command.action {
+            DisputeCommandAction::OpenDispute => self.handle_new_dispute(d),
+            DisputeCommandAction::CancelDispute => {
+                self.handle_close_dispute(d, DisputeResolution::Cancelled)
+            }
+            DisputeCommandAction::ChargebackDispute => {
+                self.handle_close_dispute(d, DisputeResolution::ChargedBack)
+            }
+            DisputeCommandAction::RepresentDispute => self.handle_representment(d),
+            DisputeCommandAction::PreArbitrateDispute => self.handle_pre_arbitration(d),
+            DisputeCommandAction::ArbitrateDispute => self.handle_arbitration(d),
+            DisputeCommandAction::ExpireDispute => self.handle_expired_dispute(d),
+        }?;
+
+        let status = self
+            .disputes
+            .get(&d.tx_id())
+            .map(|stored| stored.status.clone())
+            .unwrap_or_else(|| d.status.clone());
+        self.record_dispute_event(sub_command, status_before, &status);
+        flags.into_iter().for_each(|alert| self.raise_alert(alert));
+        if let Some(event) = risk_event {
+            self.risk_history
+                .record(&event, self.config.risk_rules.window());
+        }
+        Ok(CommandOutcome::DisputeUpdated(d.tx_id(), status, amount))
+    }
+
+    fn record_dispute_event(
+        &mut self,
+        sub_command: &DisputeCommandData,
+        status_before: Option<DisputeStatus>,
+        status: &DisputeStatus,
+    ) {
+        let d = &sub_command.dispute;
+        // Counted per transaction like the closings: partial disputes opened together count once.
+        if sub_command.action == DisputeCommandAction::OpenDispute
+            && status_before != Some(DisputeStatus::InProgress)
+        {
+            self.dispute_stats.opened += 1;
+        }
+        let resolution = match status {
+            DisputeStatus::Resolved(resolution) if status_before.as_ref() != Some(status) => {
+                resolution
+            }
+            _ => return,
+        };
+
+        match resolution {
+            DisputeResolution::Cancelled => self.dispute_stats.resolved += 1,
+            DisputeResolution::ChargedBack => self.dispute_stats.charged_back += 1,
+        }
+        if sub_command.action == DisputeCommandAction::ExpireDispute {
+            self.dispute_stats.expired += 1;
+        }
+
+        // An expired dispute carries its opening time, it closed at its deadline.
+        let opened_at = self.disputes.get(&d.tx_id()).and_then(|s| s.opened_at);
+        let closed_at = match sub_command.action {
+            DisputeCommandAction::ExpireDispute => {
+                opened_at.and_then(|at| self.config.dispute_limits.resolution_deadline(at))
+            }
+            _ => d.timestamp(),
+        };
+        if let (Some(opened_at), Some(closed_at)) = (opened_at, closed_at) {
+            self.dispute_stats
+                .record_resolution_time(closed_at.saturating_sub(opened_at));
+        }
+    }
+
+    /// Dispute figures of the account, amounts converted in the engine default currency at the
+    /// rate of their transaction's time.
+    fn dispute_stats(&self) -> DisputeStats {
+        let to_default = |amount: Decimal, tx: &Transaction| {
+            let currency = tx.currency();
+            let converted = self
+                .config
+                .fx_rates
+                .convert(
+                    amount,
+                    currency,
+                    self.config.default_currency,
+                    tx.timestamp(),
+                )
+                .map(|c| c.amount);
+            if converted.is_none() {
+                log::error!(
+                    "Account {}: no rate from {} to {}, left out of the dispute report",
+                    self.get_id(),
+                    currency,
+                    self.config.default_currency
+                );
+            }
+            converted.unwrap_or_default()
+        };
+
+        let mut stats = self.dispute_stats.clone();
+        for tx in self.transactions.values() {
+            if tx.is_credit_for(self.get_id()) {
+                stats.deposits += 1;
+            }
+            if !tx.charged_back.is_zero() && tx.is_credit_for(self.get_id()) {
+                stats.lost += to_default(tx.charged_back, tx);
+            }
+        }
+        for dispute in self.disputes.values() {
+            if dispute.status == DisputeStatus::InProgress || dispute.status.is_escalated() {
+                stats.open += 1;
+            }
+            if let Some(tx) = self.transactions.get(&dispute.tx_id()) {
+                if !dispute.held.is_zero() {
+                    stats.held += to_default(dispute.held, tx);
+                }
+            }
+        }
+        stats
+    }
+
+    pub fn handle_deposit(&mut self, transaction: &Transaction) -> AccountOperationResult<()> {
+        if self.transactions.contains_key(&transaction.id()) {
+            return Err(DuplicatedTransaction(transaction.id()));
+        }
+
+        let fee = self.fee(
+            FeeOperation::Deposit,
+            transaction.currency(),
+            transaction.amount(),
+        )?;
+        self.account
+            .deposit_with_fee(transaction.currency(), transaction.amount(), fee)?;
+        self.post_fee(
+            FeeOperation::Deposit,
+            transaction.id(),
+            transaction.currency(),
+            fee,
+        );
         let mut tx = transaction.clone();
         tx.status = TransactionStatus::Processed;
         self.transactions.insert(tx.id(), tx);
@@ -91,29 +587,187 @@ impl AccountWorker {
         Ok(())
     }
 
-    pub fn handle_withdrawal(&mut self, transaction: &Transaction) -> Result<()> {
-        if self.transactions.get(&transaction.id()).is_some() {
-            return Err(DuplicatedTransaction(transaction.id()).into());
+    pub fn handle_withdrawal(&mut self, transaction: &Transaction) -> AccountOperationResult<()> {
+        if self.transactions.contains_key(&transaction.id()) {
+            return Err(DuplicatedTransaction(transaction.id()));
         }
+        self.account.check_withdrawal_limit(transaction.amount())?;
 
-        self.account.withdraw(transaction.amount())?;
+        let fee = self.fee(
+            FeeOperation::Withdrawal,
+            transaction.currency(),
+            transaction.amount(),
+        )?;
+        self.account
+            .withdraw_with_fee(transaction.currency(), transaction.amount(), fee)?;
+        self.post_fee(
+            FeeOperation::Withdrawal,
+            transaction.id(),
+            transaction.currency(),
+            fee,
+        );
         let mut tx: Transaction = transaction.clone();
         tx.status = TransactionStatus::Processed;
         self.transactions.insert(tx.id(), tx);
         Ok(())
     }
 
-    pub fn handle_new_dispute(&mut self, d: &Dispute) -> Result<()> {
+    pub fn handle_conversion(&mut self, transaction: &Transaction) -> AccountOperationResult<()> {
+        if self.transactions.contains_key(&transaction.id()) {
+            return Err(DuplicatedTransaction(transaction.id()));
+        }
+
+        let from = transaction.currency();
+        let to = transaction.to_currency().unwrap_or(from);
+        if from == to {
+            return Err(AccountOperationError::InvalidConversion(
+                transaction.id(),
+                "same source and target currency",
+            ));
+        }
+        let conversion = self
+            .config
+            .fx_rates
+            .convert(transaction.amount(), from, to, transaction.timestamp())
+            .ok_or(AccountOperationError::MissingFxRate(from, to))?;
+
+        self.account.withdraw(from, transaction.amount())?;
+        if let Err(e) = self.account.deposit(to, conversion.amount) {
+            // A conversion is all or nothing, put the debit leg back whatever the limits.
+            self.account.refund(from, transaction.amount())?;
+            return Err(e);
+        }
+
+        let mut tx = transaction.clone();
+        tx.credit = Some(ConversionLeg {
+            currency: to,
+            amount: conversion.amount,
+            rate: conversion.rate,
+        });
+        tx.status = TransactionStatus::Processed;
+        self.transactions.insert(tx.id(), tx);
+        Ok(())
+    }
+
+    pub fn handle_transfer_debit(
+        &mut self,
+        transaction: &Transaction,
+    ) -> AccountOperationResult<()> {
+        if self.transactions.contains_key(&transaction.id()) {
+            return Err(DuplicatedTransaction(transaction.id()));
+        }
+        self.account.check_withdrawal_limit(transaction.amount())?;
+
+        self.account
+            .withdraw(transaction.currency(), transaction.amount())?;
+        self.record_processed(transaction);
+        Ok(())
+    }
+
+    pub fn handle_transfer_credit(
+        &mut self,
+        transaction: &Transaction,
+    ) -> AccountOperationResult<()> {
+        if self.transactions.contains_key(&transaction.id()) {
+            return Err(DuplicatedTransaction(transaction.id()));
+        }
+
+        self.account
+            .deposit(transaction.currency(), transaction.amount())?;
+        self.record_processed(transaction);
+        Ok(())
+    }
+
+    /// The credit leg failed: forget the debit as if the transfer never happened.
+    pub fn handle_transfer_rollback(
+        &mut self,
+        transaction: &Transaction,
+    ) -> AccountOperationResult<()> {
+        let tx = self
+            .transactions
+            .get(&transaction.id())
+            .ok_or(AccountOperationError::TransactionNotFound(transaction.id()))?;
+
+        self.account.refund(tx.currency(), tx.amount())?;
+        self.transactions.remove(&transaction.id());
+        // The debit flagged a transfer that didn't happen
+        self.alerts
+            .retain(|alert| alert.tx_id != transaction.id() || alert.action == RiskAction::Reject);
+        Ok(())
+    }
+
+    /// The destination charged (part of) the transfer back: the source gets these funds again.
+    pub fn handle_transfer_refund(
+        &mut self,
+        transaction: &Transaction,
+    ) -> AccountOperationResult<()> {
+        let tx = self
+            .transactions
+            .get_mut(&transaction.id())
+            .ok_or(AccountOperationError::TransactionNotFound(transaction.id()))?;
+
+        if tx.charged_back + transaction.amount() > tx.amount() {
+            return Err(AccountOperationError::TransactionStateMismatch(
+                tx.id(),
+                "has already been refunded",
+            ));
+        }
+
+        self.account.refund(tx.currency(), transaction.amount())?;
+        tx.charged_back += transaction.amount();
+        if tx.charged_back == tx.amount() {
+            tx.status = TransactionStatus::ChargedBack;
+        }
+        Ok(())
+    }
+
+    fn record_processed(&mut self, transaction: &Transaction) {
+        let mut tx = transaction.clone();
+        tx.status = TransactionStatus::Processed;
+        self.transactions.insert(tx.id(), tx);
+    }
+
+    /// Holds the disputed amount and returns it.
+    pub fn handle_new_dispute(&mut self, d: &Dispute) -> AccountOperationResult<Decimal> {
+        let account_id = self.account.get_id();
+        if !self.account.limits().disputes_allowed {
+            return Err(AccountOperationError::DisputesNotAllowed(account_id));
+        }
         let disputed_tx = self
             .transactions
             .get_mut(&d.tx_id())
             .ok_or_else(|| AccountOperationError::TransactionNotFound(d.tx_id()))?;
 
-        if disputed_tx.kind() != TransactionKind::Deposit {
-            return Err(AccountOperationError::DisputeIsNotDeposit(disputed_tx.kind()).into());
+        if !disputed_tx.is_credit_for(account_id) {
+            return Err(AccountOperationError::DisputeIsNotDeposit(
+                disputed_tx.kind(),
+            ));
+        }
+
+        if let (Some(transaction_at), Some(filed_at)) = (disputed_tx.timestamp(), d.timestamp()) {
+            let deadline = self.config.dispute_limits.filing_deadline(transaction_at);
+            if deadline.is_some_and(|deadline| filed_at > deadline) {
+                return Err(AccountOperationError::DisputeWindowExpired(
+                    disputed_tx.id(),
+                ));
+            }
         }
 
-        if disputed_tx.status != TransactionStatus::Processed {
+        let (status, held) = self
+            .disputes
+            .get(&d.tx_id())
+            .map_or((DisputeStatus::Created, Decimal::ZERO), |stored| {
+                (stored.status.clone(), stored.held)
+            });
+        if status.is_escalated() {
+            return Err(AccountOperationError::TransactionStateMismatch(
+                disputed_tx.id(),
+                "has a contested chargeback waiting for a ruling",
+            ));
+        }
+        let disputable = disputed_tx.amount() - disputed_tx.charged_back - held;
+
+        if disputed_tx.status == TransactionStatus::Created || disputable <= Decimal::ZERO {
             let reason = match disputed_tx.status {
                 TransactionStatus::Created => "has not been processed yet",
                 TransactionStatus::DisputeInProgress => "has another dispute in progress",
@@ -123,27 +777,40 @@ impl AccountWorker {
                 // error message.
                 TransactionStatus::Processed => "",
             };
-            return Err(
-                AccountOperationError::TransactionStateMismatch(disputed_tx.id(), reason).into(),
-            );
+            return Err(AccountOperationError::TransactionStateMismatch(
+                disputed_tx.id(),
+                reason,
+            ));
+        }
+
+        let amount = d.amount().unwrap_or(disputable);
+        if amount > disputable {
+            return Err(AccountOperationError::DisputeAmountExceeded(
+                disputed_tx.id(),
+                disputable,
+            ));
         }
 
-        self.account.hold(disputed_tx.amount())?;
+        self.account.hold(disputed_tx.currency(), amount)?;
 
         disputed_tx.status = TransactionStatus::DisputeInProgress;
+        // Only stored once accepted, a refused dispute leaves nothing behind.
+        let stored_dispute = self.disputes.entry(d.tx_id()).or_insert_with(|| d.clone());
+        if stored_dispute.status != DisputeStatus::InProgress {
+            stored_dispute.opened_at = d.timestamp();
+        }
+        stored_dispute.held += amount;
+        stored_dispute.status = DisputeStatus::InProgress;
 
-        let mut updated_d: Dispute = d.clone();
-        updated_d.status = DisputeStatus::InProgress;
-        self.disputes.insert(disputed_tx.id(), updated_d);
-
-        Ok(())
+        Ok(amount)
     }
 
+    /// Releases the disputed amount, or charges it back, and returns it.
     pub fn handle_close_dispute(
         &mut self,
         d: &Dispute,
         resolution: DisputeResolution,
-    ) -> Result<()> {
+    ) -> AccountOperationResult<Decimal> {
         let disputed_tx = self
             .transactions
             .get_mut(&d.tx_id())
@@ -154,6 +821,10 @@ impl AccountWorker {
             .get_mut(&d.tx_id())
             .ok_or_else(|| AccountOperationError::TransactionDisputeNotFound(d.tx_id()))?;
 
+        if stored_dispute.status.is_escalated() {
+            return self.handle_ruling(d, resolution);
+        }
+
         if stored_dispute.status != DisputeStatus::InProgress {
             let reason = match stored_dispute.status {
                 DisputeStatus::Created => "has not been processed yet",
@@ -161,30 +832,231 @@ impl AccountWorker {
                 // Use empty str instead of `unreachable!()` macro to avoid panics that might lead
                 // to inconsistent state or crash loops. In the worst case we can tolerate non-expressive
                 // error message.
-                DisputeStatus::InProgress => "",
+                DisputeStatus::InProgress
+                | DisputeStatus::Representment
+                | DisputeStatus::PreArbitration
+                | DisputeStatus::Arbitration => "",
             };
             return Err(AccountOperationError::TransactionStateMismatch(
                 stored_dispute.tx_id(),
                 reason,
-            )
-            .into());
+            ));
         }
 
-        self.account.unhold(disputed_tx.amount())?;
+        // Without amount, the line closes every open dispute of the transaction.
+        let amount = d.amount().unwrap_or(stored_dispute.held);
+        if amount > stored_dispute.held {
+            return Err(AccountOperationError::DisputeAmountExceeded(
+                disputed_tx.id(),
+                stored_dispute.held,
+            ));
+        }
 
+        let currency = disputed_tx.currency();
+        // Before any change, an overflowing fee leaves the dispute as it was.
+        let fee = match resolution {
+            DisputeResolution::ChargedBack => self.config.fees.fee(
+                self.account.get_id(),
+                FeeOperation::Chargeback,
+                currency,
+                amount,
+            )?,
+            DisputeResolution::Cancelled => Decimal::ZERO,
+        };
+        // The lock isn't checked: after a partial chargeback, the other disputes of the
+        // transaction must still be closed.
         match resolution {
-            DisputeResolution::Cancelled => {
-                disputed_tx.status = TransactionStatus::Processed;
-            }
             DisputeResolution::ChargedBack => {
-                self.account.withdraw(disputed_tx.amount())?;
-                disputed_tx.status = TransactionStatus::ChargedBack;
-                self.account.locked = true;
+                self.account.debit_held(currency, amount)?;
+                disputed_tx.charged_back += amount;
+            }
+            DisputeResolution::Cancelled => self.account.release_held(currency, amount)?,
+        }
+        stored_dispute.held -= amount;
+
+        if stored_dispute.held.is_zero() {
+            disputed_tx.status = match disputed_tx.charged_back == disputed_tx.amount() {
+                true => TransactionStatus::ChargedBack,
+                false => TransactionStatus::Processed,
+            };
+            stored_dispute.status = DisputeStatus::Resolved(resolution.clone());
+        }
+
+        if resolution == DisputeResolution::ChargedBack {
+            let tx_id = disputed_tx.id();
+            // The chargeback can't be refused, its fee only takes what is left to pay it.
+            let fee = self.account.charge_fee(currency, fee)?;
+            self.post_fee(FeeOperation::Chargeback, tx_id, currency, fee);
+            self.account.locked = true;
+        }
+
+        Ok(amount)
+    }
+
+    /// The client contests a chargeback: the contested amount comes back held until a ruling.
+    pub fn handle_representment(&mut self, d: &Dispute) -> AccountOperationResult<Decimal> {
+        let disputed_tx = self
+            .transactions
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionNotFound(d.tx_id()))?;
+        let stored_dispute = self
+            .disputes
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionDisputeNotFound(d.tx_id()))?;
+
+        if disputed_tx.kind() == TransactionKind::Transfer {
+            return Err(AccountOperationError::InvalidTransfer(
+                disputed_tx.id(),
+                "cannot be represented",
+            ));
+        }
+        if stored_dispute.status != DisputeStatus::Resolved(DisputeResolution::ChargedBack)
+            || stored_dispute.represented
+        {
+            return Err(AccountOperationError::TransactionStateMismatch(
+                disputed_tx.id(),
+                "has no chargeback to represent",
+            ));
+        }
+
+        let amount = d.amount().unwrap_or(disputed_tx.charged_back);
+        if amount > disputed_tx.charged_back {
+            return Err(AccountOperationError::DisputeAmountExceeded(
+                disputed_tx.id(),
+                disputed_tx.charged_back,
+            ));
+        }
+
+        self.account.credit_held(disputed_tx.currency(), amount)?;
+        disputed_tx.charged_back -= amount;
+        disputed_tx.status = TransactionStatus::DisputeInProgress;
+        stored_dispute.held += amount;
+        stored_dispute.contested = amount;
+        stored_dispute.represented = true;
+        stored_dispute.status = DisputeStatus::Representment;
+
+        Ok(amount)
+    }
+
+    /// The representment is rejected: the contested amount is removed again.
+    pub fn handle_pre_arbitration(&mut self, d: &Dispute) -> AccountOperationResult<Decimal> {
+        let disputed_tx = self
+            .transactions
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionNotFound(d.tx_id()))?;
+        let stored_dispute = self
+            .disputes
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionDisputeNotFound(d.tx_id()))?;
+
+        if stored_dispute.status != DisputeStatus::Representment {
+            return Err(AccountOperationError::TransactionStateMismatch(
+                disputed_tx.id(),
+                "is not in representment",
+            ));
+        }
+
+        let amount = stored_dispute.contested;
+        self.account.debit_held(disputed_tx.currency(), amount)?;
+        disputed_tx.charged_back += amount;
+        stored_dispute.held -= amount;
+        stored_dispute.status = DisputeStatus::PreArbitration;
+
+        Ok(amount)
+    }
+
+    /// The scheme is asked to rule, funds stay removed meanwhile.
+    pub fn handle_arbitration(&mut self, d: &Dispute) -> AccountOperationResult<Decimal> {
+        let disputed_tx = self
+            .transactions
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionNotFound(d.tx_id()))?;
+        let stored_dispute = self
+            .disputes
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionDisputeNotFound(d.tx_id()))?;
+
+        if stored_dispute.status != DisputeStatus::PreArbitration {
+            return Err(AccountOperationError::TransactionStateMismatch(
+                disputed_tx.id(),
+                "is not in pre-arbitration",
+            ));
+        }
+
+        stored_dispute.status = DisputeStatus::Arbitration;
+        Ok(stored_dispute.contested)
+    }
+
+    /// Ruling of an escalated dispute, `Cancelled` meaning the client wins.
+    /// The account stays locked from the first chargeback whatever the ruling.
+    fn handle_ruling(
+        &mut self,
+        d: &Dispute,
+        resolution: DisputeResolution,
+    ) -> AccountOperationResult<Decimal> {
+        let disputed_tx = self
+            .transactions
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionNotFound(d.tx_id()))?;
+        let stored_dispute = self
+            .disputes
+            .get_mut(&d.tx_id())
+            .ok_or_else(|| AccountOperationError::TransactionDisputeNotFound(d.tx_id()))?;
+        let amount = stored_dispute.contested;
+        let currency = disputed_tx.currency();
+
+        match (&stored_dispute.status, &resolution) {
+            (DisputeStatus::Representment, DisputeResolution::Cancelled) => {
+                self.account.release_held(currency, amount)?;
+                stored_dispute.held -= amount;
+            }
+            (DisputeStatus::Representment, DisputeResolution::ChargedBack) => {
+                return Err(AccountOperationError::TransactionStateMismatch(
+                    disputed_tx.id(),
+                    "is in representment, pre-arbitration is needed to charge it back",
+                ));
             }
+            (_, DisputeResolution::Cancelled) => {
+                self.account.refund(currency, amount)?;
+                disputed_tx.charged_back -= amount;
+            }
+            (_, DisputeResolution::ChargedBack) => {}
         }
 
+        disputed_tx.status = match disputed_tx.charged_back == disputed_tx.amount() {
+            true => TransactionStatus::ChargedBack,
+            false => TransactionStatus::Processed,
+        };
+        stored_dispute.contested = Decimal::ZERO;
         stored_dispute.status = DisputeStatus::Resolved(resolution);
 
-        Ok(())
+        Ok(amount)
+    }
+
+    /// Resolves with the default resolution a dispute left open past its deadline, unless it has
+    /// been closed (and maybe opened again) in the meantime.
+    pub fn handle_expired_dispute(&mut self, d: &Dispute) -> AccountOperationResult<Decimal> {
+        let still_open = self.disputes.get(&d.tx_id()).is_some_and(|stored| {
+            stored.status == DisputeStatus::InProgress && stored.opened_at == d.timestamp()
+        });
+        if !still_open {
+            return Ok(Decimal::ZERO);
+        }
+
+        let resolution = self.config.dispute_limits.default_resolution.clone();
+        log::warn!(
+            "Dispute of transaction {} on account {} expired, resolved as {:?}",
+            d.tx_id(),
+            d.account_id(),
+            resolution
+        );
+        let amount =
+            self.handle_close_dispute(&Dispute::new(d.account_id(), d.tx_id()), resolution)?;
+        if let Some(stored) = self.disputes.get_mut(&d.tx_id()) {
+            stored.expired_at = d
+                .timestamp()
+                .and_then(|at| self.config.dispute_limits.resolution_deadline(at));
+        }
+        Ok(amount)
     }
 }
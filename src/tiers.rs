@@ -0,0 +1,158 @@
+/// Account tiers loaded from a local CSV file with `tier,client,max_balance,max_withdrawal,disputes`
+/// columns:
+/// - rows without `client` define the limits of a tier, empty limits meaning no limit and empty
+///   `disputes` meaning disputes are allowed.
+/// - rows with a `client` put this client in a tier.
+///
+/// Clients without a tier get the `default` tier if there is one, no limit otherwise.
+/// Limits are amounts of the currency of each wallet, without conversion: `max_balance` caps
+/// every wallet of the client on its own and `max_withdrawal` every withdrawal in its currency.
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::{
+    account::AccountId,
+    csv::read_records,
+    errors::{PaymentEngineError, Result},
+};
+
+pub const DEFAULT_TIER: &str = "default";
+
+/// Limits of an account, in each of its currencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountLimits {
+    /// Cap of the total of each wallet, in the wallet currency.
+    pub max_balance: Option<Decimal>,
+    /// Cap of each withdrawal, in the currency of the wallet debited.
+    pub max_withdrawal: Option<Decimal>,
+    pub disputes_allowed: bool,
+}
+
+impl Default for AccountLimits {
+    fn default() -> Self {
+        Self {
+            max_balance: None,
+            max_withdrawal: None,
+            disputes_allowed: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TierRecord {
+    tier: String,
+    #[serde(default)]
+    client: Option<AccountId>,
+    #[serde(default)]
+    max_balance: Option<Decimal>,
+    #[serde(default)]
+    max_withdrawal: Option<Decimal>,
+    #[serde(default)]
+    disputes: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccountTiers {
+    tiers: HashMap<String, AccountLimits>,
+    clients: HashMap<AccountId, String>,
+}
+
+impl AccountTiers {
+    pub async fn from_csv<R: AsyncRead + Unpin + Send>(reader: R) -> Result<Self> {
+        let mut tiers = Self::default();
+        for row in read_records::<TierRecord, _>(reader).await? {
+            match row.client {
+                Some(client) => tiers.assign(client, row.tier),
+                None => tiers.insert(
+                    row.tier,
+                    AccountLimits {
+                        max_balance: row.max_balance,
+                        max_withdrawal: row.max_withdrawal,
+                        disputes_allowed: row.disputes.unwrap_or(true),
+                    },
+                ),
+            }
+        }
+
+        // Catch typos, a client in an unknown tier would silently get the default limits.
+        if let Some((client, tier)) = tiers
+            .clients
+            .iter()
+            .find(|(_, tier)| !tiers.tiers.contains_key(*tier))
+        {
+            return Err(PaymentEngineError::UnknownTier(tier.clone(), *client));
+        }
+
+        Ok(tiers)
+    }
+
+    pub fn insert(&mut self, tier: String, limits: AccountLimits) {
+        self.tiers.insert(tier, limits);
+    }
+
+    pub fn assign(&mut self, client: AccountId, tier: String) {
+        self.clients.insert(client, tier);
+    }
+
+    pub fn limits(&self, client: AccountId) -> AccountLimits {
+        let tier = self
+            .clients
+            .get(&client)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_TIER);
+        self.tiers.get(tier).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn account_tiers_from_csv() -> Result<()> {
+        let data = b"\
+tier,client,max_balance,max_withdrawal,disputes
+default,,1000,100,false
+verified,,10000,,
+business,,,,true
+verified,1,,,
+business,2,,,
+"
+        .as_slice();
+        let tiers = AccountTiers::from_csv(data).await?;
+
+        assert_eq!(
+            tiers.limits(1),
+            AccountLimits {
+                max_balance: Some(dec!(10000)),
+                max_withdrawal: None,
+                disputes_allowed: true,
+            }
+        );
+        assert_eq!(tiers.limits(2), AccountLimits::default());
+        assert_eq!(
+            tiers.limits(3),
+            AccountLimits {
+                max_balance: Some(dec!(1000)),
+                max_withdrawal: Some(dec!(100)),
+                disputes_allowed: false,
+            }
+        );
+
+        let data = b"\
+tier,client,max_balance,max_withdrawal,disputes
+verifed,1,,,
+"
+        .as_slice();
+        assert_eq!(
+            AccountTiers::from_csv(data).await.err(),
+            Some(PaymentEngineError::UnknownTier(String::from("verifed"), 1))
+        );
+
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 /// This module contains transaction business encapsulation.
 /// This will help us to have a clean code and intention.
-use crate::account::AccountId;
+use crate::{account::AccountId, currency::Currency};
 use rust_decimal::Decimal;
 use std::fmt::{Display, Formatter, Result};
 
@@ -10,6 +10,8 @@ pub type TransactionId = u32;
 pub enum TransactionKind {
     Deposit,
     Withdrawal,
+    Conversion,
+    Transfer,
 }
 
 impl Display for TransactionKind {
@@ -17,6 +19,8 @@ impl Display for TransactionKind {
         match *self {
             TransactionKind::Deposit => write!(f, "Deposit"),
             TransactionKind::Withdrawal => write!(f, "Withdrawal"),
+            TransactionKind::Conversion => write!(f, "Conversion"),
+            TransactionKind::Transfer => write!(f, "Transfer"),
         }
     }
 }
@@ -35,7 +39,26 @@ pub struct Transaction {
     id: TransactionId,
     account_id: AccountId,
     amount: Decimal,
+    currency: Currency,
+    /// Target currency of a conversion.
+    to_currency: Option<Currency>,
+    /// Destination account of a transfer, `account_id` being the source.
+    counterparty: Option<AccountId>,
+    /// Credit leg of a conversion once applied, the debit leg being `amount` in `currency`.
+    pub credit: Option<ConversionLeg>,
+    /// Part of the amount already charged back (or refunded for a transfer source).
+    pub charged_back: Decimal,
     pub status: TransactionStatus,
+    /// Time of the input row (unix seconds), if any.
+    timestamp: Option<i64>,
+}
+
+/// What a conversion credited in its target currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionLeg {
+    pub currency: Currency,
+    pub amount: Decimal,
+    pub rate: Decimal,
 }
 
 impl Transaction {
@@ -44,16 +67,62 @@ impl Transaction {
         id: TransactionId,
         account_id: AccountId,
         amount: Decimal,
+    ) -> Self {
+        Self::new_with_currency(kind, id, account_id, amount, Currency::default())
+    }
+
+    pub fn new_with_currency(
+        kind: TransactionKind,
+        id: TransactionId,
+        account_id: AccountId,
+        amount: Decimal,
+        currency: Currency,
     ) -> Self {
         Self {
             kind,
             id,
             account_id,
             amount,
+            currency,
+            to_currency: None,
+            counterparty: None,
+            credit: None,
+            charged_back: Decimal::ZERO,
             status: TransactionStatus::Created,
+            timestamp: None,
         }
     }
 
+    pub fn new_conversion(
+        id: TransactionId,
+        account_id: AccountId,
+        amount: Decimal,
+        from: Currency,
+        to: Currency,
+    ) -> Self {
+        Self {
+            to_currency: Some(to),
+            ..Self::new_with_currency(TransactionKind::Conversion, id, account_id, amount, from)
+        }
+    }
+
+    pub fn new_transfer(
+        id: TransactionId,
+        from: AccountId,
+        to: AccountId,
+        amount: Decimal,
+        currency: Currency,
+    ) -> Self {
+        Self {
+            counterparty: Some(to),
+            ..Self::new_with_currency(TransactionKind::Transfer, id, from, amount, currency)
+        }
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<i64>) -> Self {
+        Self { timestamp, ..self }
+    }
+
     pub fn kind(&self) -> TransactionKind {
         self.kind
     }
@@ -62,6 +131,10 @@ impl Transaction {
         self.id
     }
 
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
     pub fn account_id(&self) -> AccountId {
         self.account_id
     }
@@ -69,6 +142,27 @@ impl Transaction {
     pub fn amount(&self) -> Decimal {
         self.amount
     }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn to_currency(&self) -> Option<Currency> {
+        self.to_currency
+    }
+
+    pub fn counterparty(&self) -> Option<AccountId> {
+        self.counterparty
+    }
+
+    /// Did this transaction bring funds to an account? Only those can be disputed.
+    pub fn is_credit_for(&self, account_id: AccountId) -> bool {
+        match self.kind {
+            TransactionKind::Deposit => self.account_id == account_id,
+            TransactionKind::Transfer => self.counterparty == Some(account_id),
+            TransactionKind::Withdrawal | TransactionKind::Conversion => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -77,30 +171,99 @@ pub enum DisputeResolution {
     ChargedBack,
 }
 
+/// Stages of a dispute, following the card schemes:
+/// - `Created` -> `InProgress` when the client disputes, funds are held.
+/// - `InProgress` -> `Resolved` when the dispute is cancelled (funds released) or charged back
+///   (funds removed).
+/// - `Resolved(ChargedBack)` -> `Representment` when the client contests the chargeback, the
+///   contested funds come back held.
+/// - `Representment` -> `Resolved(Cancelled)` when the representment is accepted (funds released)
+///   or -> `PreArbitration` when it is rejected (funds removed again).
+/// - `PreArbitration` -> `Arbitration` when the scheme is asked to rule.
+/// - `PreArbitration` or `Arbitration` -> `Resolved(Cancelled)` when the client wins (funds given
+///   back) or `Resolved(ChargedBack)` when it loses (nothing moves).
 #[derive(Debug, Clone, PartialEq)]
 pub enum DisputeStatus {
     Created,
     InProgress,
+    Representment,
+    PreArbitration,
+    Arbitration,
     Resolved(DisputeResolution),
 }
 
+impl DisputeStatus {
+    /// The dispute went past the chargeback and waits for a ruling.
+    pub fn is_escalated(&self) -> bool {
+        matches!(
+            self,
+            DisputeStatus::Representment
+                | DisputeStatus::PreArbitration
+                | DisputeStatus::Arbitration
+        )
+    }
+}
+
 /// Represents a line as a business case of a dispute.
+/// A dispute may only target a part of the transaction, several of them adding up to at most the
+/// transaction amount.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Dispute {
     account_id: AccountId,
     tx_id: TransactionId,
+    /// Amount targeted by the line, everything that can be when missing.
+    amount: Option<Decimal>,
     pub status: DisputeStatus,
+    /// Amount currently held for all the open disputes of the transaction.
+    pub held: Decimal,
+    /// Amount contested by the client once charged back, moving along the escalation stages.
+    pub contested: Decimal,
+    /// A chargeback can only be represented once.
+    pub represented: bool,
+    /// Time of the input row (unix seconds), if any.
+    timestamp: Option<i64>,
+    /// Time the open disputes of the transaction were opened at, their deadline starting then.
+    pub opened_at: Option<i64>,
+    /// Deadline at which the disputes were last left open and resolved by default.
+    pub expired_at: Option<i64>,
 }
 
 impl Dispute {
     pub fn new(account_id: AccountId, tx_id: TransactionId) -> Self {
+        Self::new_partial(account_id, tx_id, None)
+    }
+
+    pub fn new_partial(
+        account_id: AccountId,
+        tx_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Self {
         Self {
             account_id,
             tx_id,
+            amount,
             status: DisputeStatus::Created,
+            held: Decimal::ZERO,
+            contested: Decimal::ZERO,
+            represented: false,
+            timestamp: None,
+            opened_at: None,
+            expired_at: None,
         }
     }
 
+    pub fn with_timestamp(self, timestamp: Option<i64>) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
     pub fn account_id(&self) -> AccountId {
         self.account_id
     }
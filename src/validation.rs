@@ -0,0 +1,206 @@
+/// Validation of the input records before they become engine commands, so malformed rows are
+/// rejected with a distinct error instead of failing somewhere in the engine:
+/// - amounts are required on deposits, withdrawals, conversions and transfers, optional on
+///   disputes, resolves, chargebacks and representments and forbidden on the other rows.
+/// - amounts must be positive, with at most `max_scale` decimal places when set.
+/// - `to_currency` is only for conversions and `to_client` only for transfers.
+/// - clients, destinations of transfers included, must be in the configured range.
+use std::ops::RangeInclusive;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    account::AccountId,
+    csv::{TransactionRecord, TransactionRecordType},
+    errors::RecordValidationError,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordValidator {
+    /// Maximum number of decimal places of amounts, unlimited when not set.
+    pub max_scale: Option<u32>,
+    pub clients: RangeInclusive<AccountId>,
+}
+
+impl Default for RecordValidator {
+    fn default() -> Self {
+        Self {
+            max_scale: None,
+            clients: AccountId::MIN..=AccountId::MAX,
+        }
+    }
+}
+
+/// Whether a row type requires, accepts or forbids an amount.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AmountPresence {
+    Required,
+    Optional,
+    Forbidden,
+}
+
+fn amount_presence(type_: &TransactionRecordType) -> AmountPresence {
+    match type_ {
+        TransactionRecordType::Deposit
+        | TransactionRecordType::Withdrawal
+        | TransactionRecordType::Conversion
+        | TransactionRecordType::Transfer => AmountPresence::Required,
+        TransactionRecordType::Dispute
+        | TransactionRecordType::Resolve
+        | TransactionRecordType::Chargeback
+        | TransactionRecordType::Representment => AmountPresence::Optional,
+        TransactionRecordType::Prearbitration | TransactionRecordType::Arbitration => {
+            AmountPresence::Forbidden
+        }
+    }
+}
+
+impl RecordValidator {
+    pub fn validate(&self, record: &TransactionRecord) -> Result<(), RecordValidationError> {
+        self.validate_client(record.client)?;
+        if let Some(to_client) = record.to_client {
+            self.validate_client(to_client)?;
+        }
+
+        let type_name = record.type_.name();
+        if record.to_currency.is_some()
+            && !matches!(record.type_, TransactionRecordType::Conversion)
+        {
+            return Err(RecordValidationError::UnexpectedField(
+                "to_currency",
+                type_name,
+            ));
+        }
+        if record.to_client.is_some() && !matches!(record.type_, TransactionRecordType::Transfer) {
+            return Err(RecordValidationError::UnexpectedField(
+                "to_client",
+                type_name,
+            ));
+        }
+
+        match (amount_presence(&record.type_), record.amount) {
+            (AmountPresence::Required, None) => Err(RecordValidationError::MissingAmount),
+            (AmountPresence::Forbidden, Some(_)) => {
+                Err(RecordValidationError::UnexpectedField("amount", type_name))
+            }
+            (_, Some(amount)) => self.validate_amount(amount),
+            (_, None) => Ok(()),
+        }
+    }
+
+    fn validate_client(&self, client: AccountId) -> Result<(), RecordValidationError> {
+        if !self.clients.contains(&client) {
+            return Err(RecordValidationError::ClientOutOfRange(
+                client,
+                *self.clients.start(),
+                *self.clients.end(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_amount(&self, amount: Decimal) -> Result<(), RecordValidationError> {
+        if amount.is_sign_negative() && !amount.is_zero() {
+            return Err(RecordValidationError::NegativeAmount(amount));
+        }
+        if amount.is_zero() {
+            return Err(RecordValidationError::ZeroAmount);
+        }
+        match self.max_scale {
+            // Trailing zeros don't count, `1.50000` is as precise as `1.5`
+            Some(max_scale) if amount.normalize().scale() > max_scale => {
+                Err(RecordValidationError::ExcessiveScale(amount, max_scale))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(type_: TransactionRecordType, amount: Option<Decimal>) -> TransactionRecord {
+        TransactionRecord::new(type_, 1, 1, amount)
+    }
+
+    #[test]
+    fn validate_records() {
+        let validator = RecordValidator {
+            max_scale: Some(4),
+            clients: 1..=100,
+        };
+        let tests = [
+            (
+                record(TransactionRecordType::Deposit, Some(dec!(1.2345))),
+                Ok(()),
+            ),
+            (
+                record(TransactionRecordType::Deposit, Some(dec!(1.50000))),
+                Ok(()),
+            ),
+            (
+                record(TransactionRecordType::Withdrawal, None),
+                Err(RecordValidationError::MissingAmount),
+            ),
+            (
+                record(TransactionRecordType::Deposit, Some(dec!(-1))),
+                Err(RecordValidationError::NegativeAmount(dec!(-1))),
+            ),
+            (
+                record(TransactionRecordType::Transfer, Some(dec!(0))),
+                Err(RecordValidationError::ZeroAmount),
+            ),
+            (
+                record(TransactionRecordType::Deposit, Some(dec!(1.23456))),
+                Err(RecordValidationError::ExcessiveScale(dec!(1.23456), 4)),
+            ),
+            (record(TransactionRecordType::Dispute, None), Ok(())),
+            (
+                record(TransactionRecordType::Chargeback, Some(dec!(-2))),
+                Err(RecordValidationError::NegativeAmount(dec!(-2))),
+            ),
+            (
+                record(TransactionRecordType::Arbitration, Some(dec!(1))),
+                Err(RecordValidationError::UnexpectedField(
+                    "amount",
+                    "arbitration",
+                )),
+            ),
+            (
+                TransactionRecord {
+                    to_client: Some(2),
+                    ..record(TransactionRecordType::Deposit, Some(dec!(1)))
+                },
+                Err(RecordValidationError::UnexpectedField(
+                    "to_client",
+                    "deposit",
+                )),
+            ),
+            (
+                TransactionRecord {
+                    to_client: Some(101),
+                    ..record(TransactionRecordType::Transfer, Some(dec!(1)))
+                },
+                Err(RecordValidationError::ClientOutOfRange(101, 1, 100)),
+            ),
+            (
+                TransactionRecord::new(TransactionRecordType::Deposit, 0, 1, Some(dec!(1))),
+                Err(RecordValidationError::ClientOutOfRange(0, 1, 100)),
+            ),
+        ];
+        for (record, expected) in tests {
+            assert_eq!(validator.validate(&record), expected, "{:?}", record);
+        }
+
+        // No scale limit by default
+        assert_eq!(
+            RecordValidator::default().validate(&record(
+                TransactionRecordType::Deposit,
+                Some(dec!(0.1000000000000000055511))
+            )),
+            Ok(())
+        );
+    }
+}